@@ -0,0 +1,86 @@
+use glam::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+
+
+/// Maps scalar values in the [0, 1] range to colors.
+#[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Colormap {
+    #[default]
+    Gray,
+    Viridis,
+    Magma,
+    Turbo,
+}
+
+impl Colormap {
+    const VIRIDIS: &'static [u32] = &[
+        0x440154, 0x472D7B, 0x3B528B, 0x2C728E, 0x21908C, 0x27AD81, 0x5DC863, 0xAADC32, 0xFDE725,
+    ];
+
+    const MAGMA: &'static [u32] = &[
+        0x000004, 0x1D1147, 0x51127C, 0x822681, 0xB63679, 0xE65164, 0xFB8861, 0xFEC287, 0xFCFDBF,
+    ];
+
+    const TURBO: &'static [u32] = &[
+        0x30123B, 0x4662D7, 0x36AAF9, 0x1AE4B6, 0x72FE5E, 0xC7EF34, 0xFABA39, 0xF66B19, 0xCA2A04, 0x7A0403,
+    ];
+
+    /// Color of the value, which is clamped to the [0, 1] range
+    pub fn eval(self, value: f32) -> Vec3 {
+        let value = if value.is_nan() { 0.0 } else { value.clamp(0.0, 1.0) };
+
+        let table = match self {
+            Self::Gray => return Vec3::splat(value),
+            Self::Viridis => Self::VIRIDIS,
+            Self::Magma => Self::MAGMA,
+            Self::Turbo => Self::TURBO,
+        };
+
+        let to_rgb = |hex: u32| vec3(
+            (hex >> 16 & 0xFF) as f32,
+            (hex >> 8 & 0xFF) as f32,
+            (hex & 0xFF) as f32,
+        ) / 255.0;
+
+        // control points are evenly spaced and interpolated linearly
+        let position = value * (table.len() - 1) as f32;
+        let i = (position as usize).min(table.len() - 2);
+
+        to_rgb(table[i]).lerp(to_rgb(table[i + 1]), position - i as f32)
+    }
+}
+
+impl std::str::FromStr for Colormap {
+    type Err = ColormapParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "gray" => Self::Gray,
+            "viridis" => Self::Viridis,
+            "magma" => Self::Magma,
+            "turbo" => Self::Turbo,
+            _ => return Err(ColormapParseError(s.to_owned())),
+        })
+    }
+}
+
+impl std::fmt::Display for Colormap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Gray => "gray",
+            Self::Viridis => "viridis",
+            Self::Magma => "magma",
+            Self::Turbo => "turbo",
+        })
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid colormap '{0}', valid values are: 'gray', 'viridis', 'magma' and 'turbo'")]
+pub struct ColormapParseError(pub String);
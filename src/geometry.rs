@@ -1,6 +1,6 @@
 use bytemuck::{Pod, Zeroable};
 use glam::*;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 
 
@@ -64,6 +64,22 @@ impl Aabb {
     pub fn with_translation(self, offset: Vec3) -> Self {
         Self { lo: self.lo + offset, hi: self.hi + offset }
     }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        std::array::from_fn(|i| vec3(
+            if i & 1 == 0 { self.lo.x } else { self.hi.x },
+            if i & 2 == 0 { self.lo.y } else { self.hi.y },
+            if i & 4 == 0 { self.lo.z } else { self.hi.z },
+        ))
+    }
+
+    /// Smallest box containing all of the points
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Self {
+        points.into_iter().fold(
+            Self::new(Vec3::INFINITY, Vec3::NEG_INFINITY),
+            |aabb, point| Self::new(aabb.lo.min(point), aabb.hi.max(point)),
+        )
+    }
 }
 
 impl Default for Aabb {
@@ -94,4 +110,378 @@ pub trait Intersect<T> {
     type Output;
 
     fn intersect(&self, other: &T) -> Self::Output;
-}
\ No newline at end of file
+}
+
+
+
+/// Infinite plane through the point, the normal points into its positive half-space.
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct Plane {
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+impl Intersect<Ray> for Plane {
+    /// Part of the ray in the positive half-space, unbounded on one side
+    type Output = Option<(f32, f32)>;
+
+    fn intersect(&self, ray: &Ray) -> Self::Output {
+        let height = self.normal.dot(ray.origin - self.point);
+        let speed = self.normal.dot(ray.direction);
+
+        if speed == 0.0 {
+            return (height > 0.0).then_some((f32::NEG_INFINITY, f32::INFINITY));
+        }
+
+        let distance = -height / speed;
+
+        Some(match speed > 0.0 {
+            true => (distance, f32::INFINITY),
+            false => (f32::NEG_INFINITY, distance),
+        })
+    }
+}
+
+
+
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Intersect<Ray> for Sphere {
+    type Output = Option<(f32, f32)>;
+
+    fn intersect(&self, ray: &Ray) -> Self::Output {
+        let offset = ray.origin - self.center;
+
+        let a = ray.direction.length_squared();
+        let b = offset.dot(ray.direction);
+        let c = offset.length_squared() - self.radius * self.radius;
+
+        let discriminant = b * b - a * c;
+
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        let root = discriminant.sqrt();
+
+        Some(((-b - root) / a, (-b + root) / a))
+    }
+}
+
+
+
+/// Shape of a clipping primitive, written with a `shape = "plane" | "sphere" | "box"` key.
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum ClipShape {
+    /// The positive half-space of the plane
+    Plane(Plane),
+    Sphere(Sphere),
+    Box(Aabb),
+}
+
+impl Intersect<Ray> for ClipShape {
+    type Output = Option<(f32, f32)>;
+
+    fn intersect(&self, ray: &Ray) -> Self::Output {
+        match self {
+            Self::Plane(plane) => plane.intersect(ray),
+            Self::Sphere(sphere) => sphere.intersect(ray),
+            Self::Box(aabb) => aabb.intersect(ray),
+        }
+    }
+}
+
+
+
+/// Region cut away from the volumes: the inside of the shape or, when inverted, its outside.
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct ClipPrimitive {
+    #[serde(flatten)]
+    pub shape: ClipShape,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+
+
+/// Part of a ray left after clipping
+#[derive(Clone, Debug, PartialEq, Copy)]
+pub struct ClippedInterval {
+    pub near: f32,
+    pub far: f32,
+    /// Whether the interval starts at the surface of a clipping primitive
+    pub cut: bool,
+}
+
+/// Removes the parts of the sorted disjoint intervals along the ray cut away by the primitives
+pub fn clip_intervals(
+    ray: &Ray, intervals: impl IntoIterator<Item = (f32, f32)>, primitives: &[ClipPrimitive],
+) -> Vec<ClippedInterval> {
+    let mut intervals = intervals.into_iter()
+        .map(|(near, far)| ClippedInterval { near, far, cut: false })
+        .collect::<Vec<_>>();
+
+    for primitive in primitives {
+        let inside = primitive.shape.intersect(ray);
+
+        intervals = intervals.into_iter()
+            .flat_map(|interval| {
+                let ClippedInterval { near, far, cut } = interval;
+
+                let pieces = match (inside, primitive.invert) {
+                    (None, false) => [Some(interval), None],
+                    (None, true) => [None, None],
+                    (Some((enter, exit)), false) => [
+                        (near < enter).then_some(ClippedInterval { near, far: far.min(enter), cut }),
+                        (exit < far).then_some(ClippedInterval {
+                            near: near.max(exit), far, cut: cut || exit > near,
+                        }),
+                    ],
+                    (Some((enter, exit)), true) => [
+                        (enter.max(near) < exit.min(far)).then_some(ClippedInterval {
+                            near: near.max(enter), far: far.min(exit), cut: cut || enter > near,
+                        }),
+                        None,
+                    ],
+                };
+
+                pieces.into_iter().flatten().filter(|interval| interval.near < interval.far)
+            })
+            .collect();
+    }
+
+    intervals
+}
+
+
+
+/// Intersection of a ray with one of several volumes
+#[derive(Clone, Debug, PartialEq, Copy)]
+pub struct VolumeHit {
+    /// Index of the volume
+    pub index: usize,
+    pub near: f32,
+    pub far: f32,
+}
+
+/// Intersects the world space ray with boxes placed into the world by transforms.
+/// Distances are measured along the world ray, volumes behind its origin are culled
+/// and the rest are sorted front to back.
+pub fn intersect_ray_volumes(
+    ray: &Ray, volumes: impl IntoIterator<Item = (Aabb, Transform)>,
+) -> Vec<VolumeHit> {
+    let mut hits = volumes.into_iter()
+        .enumerate()
+        .filter_map(|(index, (aabb, transform))| {
+            let (near, far) = aabb.intersect(&transform.ray_to_model(ray))?;
+
+            (far >= 0.0).then_some(VolumeHit {
+                index,
+                near: transform.scale * near.max(0.0),
+                far: transform.scale * far,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    hits.sort_by(|lhs, rhs| lhs.near.total_cmp(&rhs.near));
+
+    hits
+}
+
+/// Joins overlapping intervals sorted by their start
+pub fn merge_intervals(intervals: impl IntoIterator<Item = (f32, f32)>) -> Vec<(f32, f32)> {
+    let mut merged = Vec::<(f32, f32)>::new();
+
+    for (near, far) in intervals {
+        match merged.last_mut() {
+            Some((_, last_far)) if near <= *last_far => *last_far = last_far.max(far),
+            _ => merged.push((near, far)),
+        }
+    }
+
+    merged
+}
+
+
+
+/// Similarity transform from model to world space: `world = rotation * (scale * model) + translation`.
+#[repr(C)]
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+#[derive(Pod, Zeroable)]
+#[serde(default)]
+pub struct Transform {
+    /// Euler angles in degrees around X, Y and Z axes, applied in this order
+    pub rotation: Vec3,
+    pub translation: Vec3,
+    /// Uniform scale, positive and finite
+    #[serde(deserialize_with = "deserialize_scale")]
+    pub scale: f32,
+}
+
+fn deserialize_scale<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let scale = f32::deserialize(deserializer)?;
+
+    match scale.is_finite() && scale > 0.0 {
+        true => Ok(scale),
+        false => Err(de::Error::custom(format!("transform scale {scale} is not positive and finite"))),
+    }
+}
+
+impl Transform {
+    pub const IDENTITY: Self = Self { rotation: Vec3::ZERO, translation: Vec3::ZERO, scale: 1.0 };
+
+    /// Rotation from Euler angles in degrees around X, Y and Z axes, applied in this order
+    pub fn rotation_from_euler(degrees: Vec3) -> Quat {
+        let Vec3 { x, y, z } = degrees * (std::f32::consts::PI / 180.0);
+
+        Quat::from_rotation_z(z) * Quat::from_rotation_y(y) * Quat::from_rotation_x(x)
+    }
+
+    pub fn rotation_quat(&self) -> Quat {
+        Self::rotation_from_euler(self.rotation)
+    }
+
+    pub fn to_world(&self, point: Vec3) -> Vec3 {
+        self.rotation_quat() * (self.scale * point) + self.translation
+    }
+
+    pub fn to_model(&self, point: Vec3) -> Vec3 {
+        self.rotation_quat().inverse() * (point - self.translation) / self.scale
+    }
+
+    /// Ray in model space with a unit direction, so that distances along it
+    /// are world distances divided by the scale
+    pub fn ray_to_model(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: self.to_model(ray.origin),
+            direction: self.rotation_quat().inverse() * ray.direction,
+        }
+    }
+
+    /// Smallest world space box containing the transformed model space box
+    pub fn aabb_to_world(&self, aabb: &Aabb) -> Aabb {
+        Aabb::from_points(aabb.corners().map(|corner| self.to_world(corner)))
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_rejects_degenerate_scale() {
+        let transform = |scale: &str| toml::from_str::<Transform>(&format!("scale = {scale}"));
+
+        assert_eq!(transform("2.5").unwrap().scale, 2.5);
+        assert_eq!(toml::from_str::<Transform>("").unwrap(), Transform::IDENTITY);
+
+        for scale in ["0.0", "-1.0", "nan", "inf"] {
+            assert!(transform(scale).is_err(), "scale {scale} is accepted");
+        }
+    }
+
+    /// Pieces of the [0, 10] segment of the X axis left by the primitives
+    fn clip_x_axis(intervals: &[(f32, f32)], primitives: &[ClipPrimitive]) -> Vec<(f32, f32, bool)> {
+        let ray = Ray { origin: Vec3::ZERO, direction: Vec3::X };
+
+        clip_intervals(&ray, intervals.iter().copied(), primitives).into_iter()
+            .map(|ClippedInterval { near, far, cut }| (near, far, cut))
+            .collect()
+    }
+
+    fn primitive(shape: ClipShape, invert: bool) -> ClipPrimitive {
+        ClipPrimitive { shape, invert }
+    }
+
+    #[test]
+    fn primitives_cut_their_inside_or_outside() {
+        let segment = [(0.0, 10.0)];
+
+        let plane = ClipShape::Plane(Plane { point: vec3(3.0, 0.0, 0.0), normal: Vec3::X });
+        assert_eq!(clip_x_axis(&segment, &[primitive(plane, false)]), [(0.0, 3.0, false)]);
+        assert_eq!(clip_x_axis(&segment, &[primitive(plane, true)]), [(3.0, 10.0, true)]);
+
+        let sphere = ClipShape::Sphere(Sphere { center: vec3(5.0, 0.0, 0.0), radius: 1.0 });
+        assert_eq!(clip_x_axis(&segment, &[primitive(sphere, false)]), [(0.0, 4.0, false), (6.0, 10.0, true)]);
+        assert_eq!(clip_x_axis(&segment, &[primitive(sphere, true)]), [(4.0, 6.0, true)]);
+
+        let aabb = ClipShape::Box(Aabb::new(vec3(2.0, -1.0, -1.0), vec3(3.0, 1.0, 1.0)));
+        assert_eq!(
+            clip_x_axis(&segment, &[primitive(sphere, false), primitive(aabb, false)]),
+            [(0.0, 2.0, false), (3.0, 4.0, true), (6.0, 10.0, true)],
+        );
+
+        // the ray misses the primitive
+        let missed = ClipShape::Sphere(Sphere { center: vec3(5.0, 5.0, 0.0), radius: 1.0 });
+        assert_eq!(clip_x_axis(&segment, &[primitive(missed, false)]), [(0.0, 10.0, false)]);
+        assert_eq!(clip_x_axis(&segment, &[primitive(missed, true)]), []);
+
+        // only the surfaces inside of the intervals are cuts
+        let behind = ClipShape::Sphere(Sphere { center: vec3(-5.0, 0.0, 0.0), radius: 1.0 });
+        assert_eq!(clip_x_axis(&segment, &[primitive(behind, false)]), [(0.0, 10.0, false)]);
+
+        let around = ClipShape::Sphere(Sphere { center: Vec3::ZERO, radius: 2.0 });
+        assert_eq!(clip_x_axis(&segment, &[primitive(around, false)]), [(2.0, 10.0, true)]);
+
+        let middle = ClipShape::Sphere(Sphere { center: vec3(4.0, 0.0, 0.0), radius: 1.0 });
+        assert_eq!(
+            clip_x_axis(&[(0.0, 2.0), (4.0, 8.0)], &[primitive(middle, false)]),
+            [(0.0, 2.0, false), (5.0, 8.0, true)],
+        );
+    }
+
+    #[test]
+    fn rays_parallel_to_planes_are_either_kept_or_cut() {
+        let plane = Plane { point: Vec3::ZERO, normal: Vec3::Y };
+
+        let above = Ray { origin: Vec3::Y, direction: Vec3::X };
+        let below = Ray { origin: -Vec3::Y, direction: Vec3::X };
+
+        assert_eq!(plane.intersect(&above), Some((f32::NEG_INFINITY, f32::INFINITY)));
+        assert_eq!(plane.intersect(&below), None);
+        assert_eq!(plane.intersect(&Ray { origin: -Vec3::Y, direction: Vec3::Y }), Some((1.0, f32::INFINITY)));
+    }
+
+    #[test]
+    fn primitives_are_read_by_their_shape() {
+        let clipping = toml::from_str::<crate::graphics::Clipping>(r#"
+            cap_color = [1.0, 0.0, 0.0]
+
+            [[primitive]]
+            shape = "plane"
+            point = [0.0, 0.0, 0.0]
+            normal = [0.0, 1.0, 0.0]
+
+            [[primitive]]
+            shape = "box"
+            lo = [0.0, 0.0, 0.0]
+            hi = [1.0, 1.0, 1.0]
+            invert = true
+        "#).unwrap();
+
+        assert_eq!(clipping.primitives, [
+            primitive(ClipShape::Plane(Plane { point: Vec3::ZERO, normal: Vec3::Y }), false),
+            primitive(ClipShape::Box(Aabb::default()), true),
+        ]);
+        assert_eq!(clipping.cap_color, Some(Vec3::X));
+        assert_eq!(clipping.cap_density, 0.0);
+    }
+}
@@ -1,8 +1,13 @@
 use bytemuck::{Pod, Zeroable};
 use glam::*;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use thiserror::Error;
-use crate::geometry::{Ray, Aabb};
+use crate::{
+    geometry::{Ray, Aabb, ClipPrimitive, Transform},
+    render_gpu::GpuSampling,
+    spherical::{BorderMode, Filtering},
+    transfer::TransferFunction,
+};
 
 
 
@@ -154,24 +159,123 @@ pub struct RenderTargetParseU32Error(pub u32);
 
 
 
-#[repr(C)]
-#[derive(Clone, Debug, PartialEq, Copy)]
+/// How the samples along a ray are combined into the pixel. Projections reduce the density
+/// to a single value, which is colored by the transfer function.
+#[repr(u32)]
+#[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integration {
+    /// Emission-absorption compositing
+    #[default]
+    Composite = 0,
+    /// Maximum intensity projection
+    #[serde(rename = "mip")]
+    Maximum = 1,
+    /// Minimum intensity projection
+    #[serde(rename = "minip")]
+    Minimum = 2,
+    /// Average intensity projection
+    Average = 3,
+    /// Shaded first crossing of the iso-level of the density
+    Isosurface = 4,
+    /// Single scattering of the lights by the density as a participating medium
+    Scattering = 5,
+}
+
+impl std::str::FromStr for Integration {
+    type Err = IntegrationParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "composite" => Self::Composite,
+            "mip" => Self::Maximum,
+            "minip" => Self::Minimum,
+            "average" => Self::Average,
+            "isosurface" => Self::Isosurface,
+            "scattering" => Self::Scattering,
+            _ => return Err(IntegrationParseError(s.to_owned())),
+        })
+    }
+}
+
+impl std::fmt::Display for Integration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Composite => "composite",
+            Self::Maximum => "mip",
+            Self::Minimum => "minip",
+            Self::Average => "average",
+            Self::Isosurface => "isosurface",
+            Self::Scattering => "scattering",
+        })
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid integration '{0}', valid values are: 'composite', 'mip', 'minip', 'average', 'isosurface' and 'scattering'")]
+pub struct IntegrationParseError(pub String);
+
+
+
+#[derive(Clone, Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
-#[derive(Pod, Zeroable)]
 pub struct RenderConfiguration {
     pub camera: Camera,
     pub rm_settings: RaymarchSettings,
     pub bounding_box: Aabb,
+    /// Placement of the model in the world, the bounding box is in model space
+    #[serde(default)]
+    pub transform: Transform,
+    /// Reconstruction filter of the model
+    #[serde(default)]
+    pub filtering: Filtering,
+    /// Cells read by the filters outside of the model
+    #[serde(default)]
+    pub border: BorderMode,
+    /// Combination of the samples along the rays
+    #[serde(default)]
+    pub integration: Integration,
+    #[serde(default)]
+    pub gpu_sampling: GpuSampling,
+    #[serde(default)]
+    pub clipping: Clipping,
+    /// Colors and opacities of the density render target
+    #[serde(default)]
+    pub transfer: TransferFunction,
+    #[serde(default)]
+    pub isosurface: Isosurface,
+    #[serde(default)]
+    pub scattering: Scattering,
     #[serde(skip)]
     pub render_target: u32,
 }
 
+impl RenderConfiguration {
+    /// Whether the image is colored by the transfer function
+    pub fn uses_transfer(&self) -> bool {
+        self.render_target == RENDER_TARGET_DENSITY
+            || matches!(self.integration, Integration::Maximum | Integration::Minimum | Integration::Average)
+    }
+}
+
 impl Default for RenderConfiguration {
     fn default() -> Self {
         Self {
             camera: Camera::default(),
             rm_settings: RaymarchSettings::default(),
             bounding_box: Aabb::default().with_translation(Vec3::splat(-0.5)),
+            transform: Transform::IDENTITY,
+            filtering: Filtering::default(),
+            border: BorderMode::default(),
+            integration: Integration::default(),
+            gpu_sampling: GpuSampling::default(),
+            clipping: Clipping::default(),
+            transfer: TransferFunction::default(),
+            isosurface: Isosurface::default(),
+            scattering: Scattering::default(),
             render_target: RENDER_TARGET_COLOR,
         }
     }
@@ -179,6 +283,203 @@ impl Default for RenderConfiguration {
 
 
 
+/// Cutaway of the volumes, written as a `[clipping]` table with a `[[clipping.primitive]]`
+/// table per primitive. Primitives are placed in world space.
+#[derive(Clone, Debug, PartialEq, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct Clipping {
+    #[serde(default, rename = "primitive")]
+    pub primitives: Vec<ClipPrimitive>,
+    /// Color of the cut surfaces where the density is above `cap_density`,
+    /// the cut surfaces are not drawn without it
+    #[serde(default)]
+    pub cap_color: Option<Vec3>,
+    #[serde(default)]
+    pub cap_density: f32,
+}
+
+
+
+/// Surface where the density crosses the level, written as an `[isosurface]` table with
+/// an `[[isosurface.light]]` table per light. The surface is colored by the field.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Isosurface {
+    /// Density per world space unit, which is the model one divided by the scale of the model
+    pub level: f32,
+    /// Bisection steps refining the crossing between two samples of the ray
+    pub refinement_steps: u32,
+    pub shading: Shading,
+    /// Lights in world space, a light from the camera is used without them
+    #[serde(rename = "light")]
+    pub lights: Vec<DirectionalLight>,
+    /// Fraction of the surface color lit regardless of the lights
+    pub ambient: f32,
+    /// Intensity and exponent of the Blinn-Phong highlights
+    pub specular: f32,
+    pub shininess: f32,
+}
+
+impl Isosurface {
+    /// Color of the surface with the normal seen along the direction
+    pub fn shade(&self, albedo: Vec3, normal: Vec3, view_direction: Vec3) -> Vec3 {
+        let to_camera = -view_direction;
+        let headlight = [DirectionalLight { direction: to_camera, color: Vec3::ONE }];
+
+        let lights = match self.lights.is_empty() {
+            true => &headlight[..],
+            false => &self.lights[..],
+        };
+
+        lights.iter().fold(self.ambient * albedo, |color, light| {
+            let to_light = light.direction.normalize_or_zero();
+            let diffuse = albedo * normal.dot(to_light).max(0.0);
+
+            let specular = match self.shading {
+                Shading::Lambert => 0.0,
+                Shading::BlinnPhong => {
+                    let half = (to_light + to_camera).normalize_or_zero();
+                    self.specular * normal.dot(half).max(0.0).powf(self.shininess)
+                },
+            };
+
+            color + light.color * (diffuse + Vec3::splat(specular))
+        })
+    }
+}
+
+impl Default for Isosurface {
+    fn default() -> Self {
+        Self {
+            level: 1.0,
+            refinement_steps: 8,
+            shading: Shading::default(),
+            lights: Vec::new(),
+            ambient: 0.1,
+            specular: 0.5,
+            shininess: 32.0,
+        }
+    }
+}
+
+
+
+#[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shading {
+    #[default]
+    Lambert,
+    BlinnPhong,
+}
+
+
+
+/// Light coming from infinitely far away
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct DirectionalLight {
+    /// Direction towards the light, it doesn't have to be normalized but can't be zero
+    #[serde(deserialize_with = "deserialize_direction")]
+    pub direction: Vec3,
+    pub color: Vec3,
+}
+
+fn deserialize_direction<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec3, D::Error> {
+    let direction = Vec3::deserialize(deserializer)?;
+
+    match direction.try_normalize() {
+        Some(_) => Ok(direction),
+        None => Err(de::Error::custom(format!("light direction {direction} can't be normalized"))),
+    }
+}
+
+
+
+/// Lighting of the density as a participating medium, written as a `[scattering]` table with
+/// an `[[scattering.light]]` table per light. The medium scatters the light once with the albedo
+/// of the constant spherical harmonic and may still emit the baked radiance.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scattering {
+    /// Lights in world space, a light from the camera is used without them
+    #[serde(rename = "light")]
+    pub lights: Vec<Light>,
+    /// Henyey-Greenstein asymmetry in [-1, 1], positive values scatter forward
+    pub anisotropy: f32,
+    /// Weight of the baked radiance added to the scattered light
+    pub emission: f32,
+    /// Samples of the shadow rays towards the lights
+    pub shadow_steps: u32,
+}
+
+impl Scattering {
+    /// Color of the light from the camera used without the configured lights. It cancels
+    /// the normalization of the isotropic phase function, so that the medium looks as bright
+    /// as the albedo where the light isn't shadowed.
+    pub const HEADLIGHT_COLOR: Vec3 = Vec3::splat(4.0 * std::f32::consts::PI);
+
+    /// Henyey-Greenstein phase function of the cosine between the directions of the light
+    /// before and after scattering
+    pub fn phase(&self, cos_theta: f32) -> f32 {
+        let g = self.anisotropy;
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+
+        (1.0 - g * g) / (4.0 * std::f32::consts::PI * denominator * denominator.sqrt())
+    }
+}
+
+impl Default for Scattering {
+    fn default() -> Self {
+        Self {
+            lights: Vec::new(),
+            anisotropy: 0.0,
+            emission: 0.0,
+            shadow_steps: 32,
+        }
+    }
+}
+
+
+
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Light {
+    Directional(DirectionalLight),
+    Point(PointLight),
+}
+
+impl Light {
+    /// Direction towards the light from the point, the distance to it and the light arriving at the point
+    pub fn illuminate(&self, point: Vec3) -> (Vec3, f32, Vec3) {
+        match self {
+            Self::Directional(light) => (light.direction.normalize_or_zero(), f32::INFINITY, light.color),
+            Self::Point(light) => {
+                let offset = light.position - point;
+                let distance = offset.length();
+
+                (offset.normalize_or_zero(), distance, light.color / distance.max(f32::EPSILON).powi(2))
+            },
+        }
+    }
+}
+
+
+
+/// Light radiating from a point, its intensity falls off with the squared distance
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct PointLight {
+    pub position: Vec3,
+    /// Light at the unit distance
+    pub color: Vec3,
+}
+
+
+
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq, Copy, Eq, Hash)]
 #[derive(Serialize, Deserialize)]
@@ -4,15 +4,52 @@ pub mod spherical;
 pub mod render_gpu;
 pub mod graphics;
 pub mod benchmark;
+pub mod storage;
+pub mod model;
+pub mod sparse;
+pub mod npy;
+pub mod plenoxels;
+pub mod nrrd;
+pub mod statistics;
+pub mod resample;
+pub mod scene;
+pub mod mesh;
+pub mod point_cloud;
+pub mod splat;
+pub mod ply;
+pub mod colormap;
+pub mod slices;
+pub mod voxelize;
+pub mod procedural;
+pub mod transfer;
 
 use anyhow::Result as AnyResult;
 use glam::*;
-use graphics::{Color, RenderTarget, RenderConfiguration};
-use render_gpu::GpuContextMode;
-use spherical::RadianceField;
-use clap::Parser;
+use graphics::{Color, Integration, RenderTarget, RenderConfiguration};
+use render_gpu::{GpuContextMode, GpuSampling};
+use spherical::{BorderMode, Channel, Filtering, Grid, RadianceField, ResizedGrid};
+use clap::{Parser, Subcommand};
 use thiserror::Error;
 use benchmark::Bench;
+use model::{with_grid, with_model, AnyModel, Model, Storage};
+use storage::ErrorReport;
+use geometry::{Aabb, Transform};
+use npy::NpyArchive;
+use plenoxels::PlenoxelsCheckpoint;
+use nrrd::{Volume, VolumeColor};
+use statistics::{FieldStatistics, ValidationReport};
+use resample::ResampleFilter;
+use scene::SceneDescription;
+use render_cpu::DynSceneVolume;
+use mesh::{Mesh, VertexColor};
+use point_cloud::PointCloud;
+use splat::GaussianCloud;
+use ply::Ply;
+use colormap::Colormap;
+use slices::{DensityCurve, SliceAxis, SliceColor, SliceImport, SliceStack};
+use voxelize::{VoxelShape, Voxelization};
+use procedural::{Procedural, ProceduralKind};
+use std::path::Path;
 
 
 
@@ -36,79 +73,767 @@ pub fn make_textures(field: &RadianceField) -> [Vec<u8>; 9] {
 
 #[tokio::main]
 async fn main() -> AnyResult<()> {
-    const SCREEN_WIDTH: usize = 256;
-    const SCREEN_HEIGHT: usize = 256;
-
     let args = Args::parse();
 
+    match args.command {
+        Some(Command::Render(render)) => run_render(render).await,
+        Some(Command::Convert(convert)) => run_convert(convert).await,
+        Some(Command::Validate(validate)) => run_validate(validate).await,
+        Some(Command::Mesh(mesh)) => run_mesh(mesh).await,
+        Some(Command::Points(points)) => run_points(points).await,
+        Some(Command::Splats(splats)) => run_splats(splats).await,
+        Some(Command::Generate(generate)) => run_generate(generate).await,
+        None => run_render(args.render).await,
+    }
+}
+
+/// Loads a model file or imports a Plenoxels checkpoint, which is either an `.npz` archive
+/// or a directory with `.npy` files, a NRRD density volume, a PLY file with Gaussian splats,
+/// a PLY or OBJ mesh or a directory of PNG slices.
+/// Imported models come with their bounding box.
+async fn load_model(
+    path: impl AsRef<Path>, import: &ImportArgs,
+) -> AnyResult<(AnyModel, Option<Aabb>)> {
+    let path = path.as_ref();
+
+    let archive = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("nhdr" | "nrrd") => {
+            eprintln!("Reading density volume...");
+
+            let density = Volume::read(path)?;
+
+            let color = match (&import.color_volume, import.color) {
+                (Some(path), _) => VolumeColor::Volume(Volume::read(path)?),
+                (None, Some(color)) => VolumeColor::Constant(color),
+                (None, None) => VolumeColor::default(),
+            };
+
+            let field = density.to_field(&color)?;
+            let model = AnyModel::encode(&field, 0, Storage::Float32, 0.0)?;
+
+            return Ok((model, Some(density.bounding_box)));
+        },
+        Some("ply" | "obj") => {
+            let ply = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("ply") => Some(Ply::read(std::io::BufReader::new(std::fs::File::open(path)?))?),
+                _ => None,
+            };
+
+            // PLY files with faces are meshes, vertices with colors and opacities are Gaussian splats
+            let color = import.color.unwrap_or(Vec3::ONE);
+
+            let mesh = match &ply {
+                Some(ply) if ply.element("face").is_ok() => Some(Mesh::from_ply(ply, color)?),
+                Some(ply) if GaussianCloud::is_splat_ply(ply) => None,
+                Some(_) => anyhow::bail!(
+                    "PLY file '{}' is neither a mesh with faces nor 3D Gaussian splats \
+                     with 'f_dc_*' and 'opacity' vertex properties",
+                    path.display(),
+                ),
+                None => Some(Mesh::read(path, color)?),
+            };
+
+            if let Some(mesh) = mesh {
+                let voxelization = Voxelization {
+                    shape: match import.shell {
+                        Some(thickness) => VoxelShape::Shell { thickness },
+                        None => VoxelShape::Solid,
+                    },
+                    density: import.mesh_density,
+                    coverage: import.coverage,
+                };
+
+                // the surface and its shell are kept off the border of the grid
+                let padding = 1.0 + import.shell.map_or(0.0, |thickness| 0.5 * thickness);
+                let (size, bounding_box) = cubic_grid(mesh.bounding_box(), import.resolution, padding);
+
+                eprintln!("Voxelizing {} triangles into a {size:?} grid...", mesh.triangles.len());
+
+                let field = mesh.voxelized(size, bounding_box, &voxelization);
+                let model = AnyModel::encode(&field, 0, Storage::Float32, 0.0)?;
+
+                return Ok((model, Some(bounding_box)));
+            }
+
+            eprintln!("Reading Gaussian splats...");
+
+            let splats = GaussianCloud::from_ply(ply.as_ref().expect("meshes are returned above"))?;
+            let (size, bounding_box) = cubic_grid(splats.bounding_box(), import.resolution, 0.0);
+
+            eprintln!("Rasterizing {} Gaussians into a {size:?} grid...", splats.len());
+
+            // degree 3 coefficients are dropped
+            let model = match splats.sh_width {
+                1 => AnyModel::encode(&splats.rasterize::<1>(size, bounding_box), 0, Storage::Float32, 0.0)?,
+                4 => AnyModel::encode(&splats.rasterize::<4>(size, bounding_box), 1, Storage::Float32, 0.0)?,
+                _ => AnyModel::encode(&splats.rasterize::<9>(size, bounding_box), 2, Storage::Float32, 0.0)?,
+            };
+
+            return Ok((model, Some(bounding_box)));
+        },
+        Some("npz") => {
+            eprintln!("Reading Plenoxels checkpoint from archive...");
+            NpyArchive::read_npz(std::io::BufReader::new(std::fs::File::open(path)?))?
+        },
+        Some("npy") => {
+            eprintln!("Reading Plenoxels checkpoint from directory...");
+            NpyArchive::read_dir(path.parent().unwrap_or(Path::new(".")))?
+        },
+        // checkpoints may come with preview images, so arrays are looked for first
+        _ if NpyArchive::is_npy_dir(path) => {
+            eprintln!("Reading Plenoxels checkpoint from directory...");
+            NpyArchive::read_dir(path)?
+        },
+        _ if SliceStack::is_slice_dir(path) => {
+            let stack = SliceStack::find(path)?;
+            let axis = import.slice_axis;
+
+            eprintln!(
+                "Importing {} slices of {:?} pixels into a {:?} grid...",
+                stack.paths.len(), stack.image_size, stack.size(axis),
+            );
+
+            let color = match (import.colormap, import.color) {
+                (Some(colormap), _) => SliceColor::Colormap(colormap),
+                (None, Some(color)) => SliceColor::Constant(color),
+                (None, None) => SliceColor::Image,
+            };
+
+            let field = stack.to_field(&SliceImport {
+                axis,
+                density_scale: import.slice_scale,
+                curve: import.slice_curve,
+                color,
+            })?;
+
+            let model = AnyModel::encode(&field, 0, Storage::Float32, 0.0)?;
+
+            return Ok((model, Some(stack.bounding_box(axis, import.spacing))));
+        },
+        _ if path.is_dir() => {
+            eprintln!("Reading Plenoxels checkpoint from directory...");
+            NpyArchive::read_dir(path)?
+        },
+        _ => {
+            eprintln!("Reading model from file...");
+            return Ok((AnyModel::from_bytes(&tokio::fs::read(path).await?)?, None));
+        },
+    };
+
+    let checkpoint = PlenoxelsCheckpoint::from_archive(&archive)?;
+
+    eprintln!(
+        "Importing {} voxels of a {:?} grid with spherical harmonics of degree {}...",
+        checkpoint.n_voxels(), checkpoint.size, checkpoint.sh_degree(),
+    );
+
+    // empty cells have no density, so only occupied bricks are kept
+    let model = AnyModel::encode::<16>(&checkpoint, checkpoint.sh_degree(), Storage::Sparse, 0.0)?;
+
+    Ok((model, Some(checkpoint.bounding_box)))
+}
+
+/// Grid of cubic cells with the resolution along the longest axis of the box,
+/// which is extended by the padding in cells on every side
+fn cubic_grid(bounding_box: Aabb, resolution: usize, padding: f32) -> ([usize; 3], Aabb) {
+    let extent = bounding_box.hi - bounding_box.lo;
+    let cell_size = extent.max_element() / resolution as f32;
+    let size = (extent / cell_size + 2.0 * padding).ceil().to_array().map(|len| (len as usize).max(1));
+
+    let center = 0.5 * (bounding_box.lo + bounding_box.hi);
+    let half = 0.5 * cell_size * RadianceField::size_vec(size);
+
+    (size, Aabb::new(center - half, center + half))
+}
+
+const SCREEN_WIDTH: usize = 256;
+const SCREEN_HEIGHT: usize = 256;
+
+async fn run_render(args: RenderArgs) -> AnyResult<()> {
+    if let Some(scene) = &args.scene {
+        return run_render_scene(scene, &args).await;
+    }
+
     eprintln!("Reading rendering configuration from file...");
 
     let cfg = {
         let mut cfg: RenderConfiguration = toml::from_str(
-            &tokio::fs::read_to_string("assets/render_configuration.toml").await?,
+            &tokio::fs::read_to_string(&args.config).await?,
         )?;
         
         cfg.render_target = args.target as u32;
+        cfg.filtering = args.filtering.unwrap_or(cfg.filtering);
+        cfg.border = args.border.unwrap_or(cfg.border);
+        cfg.integration = args.integration.unwrap_or(cfg.integration);
+        cfg.isosurface.level = args.iso_level.unwrap_or(cfg.isosurface.level);
+        cfg.gpu_sampling = args.sampling.unwrap_or(cfg.gpu_sampling);
         cfg
     };
 
-    eprintln!("Reading model from file...");
+    let (model, bounding_box) = load_model(&args.model, &args.import).await?;
 
-    let field = bincode::deserialize::<RadianceField>(
-        &tokio::fs::read("assets/model.bin").await?,
-    )?;
+    let mut cfg = RenderConfiguration {
+        bounding_box: bounding_box.unwrap_or(cfg.bounding_box),
+        ..cfg
+    };
+
+    if cfg.uses_transfer() && cfg.transfer.range.is_none() {
+        let range = with_model!(&model, |model| transfer::detect_density_range(model));
+        eprintln!("Detected density range: [{}, {}]", range[0], range[1]);
+        cfg.transfer.range = Some(range);
+    }
 
     let mut bench = Bench::new();
 
     let image = match args.r#type {
+        MethodType::Gpu if matches!(cfg.integration, Integration::Isosurface | Integration::Scattering) => {
+            anyhow::bail!("'{}' integration is rendered on CPU only, use `--type multicpu`", cfg.integration)
+        },
         MethodType::Gpu => {
             let ctx = render_gpu::GpuContext::new(render_gpu::GpuContextMode::Debug).await?;
-            render_gpu::render_gpu(SCREEN_WIDTH, SCREEN_HEIGHT, &ctx, &field, &cfg, &mut bench)
+
+            let format = with_model!(&model, |model| model.texture_format());
+
+            with_grid!(&model, |grid| render_gpu::render_gpu(
+                SCREEN_WIDTH, SCREEN_HEIGHT, &ctx, grid, format, &cfg, &mut bench,
+            ))
         },
-        MethodType::MultiCpu
-            => render_cpu::render_multicpu(SCREEN_WIDTH, SCREEN_HEIGHT, &field, &cfg, &mut bench),
-        MethodType::SingleCpu
-            => render_cpu::render_singlecpu(SCREEN_WIDTH, SCREEN_HEIGHT, &field, &cfg, &mut bench),
+        MethodType::MultiCpu => with_grid!(&model, |grid| render_cpu::render_multicpu(
+            SCREEN_WIDTH, SCREEN_HEIGHT, grid, &cfg, &mut bench,
+        )),
+        MethodType::SingleCpu => with_grid!(&model, |grid| render_cpu::render_singlecpu(
+            SCREEN_WIDTH, SCREEN_HEIGHT, grid, &cfg, &mut bench,
+        )),
+    };
+
+    if args.bench {
+        println!("{}", bench.total());
+    }
+
+    write_image(&args.out, SCREEN_WIDTH, SCREEN_HEIGHT, &image)
+}
+
+/// Renders several fields described by the scene file on CPU
+async fn run_render_scene(path: &str, args: &RenderArgs) -> AnyResult<()> {
+    eprintln!("Reading scene description from file...");
+
+    let scene: SceneDescription = toml::from_str(&tokio::fs::read_to_string(path).await?)?;
+
+    let scene_cfg = scene.configuration();
+
+    let mut cfg = RenderConfiguration {
+        render_target: args.target as u32,
+        filtering: args.filtering.unwrap_or(scene_cfg.filtering),
+        border: args.border.unwrap_or(scene_cfg.border),
+        integration: args.integration.unwrap_or(scene_cfg.integration),
+        ..scene_cfg
+    };
+
+    cfg.isosurface.level = args.iso_level.unwrap_or(cfg.isosurface.level);
+
+    let mut fields = Vec::with_capacity(scene.fields.len());
+
+    for (field, model_path) in scene.fields.iter().zip(scene.model_paths(path)) {
+        let (model, bounding_box) = load_model(&model_path, &args.import).await?;
+
+        let bounding_box = field.bounding_box
+            .or(bounding_box)
+            .unwrap_or(RenderConfiguration::default().bounding_box);
+
+        fields.push((model, bounding_box, field.transform));
+    }
+
+    if cfg.uses_transfer() && cfg.transfer.range.is_none() {
+        // the range covers the densest of the fields
+        let range = fields.iter()
+            .map(|(model, ..)| with_model!(model, |model| transfer::detect_density_range(model)))
+            .fold([0.0, 0.0], |[lo, hi], [field_lo, field_hi]| [f32::min(lo, field_lo), f32::max(hi, field_hi)]);
+
+        eprintln!("Detected density range: [{}, {}]", range[0], range[1]);
+        cfg.transfer.range = Some(range);
+    }
+
+    let volumes = fields.iter()
+        .map(|(model, bounding_box, transform)| with_grid!(model, |grid| {
+            DynSceneVolume::new(grid, *bounding_box, *transform, cfg.filtering, cfg.border)
+        }))
+        .collect::<Vec<_>>();
+
+    let mut bench = Bench::new();
+
+    let image = match args.r#type {
+        MethodType::Gpu => anyhow::bail!("scenes are rendered on CPU only, use `--type multicpu`"),
+        MethodType::MultiCpu => render_cpu::render_scene_multicpu(
+            SCREEN_WIDTH, SCREEN_HEIGHT, &volumes, &cfg, &mut bench,
+        ),
+        MethodType::SingleCpu => render_cpu::render_scene_singlecpu(
+            SCREEN_WIDTH, SCREEN_HEIGHT, &volumes, &cfg, &mut bench,
+        ),
     };
 
     if args.bench {
         println!("{}", bench.total());
     }
 
-    let file = std::fs::File::create(&args.out)?;
+    write_image(&args.out, SCREEN_WIDTH, SCREEN_HEIGHT, &image)
+}
+
+fn write_image(path: &str, width: usize, height: usize, image: &[u8]) -> AnyResult<()> {
+    let file = std::fs::File::create(path)?;
 
     let buf_writer = std::io::BufWriter::new(file);
 
     let mut encoder = png::Encoder::new(
-        buf_writer, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32,
+        buf_writer, width as u32, height as u32,
     );
 
     encoder.set_color(png::ColorType::Rgba);
 
     let mut writer = encoder.write_header()?;
-    writer.write_image_data(bytemuck::cast_slice(&image))?; 
+    writer.write_image_data(image)?; 
+
+    Ok(())
+}
+
+/// Reads the rendering configuration, missing one is replaced by the default
+async fn read_configuration_or_default(path: &str) -> AnyResult<RenderConfiguration> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(cfg) => Ok(toml::from_str(&cfg)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Bounding box of the rendering configuration or the default one
+async fn configured_bounding_box(config: Option<&str>) -> AnyResult<Aabb> {
+    Ok(match config {
+        Some(path) => read_configuration_or_default(path).await?.bounding_box,
+        None => RenderConfiguration::default().bounding_box,
+    })
+}
+
+async fn run_convert(args: ConvertArgs) -> AnyResult<()> {
+    anyhow::ensure!(
+        Path::new(&args.output).extension().is_none_or(|ext| ext != "ply"),
+        "models are not written as PLY files, use the `splats` command to export Gaussian splats",
+    );
+
+    let (mut input, mut bounding_box) = load_model(&args.input, &args.import).await?;
+
+    // transformed fields keep the storage of the input, so that a compact model
+    // doesn't stay in memory as a dense one until it is converted
+    let storage = input.storage();
+
+    if let Some([x0, y0, z0, x1, y1, z1]) = args.crop {
+        let (lo, hi) = ([x0, y0, z0], [x1, y1, z1]);
+
+        let base = match bounding_box {
+            Some(bounding_box) => bounding_box,
+            None => configured_bounding_box(args.config.as_deref()).await?,
+        };
+
+        eprintln!("Cropping model to cells {lo:?}..{hi:?}...");
+
+        let size = with_model!(&input, |input| input.size());
+        bounding_box = Some(resample::crop_bounding_box(base, size, lo, hi));
+
+        input = with_model!(&input, |model| AnyModel::encode(
+            &RadianceField::cropped(model, lo, hi)?, input.sh_degree(), storage, 0.0,
+        ))?;
+    }
+
+    if let Some(degrees) = args.rotate {
+        let base = match bounding_box {
+            Some(bounding_box) => bounding_box,
+            None => configured_bounding_box(args.config.as_deref()).await?,
+        };
+
+        eprintln!("Rotating model by {degrees} degrees...");
+
+        let rotation = Transform::rotation_from_euler(degrees);
+        let (rotated, rotated_box) = with_model!(&input, |model| {
+            let (field, rotated_box) = RadianceField::rotated(model, base, rotation);
+            (AnyModel::encode(&field, input.sh_degree(), storage, 0.0), rotated_box)
+        });
+
+        bounding_box = Some(rotated_box);
+        input = rotated?;
+    }
+
+    if let Some(new_size) = args.resize {
+        let size = with_model!(&input, |input| input.size());
+
+        let filter = args.filter.unwrap_or(match (0..3).all(|i| new_size[i] <= size[i]) {
+            true => ResampleFilter::Box,
+            false => ResampleFilter::Trilinear,
+        });
+
+        eprintln!("Resampling model from {size:?} to {new_size:?} with '{filter}' filter...");
+
+        input = with_model!(&input, |model| AnyModel::encode(
+            &RadianceField::resampled(model, new_size, filter)?,
+            input.sh_degree(), storage, 0.0,
+        ))?;
+    }
+
+    let output_extension = Path::new(&args.output).extension().and_then(|ext| ext.to_str());
+
+    if let Some(extension @ ("nhdr" | "nrrd")) = output_extension {
+        let bounding_box = match bounding_box {
+            Some(bounding_box) => bounding_box,
+            None => configured_bounding_box(args.config.as_deref()).await?,
+        };
+
+        eprintln!("Exporting '{}' channel of the model...", args.channel);
+
+        let volume = with_model!(&input, |input| Volume::from_grid(input, args.channel, bounding_box))?;
+        match extension {
+            "nhdr" => volume.write_nhdr(&args.output)?,
+            _ => volume.write_nrrd(&args.output)?,
+        }
+
+        return Ok(());
+    }
+
+    let sh_degree = args.degree.unwrap_or(input.sh_degree());
+
+    eprintln!(
+        "Converting model to '{}' storage with spherical harmonics of degree {sh_degree}...",
+        args.storage,
+    );
+
+    let model = with_model!(&input, |input| {
+        let model = AnyModel::encode(input, sh_degree, args.storage, args.threshold)?;
+
+        if args.storage != Storage::Float32 {
+            with_model!(&model, |model| {
+                println!("{}", ErrorReport::compare(&ResizedGrid(input), model));
+
+                if let Model::Sparse(sparse) = model {
+                    println!(
+                        "occupied bricks: {} of {}",
+                        sparse.n_occupied_bricks(), sparse.brick_index.len(),
+                    );
+                }
+            });
+        }
+
+        model
+    });
+
+    eprintln!("Writing model to file...");
+
+    tokio::fs::write(&args.output, model.to_bytes()?).await?;
+
+    if let Some(bounding_box) = bounding_box {
+        match &args.config {
+            Some(path) => {
+                eprintln!("Writing bounding box to rendering configuration...");
+
+                let mut cfg = read_configuration_or_default(path).await?;
+                cfg.bounding_box = bounding_box;
+
+                tokio::fs::write(path, toml::to_string(&cfg)?).await?;
+            },
+            None => eprintln!(
+                "Bounding box of the model: lo = {}, hi = {}",
+                bounding_box.lo, bounding_box.hi,
+            ),
+        }
+    }
 
     Ok(())
 }
 
 
 
-/// Simple program to greet a person
+async fn run_validate(args: ValidateArgs) -> AnyResult<()> {
+    let report = match load_model(&args.model, &args.import).await {
+        Ok((model, _)) => {
+            eprintln!("Computing statistics...");
+
+            let statistics = with_model!(&model, |model| FieldStatistics::compute(model, args.threshold));
+            let issues = statistics.issues();
+
+            ValidationReport {
+                path: args.model.clone(),
+                valid: issues.is_empty(),
+                error: None,
+                storage: Some(model.storage().to_string()),
+                sh_degree: Some(model.sh_degree()),
+                issues,
+                warnings: statistics.warnings(),
+                statistics: Some(statistics),
+            }
+        },
+        Err(err) => ValidationReport {
+            path: args.model.clone(),
+            valid: false,
+            error: Some(format!("{err:#}")),
+            storage: None,
+            sh_degree: None,
+            issues: Vec::new(),
+            warnings: Vec::new(),
+            statistics: None,
+        },
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+
+    anyhow::ensure!(report.valid, "model '{}' is invalid", args.model);
+
+    Ok(())
+}
+
+async fn run_mesh(args: MeshArgs) -> AnyResult<()> {
+    anyhow::ensure!(args.iso >= 0.0, "iso level should not be negative, got {}", args.iso);
+    anyhow::ensure!(args.decimate != Some(0), "decimation cubes should have at least one cell");
+
+    let (model, bounding_box) = load_model(&args.model, &args.import).await?;
+
+    let bounding_box = match bounding_box {
+        Some(bounding_box) => bounding_box,
+        None => configured_bounding_box(args.config.as_deref()).await?,
+    };
+
+    let color = match args.direction {
+        Some(direction) => {
+            anyhow::ensure!(
+                direction.try_normalize().is_some(), "direction {direction} can't be normalized",
+            );
+            VertexColor::Direction(direction)
+        },
+        None => VertexColor::Dc,
+    };
+
+    eprintln!("Extracting isosurface at density {}...", args.iso);
+
+    let mut mesh = with_model!(&model, |model| Mesh::marching_cubes(model, bounding_box, args.iso, color));
+
+    if let Some(cells) = args.decimate {
+        let size = with_model!(&model, |model| model.size());
+        let cell_size = (bounding_box.hi - bounding_box.lo) / RadianceField::size_vec(size);
+
+        eprintln!("Decimating {} triangles...", mesh.triangles.len());
+
+        mesh = mesh.decimated(cells as f32 * cell_size);
+    }
+
+    eprintln!(
+        "Writing {} vertices and {} triangles to file...",
+        mesh.positions.len(), mesh.triangles.len(),
+    );
+
+    mesh.write(&args.output)?;
+
+    Ok(())
+}
+
+async fn run_points(args: PointsArgs) -> AnyResult<()> {
+    let (model, bounding_box) = load_model(&args.model, &args.import).await?;
+
+    let bounding_box = match bounding_box {
+        Some(bounding_box) => bounding_box,
+        None => configured_bounding_box(args.config.as_deref()).await?,
+    };
+
+    eprintln!("Collecting cells with density above {}...", args.threshold);
+
+    let mut points = with_model!(&model, |model| PointCloud::from_grid(
+        model, bounding_box, args.threshold, args.sh,
+    ));
+
+    if let Some(count) = args.count {
+        eprintln!("Subsampling {} points to {count}...", points.len());
+        points = points.subsampled(count, args.seed);
+    }
+
+    eprintln!("Writing {} points to file...", points.len());
+
+    points.write_ply(std::fs::File::create(&args.output)?)?;
+
+    Ok(())
+}
+
+async fn run_splats(args: SplatsArgs) -> AnyResult<()> {
+    let (model, bounding_box) = load_model(&args.model, &args.import).await?;
+
+    let bounding_box = match bounding_box {
+        Some(bounding_box) => bounding_box,
+        None => configured_bounding_box(args.config.as_deref()).await?,
+    };
+
+    let model = match args.degree {
+        Some(sh_degree) => with_model!(&model, |input| AnyModel::encode(
+            input, sh_degree, model.storage(), 0.0,
+        ))?,
+        None => model,
+    };
+
+    eprintln!("Exporting cells with density above {} as Gaussian splats...", args.threshold);
+
+    let splats = with_model!(&model, |model| GaussianCloud::from_grid(
+        model, bounding_box, args.threshold, args.cluster,
+    ));
+
+    eprintln!("Writing {} Gaussians to file...", splats.len());
+
+    splats.write_ply(std::fs::File::create(&args.output)?)?;
+
+    Ok(())
+}
+
+
+/// Generates a procedural field and renders its closed form if asked
+async fn run_generate(args: GenerateArgs) -> AnyResult<()> {
+    anyhow::ensure!(args.density >= 0.0, "density should not be negative, got {}", args.density);
+    let radius = args.radius.unwrap_or(args.kind.default_radius());
+
+    anyhow::ensure!(radius > 0.0, "radius should be positive, got {radius}");
+    anyhow::ensure!(args.count > 0, "count should be positive");
+
+    let procedural = match args.kind {
+        ProceduralKind::Cube => Procedural::Cube { density: args.density, color: args.color },
+        ProceduralKind::Sphere => Procedural::SoftSphere {
+            density: args.density, sigma: radius, color: args.color,
+        },
+        ProceduralKind::Checkerboard => Procedural::Checkerboard { density: args.density, blocks: args.count },
+        ProceduralKind::ShSpheres => {
+            anyhow::ensure!(
+                radius <= 0.5 / args.count as f32,
+                "spheres of radius {radius} overlap on a lattice of {} per axis", args.count,
+            );
+
+            Procedural::ShSpheres { density: args.density, count: args.count, radius }
+        },
+        ProceduralKind::Slab => Procedural::GradientSlab {
+            density: args.density, thickness: args.thickness, color: args.color,
+        },
+        ProceduralKind::Noise => Procedural::Noise {
+            density: args.density, frequency: args.count, seed: args.seed,
+        },
+    };
+
+    eprintln!("Generating {} field of size {:?}...", args.kind, args.size);
+
+    let field = procedural.generate(args.size);
+    let degree = args.degree.unwrap_or(procedural.sh_degree());
+
+    eprintln!("Writing model to file...");
+
+    let model = AnyModel::encode(&field, degree, args.storage, 0.0)?;
+    tokio::fs::write(&args.output, model.to_bytes()?).await?;
+
+    if let Some(path) = &args.expected {
+        let cfg = read_configuration_or_default(&args.config).await?;
+
+        let image = procedural.expected_image(SCREEN_WIDTH, SCREEN_HEIGHT, &cfg)
+            .ok_or_else(|| anyhow::anyhow!("{} field has no closed form render", args.kind))?;
+
+        write_image(path, SCREEN_WIDTH, SCREEN_HEIGHT, &image)?;
+    }
+
+    Ok(())
+}
+
+
+
+/// Radiance field volume renderer
 #[derive(Parser, Debug)]
 #[command(version, about = "Radiance field volume renderer", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    render: RenderArgs,
+}
+
+
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Render the model into an image (default)
+    Render(RenderArgs),
+
+    /// Convert the model into another storage and report the conversion error
+    Convert(ConvertArgs),
+
+    /// Check the model for corruption and print statistics of its channels
+    Validate(ValidateArgs),
+
+    /// Extract the density isosurface as a colored triangle mesh
+    Mesh(MeshArgs),
+
+    /// Export occupied cells as a colored point cloud
+    Points(PointsArgs),
+
+    /// Export occupied cells as 3D Gaussian splats
+    Splats(SplatsArgs),
+
+    /// Generate an analytic test field and optionally its expected render
+    Generate(GenerateArgs),
+}
+
+
+
+#[derive(clap::Args, Debug)]
+struct RenderArgs {
     /// Name of the output file
     #[arg(short, long, default_value_t = String::from("output/result.png"))]
     out: String,
 
+    /// Model file, Plenoxels checkpoint or NRRD density volume, whose bounding box
+    /// overrides the configured one
+    #[arg(long, default_value_t = String::from("assets/model.bin"))]
+    model: String,
+
+    /// Render configuration file
+    #[arg(long, default_value_t = String::from("assets/render_configuration.toml"))]
+    config: String,
+
+    /// Scene description with several fields to render on CPU instead of the model
+    /// and the configuration
+    #[arg(long)]
+    scene: Option<String>,
+
     /// Set render target
     #[arg(long, default_value_t = RenderTarget::Color)]
     target: RenderTarget,
 
+    /// Reconstruction filter overriding the configured one. Valid values are: nearest,
+    /// trilinear, bspline, catmull_rom.
+    #[arg(long)]
+    filtering: Option<Filtering>,
+
+    /// Cells read outside of the model overriding the configured ones. Valid values are:
+    /// zero, clamp, mirror, wrap.
+    #[arg(long)]
+    border: Option<BorderMode>,
+
+    /// Combination of the samples along the rays overriding the configured one. Valid values
+    /// are: composite, mip, minip, average, isosurface, scattering.
+    #[arg(long)]
+    integration: Option<Integration>,
+
+    /// Density of the rendered isosurface overriding the configured one
+    #[arg(long)]
+    iso_level: Option<f32>,
+
     /// Compute context
     #[arg(long, default_value_t = GpuContextMode::Debug)]
     mode: GpuContextMode,
 
+    /// Filtering of the model on GPU overriding the configured one: in the shader or by
+    /// the texture units where the texture format supports it. Valid values are: manual, hardware.
+    #[arg(long)]
+    sampling: Option<GpuSampling>,
+
     /// Enables benchmarking
     #[arg(long, short)]
     bench: bool,
@@ -116,6 +841,318 @@ struct Args {
     /// Computation method. Valid values are: singlecpu, multicpu, gpu.
     #[arg(long, short, default_value_t = MethodType::Gpu)]
     r#type: MethodType,
+
+    #[command(flatten)]
+    import: ImportArgs,
+}
+
+
+
+#[derive(clap::Args, Debug)]
+struct ImportArgs {
+    /// Constant color of a field imported from a NRRD density volume or PNG slices, e.g. `1,0.5,0`,
+    /// or of the mesh vertices without colors. White for volumes and meshes and the colors
+    /// of the pixels for slices by default.
+    #[arg(long, value_parser = parse_vec3)]
+    color: Option<Vec3>,
+
+    /// NRRD volume with 3 color components per voxel for a field imported from a density volume
+    #[arg(long)]
+    color_volume: Option<String>,
+
+    /// Number of cells along the longest axis of a field rasterized from Gaussian splats
+    /// or voxelized from a mesh
+    #[arg(long, default_value_t = 256)]
+    resolution: usize,
+
+    /// Axis along which PNG slices of a directory are stacked. Valid values are: x, y, z.
+    #[arg(long, default_value_t = SliceAxis::Z)]
+    slice_axis: SliceAxis,
+
+    /// Density of the cells imported from PNG slices with the full intensity
+    #[arg(long, default_value_t = 50.0)]
+    slice_scale: f32,
+
+    /// Mapping of the slice intensity before scaling. Valid values are:
+    /// linear, gamma:<exponent>, window:<lo>,<hi>.
+    #[arg(long, default_value_t = DensityCurve::Linear)]
+    slice_curve: DensityCurve,
+
+    /// Colormap of the mapped slice intensity used instead of the colors of the pixels.
+    /// Valid values are: gray, viridis, magma, turbo.
+    #[arg(long)]
+    colormap: Option<Colormap>,
+
+    /// Distance between the neighbouring cells imported from PNG slices along X, Y and Z axes,
+    /// e.g. `1,1,2.5` for slices thicker than their pixels. The longest side of the volume is 1.
+    #[arg(long, value_parser = parse_vec3, default_value = "1,1,1")]
+    spacing: Vec3,
+
+    /// Density of the cells inside of a voxelized mesh
+    #[arg(long, default_value_t = 50.0)]
+    mesh_density: f32,
+
+    /// Voxelize only a shell of the thickness in cells around the mesh surface instead of its interior
+    #[arg(long)]
+    shell: Option<f32>,
+
+    /// Make the density of a voxelized mesh proportional to the covered part of every cell
+    #[arg(long)]
+    coverage: bool,
+}
+
+/// Parses `N` comma separated values
+fn parse_components<T, const N: usize>(s: &str) -> Result<[T; N], String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let components = s.split(',')
+        .map(|x| x.trim().parse::<T>().map_err(|err| err.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    components.try_into().map_err(|_| format!("expected {N} comma separated components"))
+}
+
+fn parse_vec3(s: &str) -> Result<Vec3, String> {
+    parse_components::<f32, 3>(s).map(Vec3::from_array)
+}
+
+fn parse_size(s: &str) -> Result<[usize; 3], String> {
+    match s.parse::<usize>() {
+        Ok(size) => Ok([size; 3]),
+        Err(_) => parse_components(s),
+    }
+}
+
+
+
+#[derive(clap::Args, Debug)]
+struct ConvertArgs {
+    /// Model file, Plenoxels checkpoint (`.npz` archive or directory with `.npy` files),
+    /// NRRD density volume (`.nhdr` or `.nrrd`), `.ply` Gaussian splats, `.ply` or `.obj` mesh
+    /// or directory of PNG slices to convert
+    input: String,
+
+    /// Converted model file, `.nhdr` header of an exported channel, whose raw data
+    /// is written next to it, or `.nrrd` file of an exported channel with attached data
+    output: String,
+
+    /// Cell storage of the converted model. Valid values are: f32, f16, q8, sparse.
+    #[arg(long, short, default_value_t = Storage::Float16)]
+    storage: Storage,
+
+    /// Density threshold below which bricks of the sparse storage are dropped
+    #[arg(long, default_value_t = 0.0)]
+    threshold: f32,
+
+    /// Spherical harmonic degree of the converted model, from 0 to 3. Higher
+    /// degrees are truncated, missing ones are filled with zeros. Keeps the degree by default.
+    #[arg(long, short)]
+    degree: Option<u32>,
+
+    /// Rendering configuration to store the bounding box of an imported model into,
+    /// created if it does not exist. Exported channels are placed into its bounding box.
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Channel to export: density or a spherical harmonic coefficient such as `sh_r[0]`
+    #[arg(long, default_value_t = Channel::Density)]
+    channel: Channel,
+
+    /// Cells to keep as `X0,Y0,Z0,X1,Y1,Z1`, from the first corner inclusive to the second exclusive.
+    /// The bounding box shrinks accordingly.
+    #[arg(long, value_parser = parse_components::<usize, 6>)]
+    crop: Option<[usize; 6]>,
+
+    /// Rotate the model about the origin by Euler angles `X,Y,Z` in degrees, applied in this order,
+    /// after cropping. View-dependent color is rotated too and the bounding box grows to fit.
+    #[arg(long, value_parser = parse_vec3)]
+    rotate: Option<Vec3>,
+
+    /// Resample the model to `X,Y,Z` cells or `N` cells along every axis, after cropping
+    #[arg(long, value_parser = parse_size)]
+    resize: Option<[usize; 3]>,
+
+    /// Resampling filter. Valid values are: box, lanczos, trilinear, tricubic.
+    /// Box for downsampling and trilinear for upsampling by default.
+    #[arg(long)]
+    filter: Option<ResampleFilter>,
+
+    #[command(flatten)]
+    import: ImportArgs,
+}
+
+
+
+#[derive(clap::Args, Debug)]
+struct ValidateArgs {
+    /// Model file to validate
+    model: String,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Density above which a cell is considered occupied
+    #[arg(long, default_value_t = 0.0)]
+    threshold: f32,
+
+    #[command(flatten)]
+    import: ImportArgs,
+}
+
+
+
+#[derive(clap::Args, Debug)]
+struct MeshArgs {
+    /// Model file, Plenoxels checkpoint or NRRD density volume to extract the surface from
+    model: String,
+
+    /// Mesh file, binary `.ply` or `.obj`
+    output: String,
+
+    /// Density of the surface
+    #[arg(long, default_value_t = 1.0)]
+    iso: f32,
+
+    /// Color vertices by spherical harmonics evaluated in the `X,Y,Z` direction
+    /// instead of the degree 0 term only
+    #[arg(long, value_parser = parse_vec3)]
+    direction: Option<Vec3>,
+
+    /// Merge vertices within cubes of `N` cells to simplify the mesh
+    #[arg(long)]
+    decimate: Option<u32>,
+
+    /// Rendering configuration with the bounding box of the model
+    #[arg(long)]
+    config: Option<String>,
+
+    #[command(flatten)]
+    import: ImportArgs,
+}
+
+
+
+#[derive(clap::Args, Debug)]
+struct PointsArgs {
+    /// Model file, Plenoxels checkpoint or NRRD density volume to export
+    model: String,
+
+    /// Binary `.ply` point cloud
+    output: String,
+
+    /// Density above which a cell becomes a point
+    #[arg(long, default_value_t = 0.0)]
+    threshold: f32,
+
+    /// Also export all spherical harmonic coefficients of the cells
+    #[arg(long)]
+    sh: bool,
+
+    /// Randomly keep at most this many points
+    #[arg(long)]
+    count: Option<usize>,
+
+    /// Seed of the random subsampling
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Rendering configuration with the bounding box of the model
+    #[arg(long)]
+    config: Option<String>,
+
+    #[command(flatten)]
+    import: ImportArgs,
+}
+
+
+
+#[derive(clap::Args, Debug)]
+struct SplatsArgs {
+    /// Model file, Plenoxels checkpoint or NRRD density volume to export
+    model: String,
+
+    /// Binary `.ply` file with 3D Gaussian splats
+    output: String,
+
+    /// Density above which a cell contributes to a Gaussian splat
+    #[arg(long, default_value_t = 0.0)]
+    threshold: f32,
+
+    /// Number of cells along every axis of a cluster merged into a single Gaussian splat
+    #[arg(long, default_value_t = 1)]
+    cluster: usize,
+
+    /// Spherical harmonic degree of the splats, from 0 to 3. Higher degrees are truncated,
+    /// missing ones are filled with zeros. Keeps the degree of the model by default.
+    #[arg(long, short)]
+    degree: Option<u32>,
+
+    /// Rendering configuration with the bounding box of the model
+    #[arg(long)]
+    config: Option<String>,
+
+    #[command(flatten)]
+    import: ImportArgs,
+}
+
+
+
+#[derive(clap::Args, Debug)]
+struct GenerateArgs {
+    /// Kind of the field. Valid values are: cube, sphere, checkerboard, sh-spheres, slab, noise.
+    kind: ProceduralKind,
+
+    /// Generated model file
+    output: String,
+
+    /// Number of cells along every axis, e.g. `64` or `128,64,64`
+    #[arg(long, value_parser = parse_size, default_value = "64")]
+    size: [usize; 3],
+
+    /// Density of the filled space per unit of length
+    #[arg(long, default_value_t = 20.0)]
+    density: f32,
+
+    /// Color of the cube, the sphere and the slab
+    #[arg(long, value_parser = parse_vec3, default_value = "1,1,1")]
+    color: Vec3,
+
+    /// Standard deviation of the soft sphere or radius of the view-dependent spheres in box units,
+    /// 0.15 for the sphere and 0.1 for the view-dependent spheres by default
+    #[arg(long)]
+    radius: Option<f32>,
+
+    /// Blocks of the checkerboard, spheres or noise features along every axis
+    #[arg(long, default_value_t = 4)]
+    count: usize,
+
+    /// Thickness of the slab in box units
+    #[arg(long, default_value_t = 0.5)]
+    thickness: f32,
+
+    /// Seed of the noise
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Spherical harmonic degree of the model, the lowest one the field needs by default
+    #[arg(long)]
+    degree: Option<u32>,
+
+    /// Cell storage of the model. Valid values are: f32, f16, q8, sparse.
+    #[arg(long, short, default_value_t = Storage::Float32)]
+    storage: Storage,
+
+    /// Image of the closed form render of the field, whose camera and bounding box are
+    /// taken from the configuration. Not available for noise.
+    #[arg(long)]
+    expected: Option<String>,
+
+    /// Rendering configuration of the expected render, the default one if it is missing
+    #[arg(long, default_value_t = String::from("assets/render_configuration.toml"))]
+    config: String,
 }
 
 
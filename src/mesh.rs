@@ -0,0 +1,540 @@
+use crate::{
+    geometry::Aabb,
+    npy::Dtype,
+    ply::{Ply, PlyError},
+    spherical::{Cell, Grid, RadianceField},
+};
+use glam::*;
+use rayon::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufWriter, Write},
+    path::Path,
+};
+use thiserror::Error;
+
+
+
+#[derive(Debug, Error)]
+pub enum MeshError {
+    #[error("unsupported mesh format '{0}', valid extensions are: 'ply' and 'obj'")]
+    UnsupportedFormat(String),
+
+    #[error("malformed OBJ line {0}")]
+    BadObj(usize),
+
+    #[error("face refers to vertex {index} of {n_vertices}")]
+    BadIndex { index: i64, n_vertices: usize },
+
+    #[error(transparent)]
+    Ply(#[from] PlyError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+
+
+/// Source of the vertex colors of an extracted mesh
+#[derive(Clone, Debug, Default, PartialEq, Copy)]
+pub enum VertexColor {
+    /// Degree 0 spherical harmonic term only
+    #[default]
+    Dc,
+    /// Spherical harmonics evaluated in the direction
+    Direction(Vec3),
+}
+
+
+
+/// Edge of the marching cube given by its corner with the lower coordinate and its axis.
+/// Corners are numbered by their coordinates, X is the lowest bit and Z is the highest.
+type CubeEdge = (u8, u8);
+
+/// Triangles of the cube for every combination of the corners inside the surface
+type CaseTable = Vec<Vec<[CubeEdge; 3]>>;
+
+/// Whether the edges of the cube lie on the same face
+fn share_face((lhs_corner, lhs_axis): CubeEdge, (rhs_corner, rhs_axis): CubeEdge) -> bool {
+    (0..3)
+        .filter(|&axis| axis != lhs_axis && axis != rhs_axis)
+        .any(|axis| (lhs_corner ^ rhs_corner) >> axis & 1 == 0)
+}
+
+/// Builds the marching cubes table by tracing the surface over the faces of the cube.
+///
+/// Every face is crossed by segments from an edge where its counter-clockwise walk enters
+/// the surface to the next edge where it exits. On ambiguous faces this separates the inside
+/// corners, which only depends on the face itself, so the neighbouring cubes agree and the
+/// mesh has no holes. Segments chain into loops around the cube, which are fanned into triangles.
+fn case_table() -> CaseTable {
+    let offset = |corner: u8, axis: u8| corner | 1 << axis;
+
+    (0..=255u8)
+        .map(|case| {
+            let inside = |corner: u8| case >> corner & 1 != 0;
+            let mut next = HashMap::<CubeEdge, CubeEdge>::new();
+
+            for axis in 0..3 {
+                for side in 0..2 {
+                    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                    let base = side << axis;
+
+                    // counter-clockwise when looking along the outward normal from outside
+                    let mut corners = [base, offset(base, u), offset(offset(base, u), v), offset(base, v)];
+
+                    if side == 0 {
+                        corners.reverse();
+                    }
+
+                    let crossings = (0..4)
+                        .filter_map(|i| {
+                            let (from, to) = (corners[i], corners[(i + 1) % 4]);
+
+                            (inside(from) != inside(to))
+                                .then_some(((from.min(to), (from ^ to).trailing_zeros() as u8), inside(to)))
+                        })
+                        .collect::<Vec<_>>();
+
+                    for (i, &(edge, enters)) in crossings.iter().enumerate() {
+                        if enters {
+                            next.insert(edge, crossings[(i + 1) % crossings.len()].0);
+                        }
+                    }
+                }
+            }
+
+            let mut triangles = Vec::new();
+
+            while let Some(&start) = next.keys().next() {
+                let mut polygon = vec![start];
+                let mut edge = next.remove(&start).unwrap();
+
+                while edge != start {
+                    polygon.push(edge);
+                    edge = next.remove(&edge).unwrap();
+                }
+
+                // a diagonal lying on a face could also be made by the neighbouring cube,
+                // so the fan starts at a vertex that shares no face with non-adjacent ones
+                let n = polygon.len();
+                let apex = (0..n)
+                    .find(|&apex| (2..n - 1).all(|i| !share_face(polygon[apex], polygon[(apex + i) % n])))
+                    .unwrap_or(0);
+
+                for i in 1..n - 1 {
+                    triangles.push([polygon[apex], polygon[(apex + i) % n], polygon[(apex + i + 1) % n]]);
+                }
+            }
+
+            triangles
+        })
+        .collect()
+}
+
+
+
+/// Triangle mesh with colored vertices in world space.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    /// Colors in the [0, 1] range
+    pub colors: Vec<Vec3>,
+    /// Counter-clockwise when looking from outside of the surface
+    pub triangles: Vec<[u32; 3]>,
+}
+
+impl Mesh {
+    /// Extracts the surface where the density crosses the iso level with marching cubes
+    /// over the cell centers. Space outside of the grid has no density, so surfaces are closed.
+    pub fn marching_cubes<const W: usize>(
+        grid: &(impl Grid<W> + Sync), bounding_box: Aabb, iso_level: f32, color: VertexColor,
+    ) -> Self {
+        let size = grid.size();
+        let cell_size = (bounding_box.hi - bounding_box.lo) / RadianceField::size_vec(size);
+        let table = case_table();
+
+        // samples are padded with an empty layer on every side
+        let padded = size.map(|len| len + 2);
+        let [px, py, _] = padded;
+        let flat = |[x, y, z]: [usize; 3]| x + px * (y + py * z);
+
+        let sample = |[x, y, z]: [usize; 3]| -> Option<Cell<W>> {
+            grid.cell([x.checked_sub(1)?, y.checked_sub(1)?, z.checked_sub(1)?])
+        };
+        let density = |index| sample(index).map_or(0.0, |cell| cell.density);
+
+        let corner_index = |[x, y, z]: [usize; 3], corner: u8| [
+            x + (corner & 1) as usize,
+            y + (corner >> 1 & 1) as usize,
+            z + (corner >> 2 & 1) as usize,
+        ];
+
+        let n_cubes = RadianceField::volume(size.map(|len| len + 1));
+        let [cx, cy, _] = size.map(|len| len + 1);
+
+        // vertices are named by the padded sample at the lower end of their edge and the edge axis
+        let triangles = (0..n_cubes)
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let cube = [i % cx, i / cx % cy, i / (cx * cy)];
+
+                let case = (0..8).fold(0, |case, corner| {
+                    let inside = density(corner_index(cube, corner)) > iso_level;
+                    case | (inside as usize) << corner
+                });
+
+                table[case].iter()
+                    .map(|triangle| triangle.map(|(corner, axis)| {
+                        (flat(corner_index(cube, corner)), axis)
+                    }))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut vertices = triangles.par_iter().flatten().copied().collect::<Vec<_>>();
+        vertices.par_sort_unstable();
+        vertices.dedup();
+
+        let (positions, colors) = vertices.par_iter()
+            .map(|&(index, axis)| {
+                let lo = [index % px, index / px % py, index / (px * py)];
+                let mut hi = lo;
+                hi[axis as usize] += 1;
+
+                let (lo_density, hi_density) = (density(lo), density(hi));
+                let t = ((iso_level - lo_density) / (hi_density - lo_density)).clamp(0.0, 1.0);
+
+                let index = Vec3::from_array(lo.map(|i| i as f32)) + t * Vec3::AXES[axis as usize];
+                let position = bounding_box.lo + cell_size * (index - 0.5);
+
+                // empty cells outside of the surface usually have no meaningful color
+                let inside = if lo_density > iso_level { lo } else { hi };
+                let cell = sample(inside).expect("padding is never inside of the surface");
+
+                let color = match color {
+                    VertexColor::Dc => cell.diffuse(),
+                    VertexColor::Direction(direction) => cell.eval(direction.normalize()).color,
+                };
+
+                (position, color.clamp(Vec3::ZERO, Vec3::ONE))
+            })
+            .unzip();
+
+        let triangles = triangles.par_iter()
+            .map(|triangle| triangle.map(|vertex| vertices.binary_search(&vertex).unwrap() as u32))
+            .collect();
+
+        Self { positions, colors, triangles }
+    }
+
+    /// Reads a mesh from the vertices and faces of a PLY file. Polygons are split into triangles,
+    /// vertices without `red`, `green` and `blue` properties get the default color.
+    pub fn from_ply(ply: &Ply, default_color: Vec3) -> Result<Self, MeshError> {
+        let vertex = ply.element("vertex")?;
+        let face = ply.element("face")?;
+
+        let (x, y, z) = (vertex.scalar("x")?, vertex.scalar("y")?, vertex.scalar("z")?);
+        let positions = (0..vertex.count).map(|i| vec3(x[i], y[i], z[i])).collect::<Vec<_>>();
+
+        let colors = match ["red", "green", "blue"].map(|name| vertex.scalar(name)) {
+            [Ok(r), Ok(g), Ok(b)] => {
+                // integer colors use their full range
+                let scale = match vertex.properties.iter().find(|property| property.name == "red") {
+                    Some(property) if property.dtype == Dtype::U8 => 1.0 / u8::MAX as f32,
+                    Some(property) if property.dtype == Dtype::U16 => 1.0 / u16::MAX as f32,
+                    _ => 1.0,
+                };
+
+                (0..vertex.count).map(|i| scale * vec3(r[i], g[i], b[i])).collect()
+            },
+            _ => vec![default_color; vertex.count],
+        };
+
+        let polygons = face.list("vertex_indices").or_else(|_| face.list("vertex_index"))?;
+
+        let triangles = polygons.iter()
+            .map(|polygon| Self::triangulate(polygon, positions.len()))
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+
+        Ok(Self { positions, colors, triangles })
+    }
+
+    /// Reads a mesh from `v` and `f` lines of an OBJ file. Vertex colors may follow
+    /// the positions, vertices without them get the default color.
+    pub fn read_obj(reader: impl BufRead, default_color: Vec3) -> Result<Self, MeshError> {
+        let mut mesh = Self::default();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let bad_line = || MeshError::BadObj(i + 1);
+
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("v") => {
+                    let values = words
+                        .map(|word| word.parse::<f32>().map_err(|_| bad_line()))
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    let (position, color) = match *values.as_slice() {
+                        [x, y, z] | [x, y, z, _] => (vec3(x, y, z), default_color),
+                        [x, y, z, r, g, b] | [x, y, z, r, g, b, _] => (vec3(x, y, z), vec3(r, g, b)),
+                        _ => return Err(bad_line()),
+                    };
+
+                    mesh.positions.push(position);
+                    mesh.colors.push(color);
+                },
+                Some("f") => {
+                    let n_vertices = mesh.positions.len() as i64;
+
+                    // `index/texture/normal` triplets, negative indices count from the last vertex
+                    let polygon = words
+                        .map(|word| match word.split('/').next().unwrap_or_default().parse::<i64>() {
+                            Ok(index) if index < 0 => Ok(n_vertices + index),
+                            Ok(index) => Ok(index - 1),
+                            Err(_) => Err(bad_line()),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    mesh.triangles.extend(Self::triangulate(&polygon, mesh.positions.len())?);
+                },
+                _ => continue,
+            }
+        }
+
+        Ok(mesh)
+    }
+
+    /// Reads a PLY or OBJ mesh depending on the extension of the path
+    pub fn read(path: impl AsRef<Path>, default_color: Vec3) -> Result<Self, MeshError> {
+        let path = path.as_ref();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+
+        match extension.as_ref() {
+            "ply" => Self::from_ply(&Ply::read(reader)?, default_color),
+            "obj" => Self::read_obj(reader, default_color),
+            _ => Err(MeshError::UnsupportedFormat(extension.into_owned())),
+        }
+    }
+
+    /// Splits the polygon into a fan of triangles
+    fn triangulate(polygon: &[i64], n_vertices: usize) -> Result<Vec<[u32; 3]>, MeshError> {
+        let indices = polygon.iter()
+            .map(|&index| match usize::try_from(index) {
+                Ok(vertex) if vertex < n_vertices => Ok(vertex as u32),
+                _ => Err(MeshError::BadIndex { index, n_vertices }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((2..indices.len()).map(|i| [indices[0], indices[i - 1], indices[i]]).collect())
+    }
+
+    /// Box containing all of the vertices
+    pub fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(self.positions.iter().copied())
+    }
+
+    /// Simplifies the mesh by merging all vertices within every cube of the size into one
+    pub fn decimated(&self, cluster_size: Vec3) -> Self {
+        let lo = self.positions.iter().copied().fold(Vec3::INFINITY, Vec3::min);
+
+        let mut clusters = HashMap::<IVec3, u32>::new();
+        let mut sums = Vec::<(Vec3, Vec3, f32)>::new();
+
+        let remap = self.positions.iter().zip(&self.colors)
+            .map(|(&position, &color)| {
+                let key = ((position - lo) / cluster_size).floor().as_ivec3();
+
+                let index = *clusters.entry(key).or_insert_with(|| {
+                    sums.push((Vec3::ZERO, Vec3::ZERO, 0.0));
+                    sums.len() as u32 - 1
+                });
+
+                let (position_sum, color_sum, count) = &mut sums[index as usize];
+                *position_sum += position;
+                *color_sum += color;
+                *count += 1.0;
+
+                index
+            })
+            .collect::<Vec<_>>();
+
+        let mut unique = HashSet::new();
+
+        let triangles = self.triangles.iter()
+            .map(|triangle| triangle.map(|vertex| remap[vertex as usize]))
+            .filter(|&[a, b, c]| a != b && b != c && c != a)
+            .filter(|triangle| {
+                let mut key = *triangle;
+                key.sort_unstable();
+                unique.insert(key)
+            })
+            .collect();
+
+        Self {
+            positions: sums.iter().map(|&(position, _, count)| position / count).collect(),
+            colors: sums.iter().map(|&(_, color, count)| color / count).collect(),
+            triangles,
+        }
+    }
+
+    /// Writes the mesh as binary little-endian PLY
+    pub fn write_ply(&self, writer: impl Write) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+
+        write!(
+            writer,
+            "ply\n\
+             format binary_little_endian 1.0\n\
+             element vertex {}\n\
+             property float x\n\
+             property float y\n\
+             property float z\n\
+             property uchar red\n\
+             property uchar green\n\
+             property uchar blue\n\
+             element face {}\n\
+             property list uchar int vertex_indices\n\
+             end_header\n",
+            self.positions.len(), self.triangles.len(),
+        )?;
+
+        for (position, color) in self.positions.iter().zip(&self.colors) {
+            writer.write_all(bytemuck::bytes_of(position))?;
+            writer.write_all(&to_rgb8(*color))?;
+        }
+
+        for triangle in &self.triangles {
+            writer.write_all(&[3])?;
+            writer.write_all(bytemuck::cast_slice(&triangle.map(|index| index as i32)))?;
+        }
+
+        writer.flush()
+    }
+
+    /// Writes the mesh as OBJ with vertex colors following positions
+    pub fn write_obj(&self, writer: impl Write) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+
+        for (position, color) in self.positions.iter().zip(&self.colors) {
+            writeln!(
+                writer, "v {} {} {} {} {} {}",
+                position.x, position.y, position.z, color.x, color.y, color.z,
+            )?;
+        }
+
+        for [a, b, c] in &self.triangles {
+            writeln!(writer, "f {} {} {}", a + 1, b + 1, c + 1)?;
+        }
+
+        writer.flush()
+    }
+
+    /// Writes the mesh in the format given by the extension of the path
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), MeshError> {
+        let path = path.as_ref();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+
+        match extension.as_ref() {
+            "ply" => self.write_ply(std::fs::File::create(path)?)?,
+            "obj" => self.write_obj(std::fs::File::create(path)?)?,
+            _ => return Err(MeshError::UnsupportedFormat(extension.into_owned())),
+        }
+
+        Ok(())
+    }
+}
+
+/// Color in the [0, 1] range as 8-bit RGB
+pub fn to_rgb8(color: Vec3) -> [u8; 3] {
+    (color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).round().to_array().map(|x| x as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procedural::Procedural;
+    use bytemuck::Zeroable;
+
+    fn unit_box() -> Aabb {
+        Aabb::new(Vec3::ZERO, Vec3::ONE)
+    }
+
+    /// Field of a single cell of the density
+    fn single_cell(density: f32) -> RadianceField<1> {
+        RadianceField { size: [1; 3], cells: vec![Cell { density, ..Cell::zeroed() }] }
+    }
+
+    /// Checks that every directed edge of the triangles is met once and in reverse once
+    fn assert_watertight(mesh: &Mesh) {
+        let mut edges = HashMap::<(u32, u32), usize>::new();
+
+        for &[a, b, c] in &mesh.triangles {
+            for edge in [(a, b), (b, c), (c, a)] {
+                *edges.entry(edge).or_default() += 1;
+            }
+        }
+
+        for (&(from, to), &count) in &edges {
+            assert_eq!(count, 1, "edge {from} -> {to} is shared by {count} triangles");
+            assert_eq!(edges.get(&(to, from)), Some(&1), "edge {from} -> {to} has no reverse");
+        }
+    }
+
+    #[test]
+    fn single_cell_is_closed_and_faces_outward() {
+        let mesh = Mesh::marching_cubes(&single_cell(1.0), unit_box(), 0.5, VertexColor::Dc);
+
+        assert!(!mesh.triangles.is_empty());
+        assert_watertight(&mesh);
+
+        for &triangle in &mesh.triangles {
+            let [a, b, c] = triangle.map(|vertex| mesh.positions[vertex as usize]);
+            let normal = (b - a).cross(c - a);
+
+            let outward = (a + b + c) / 3.0 - Vec3::splat(0.5);
+
+            assert!(normal.dot(outward) > 0.0, "triangle {triangle:?} faces inward");
+        }
+    }
+
+    #[test]
+    fn noise_surfaces_are_watertight() {
+        // noise visits most of the cases including the ambiguous ones
+        for seed in 0..4 {
+            let field = Procedural::Noise { density: 20.0, frequency: 4, seed }.generate([16; 3]);
+            let mesh = Mesh::marching_cubes(&field, unit_box(), 10.0, VertexColor::Dc);
+
+            assert!(!mesh.triangles.is_empty());
+            assert_watertight(&mesh);
+        }
+    }
+
+    #[test]
+    fn ply_and_obj_round_trip() {
+        let field = Procedural::SoftSphere { density: 20.0, sigma: 0.2, color: vec3(1.0, 0.5, 0.25) }
+            .generate([8; 3]);
+        let mesh = Mesh::marching_cubes(&field, unit_box(), 5.0, VertexColor::Dc);
+
+        let mut ply = Vec::new();
+        mesh.write_ply(&mut ply).unwrap();
+        let from_ply = Mesh::from_ply(&Ply::read(ply.as_slice()).unwrap(), Vec3::ONE).unwrap();
+
+        let mut obj = Vec::new();
+        mesh.write_obj(&mut obj).unwrap();
+        let from_obj = Mesh::read_obj(obj.as_slice(), Vec3::ONE).unwrap();
+
+        for read in [&from_ply, &from_obj] {
+            assert_eq!(read.positions, mesh.positions);
+            assert_eq!(read.triangles, mesh.triangles);
+
+            for (&read, &color) in read.colors.iter().zip(&mesh.colors) {
+                assert!(read.abs_diff_eq(color, 1.0 / 255.0), "color {read} instead of {color}");
+            }
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{
+    render_gpu::ModelTextureFormat,
+    spherical::{
+        Cell, Grid, RadianceField, ResizedGrid, MAX_SPHERICAL_HARMONIC_DEGREE,
+        SPHERICAL_HARMONIC_WIDTH,
+    },
+    storage::{HalfField, QuantizedField},
+    sparse::{SparseField, BRICK_VOLUME, EMPTY_BRICK},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+
+
+/// First bytes of every model file written by this program. Files without
+/// it are treated as a bare bincode-serialized cubic [`LegacyField`].
+pub const MAGIC: [u8; 4] = *b"RFLD";
+pub const FORMAT_VERSION: u32 = 1;
+
+/// First line of a Git LFS pointer file, which is stored instead of a model that was not fetched
+pub const LFS_POINTER_PREFIX: &[u8] = b"version https://git-lfs.github.com/spec/v1";
+
+
+
+/// Cubic radiance field as it was stored before non-cubic grids were supported.
+#[derive(Clone, Default, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct LegacyField {
+    pub size: usize,
+    pub cells: Vec<Cell>,
+}
+
+impl From<LegacyField> for RadianceField {
+    fn from(value: LegacyField) -> Self {
+        Self { size: [value.size; 3], cells: value.cells }
+    }
+}
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Copy, Eq, PartialOrd, Ord, Hash)]
+#[derive(Serialize, Deserialize)]
+pub enum Storage {
+    #[default]
+    #[serde(rename = "f32")]
+    Float32,
+    #[serde(rename = "f16")]
+    Float16,
+    #[serde(rename = "q8")]
+    Quantized8,
+    #[serde(rename = "sparse")]
+    Sparse,
+}
+
+impl std::str::FromStr for Storage {
+    type Err = StorageParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "f32" => Self::Float32,
+            "f16" => Self::Float16,
+            "q8" => Self::Quantized8,
+            "sparse" => Self::Sparse,
+            _ => return Err(StorageParseError(s.to_owned())),
+        })
+    }
+}
+
+impl std::fmt::Display for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Float32 => "f32",
+            Self::Float16 => "f16",
+            Self::Quantized8 => "q8",
+            Self::Sparse => "sparse",
+        })
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid storage '{0}', valid values are: 'f32', 'f16', 'q8' and 'sparse'")]
+pub struct StorageParseError(pub String);
+
+
+
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct ModelHeader {
+    pub magic: [u8; 4],
+    pub version: u32,
+    pub storage: Storage,
+    pub sh_degree: u32,
+}
+
+
+
+#[derive(Debug, Error)]
+pub enum ModelError {
+    #[error("unsupported model format version {0}, expected {FORMAT_VERSION}")]
+    UnsupportedVersion(u32),
+
+    #[error("unsupported spherical harmonic degree {0}, \
+             expected at most {MAX_SPHERICAL_HARMONIC_DEGREE}")]
+    UnsupportedDegree(u32),
+
+    #[error("the file is a Git LFS pointer to a {size} byte object, fetch it with `git lfs pull`")]
+    LfsPointer { size: u64 },
+
+    #[error("unknown model format, the file is neither a model nor a legacy field: {0}")]
+    UnknownFormat(bincode::Error),
+
+    #[error("the model has {actual} cells, expected {expected} for its size {size:?}")]
+    CellCount { size: [usize; 3], expected: usize, actual: usize },
+
+    #[error("the sparse model has a malformed brick index")]
+    BrickIndex,
+
+    #[error("failed to (de)serialize the model: {0}")]
+    Bincode(#[from] bincode::Error),
+}
+
+
+
+/// Radiance field in any of the supported storages.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Model<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
+    Float32(RadianceField<W>),
+    Float16(HalfField<W>),
+    Quantized8(Box<QuantizedField<W>>),
+    Sparse(SparseField<W>),
+}
+
+impl<const W: usize> Model<W> {
+    pub const fn storage(&self) -> Storage {
+        match self {
+            Self::Float32(_) => Storage::Float32,
+            Self::Float16(_) => Storage::Float16,
+            Self::Quantized8(_) => Storage::Quantized8,
+            Self::Sparse(_) => Storage::Sparse,
+        }
+    }
+
+    /// Texture format to upload the model to the GPU with, so that no precision is lost
+    pub const fn texture_format(&self) -> ModelTextureFormat {
+        match self {
+            Self::Float32(_) | Self::Sparse(_) => ModelTextureFormat::Rgba32Float,
+            Self::Float16(_) | Self::Quantized8(_) => ModelTextureFormat::Rgba16Float,
+        }
+    }
+
+    fn read_body(storage: Storage, reader: &[u8]) -> Result<Self, ModelError> {
+        let model = match storage {
+            Storage::Float32 => Self::Float32(bincode::deserialize_from(reader)?),
+            Storage::Float16 => Self::Float16(bincode::deserialize_from(reader)?),
+            Storage::Quantized8 => Self::Quantized8(Box::new(bincode::deserialize_from(reader)?)),
+            Storage::Sparse => Self::Sparse(bincode::deserialize_from(reader)?),
+        };
+
+        model.check()?;
+
+        Ok(model)
+    }
+
+    /// Checks that the number of stored cells matches the size of the model
+    pub fn check(&self) -> Result<(), ModelError> {
+        let size = self.size();
+
+        let check_count = |expected: usize, actual: usize| match expected == actual {
+            true => Ok(()),
+            false => Err(ModelError::CellCount { size, expected, actual }),
+        };
+
+        match self {
+            Self::Float32(field) => check_count(RadianceField::volume(size), field.cells.len()),
+            Self::Float16(field) => check_count(RadianceField::volume(size), field.cells.len()),
+            Self::Quantized8(field) => check_count(RadianceField::volume(size), field.cells.len()),
+            Self::Sparse(field) => {
+                if field.brick_index.len() != RadianceField::volume(field.n_bricks())
+                    || field.bricks.len() % BRICK_VOLUME != 0
+                {
+                    return Err(ModelError::BrickIndex);
+                }
+
+                let n_occupied = field.n_occupied_bricks() as u32;
+
+                match field.brick_index.iter().all(|&brick| brick == EMPTY_BRICK || brick < n_occupied) {
+                    true => Ok(()),
+                    false => Err(ModelError::BrickIndex),
+                }
+            },
+        }
+    }
+
+    fn write_body(&self, bytes: &mut Vec<u8>) -> Result<(), ModelError> {
+        match self {
+            Self::Float32(field) => bincode::serialize_into(bytes, field)?,
+            Self::Float16(field) => bincode::serialize_into(bytes, field)?,
+            Self::Quantized8(field) => bincode::serialize_into(bytes, field)?,
+            Self::Sparse(field) => bincode::serialize_into(bytes, field)?,
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the model into full precision
+    pub fn to_field(&self) -> RadianceField<W> {
+        match self {
+            Self::Float32(field) => field.clone(),
+            Self::Float16(field) => field.to_field(),
+            Self::Quantized8(field) => field.to_field(),
+            Self::Sparse(field) => field.to_field(),
+        }
+    }
+
+    /// Encodes the grid into the given storage. Sparse storage drops
+    /// bricks with no cell denser than `density_threshold`.
+    pub fn encode(grid: &(impl Grid<W> + Sync), storage: Storage, density_threshold: f32) -> Self {
+        match storage {
+            Storage::Float32 => Self::Float32(RadianceField::from_grid(grid)),
+            Storage::Float16 => Self::Float16(HalfField::from_grid(grid)),
+            Storage::Quantized8 => Self::Quantized8(Box::new(QuantizedField::from_grid(grid))),
+            Storage::Sparse => Self::Sparse(SparseField::from_grid(grid, density_threshold)),
+        }
+    }
+}
+
+impl<const W: usize> Grid<W> for Model<W> {
+    fn size(&self) -> [usize; 3] {
+        match self {
+            Self::Float32(field) => field.size(),
+            Self::Float16(field) => field.size,
+            Self::Quantized8(field) => field.size,
+            Self::Sparse(field) => field.size,
+        }
+    }
+
+    fn cell(&self, index: [usize; 3]) -> Option<Cell<W>> {
+        match self {
+            Self::Float32(field) => field.cell(index),
+            Self::Float16(field) => field.cell(index),
+            Self::Quantized8(field) => field.cell(index),
+            Self::Sparse(field) => field.cell(index),
+        }
+    }
+}
+
+
+
+/// [`Model`] with the spherical harmonic degree known only at runtime.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnyModel {
+    Degree0(Model<1>),
+    Degree1(Model<4>),
+    Degree2(Model<9>),
+    Degree3(Model<16>),
+}
+
+/// Evaluates the expression with `$model` bound to the [`Model`] of any degree.
+macro_rules! with_model {
+    ($any_model:expr, |$model:ident| $body:expr) => {
+        match $any_model {
+            $crate::model::AnyModel::Degree0($model) => $body,
+            $crate::model::AnyModel::Degree1($model) => $body,
+            $crate::model::AnyModel::Degree2($model) => $body,
+            $crate::model::AnyModel::Degree3($model) => $body,
+        }
+    };
+}
+
+pub(crate) use with_model;
+
+/// Evaluates the expression with `$grid` bound to the concrete storage of the model of any degree,
+/// so that hot loops over the cells don't dispatch on the storage of every cell.
+macro_rules! with_grid {
+    ($any_model:expr, |$grid:ident| $body:expr) => {
+        $crate::model::with_model!($any_model, |model| match model {
+            $crate::model::Model::Float32($grid) => $body,
+            $crate::model::Model::Float16($grid) => $body,
+            $crate::model::Model::Quantized8($grid) => {
+                let $grid = &**$grid;
+                $body
+            },
+            $crate::model::Model::Sparse($grid) => $body,
+        })
+    };
+}
+
+pub(crate) use with_grid;
+
+impl AnyModel {
+    pub fn sh_degree(&self) -> u32 {
+        match self {
+            Self::Degree0(_) => 0,
+            Self::Degree1(_) => 1,
+            Self::Degree2(_) => 2,
+            Self::Degree3(_) => 3,
+        }
+    }
+
+    pub fn storage(&self) -> Storage {
+        with_model!(self, |model| model.storage())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ModelError> {
+        if bytes.starts_with(LFS_POINTER_PREFIX) {
+            let size = String::from_utf8_lossy(bytes).lines()
+                .find_map(|line| line.strip_prefix("size "))
+                .and_then(|size| size.trim().parse().ok())
+                .unwrap_or_default();
+
+            return Err(ModelError::LfsPointer { size });
+        }
+
+        if !bytes.starts_with(&MAGIC) {
+            let legacy: LegacyField = bincode::deserialize(bytes).map_err(ModelError::UnknownFormat)?;
+            let model = Model::Float32(legacy.into());
+            model.check()?;
+
+            return Ok(Self::Degree2(model));
+        }
+
+        let mut reader = bytes;
+        let header: ModelHeader = bincode::deserialize_from(&mut reader)?;
+
+        if header.version != FORMAT_VERSION {
+            return Err(ModelError::UnsupportedVersion(header.version));
+        }
+
+        Ok(match header.sh_degree {
+            0 => Self::Degree0(Model::read_body(header.storage, reader)?),
+            1 => Self::Degree1(Model::read_body(header.storage, reader)?),
+            2 => Self::Degree2(Model::read_body(header.storage, reader)?),
+            3 => Self::Degree3(Model::read_body(header.storage, reader)?),
+            degree => return Err(ModelError::UnsupportedDegree(degree)),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ModelError> {
+        let header = ModelHeader {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            storage: self.storage(),
+            sh_degree: self.sh_degree(),
+        };
+
+        let mut bytes = bincode::serialize(&header)?;
+
+        with_model!(self, |model| model.write_body(&mut bytes))?;
+
+        Ok(bytes)
+    }
+
+    /// Converts the grid to the spherical harmonic degree and encodes it into the storage
+    pub fn encode<const W: usize>(
+        grid: &(impl Grid<W> + Sync), sh_degree: u32, storage: Storage, density_threshold: f32,
+    ) -> Result<Self, ModelError> {
+        let grid = ResizedGrid(grid);
+
+        Ok(match sh_degree {
+            0 => Self::Degree0(Model::encode(&grid, storage, density_threshold)),
+            1 => Self::Degree1(Model::encode(&grid, storage, density_threshold)),
+            2 => Self::Degree2(Model::encode(&grid, storage, density_threshold)),
+            3 => Self::Degree3(Model::encode(&grid, storage, density_threshold)),
+            degree => return Err(ModelError::UnsupportedDegree(degree)),
+        })
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procedural::Procedural;
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn bare_field_is_read_as_legacy_cubic_field() {
+        let cells = Procedural::Noise { density: 10.0, frequency: 2, seed: 5 }.generate([3; 3]).cells;
+        let legacy = LegacyField { size: 3, cells: cells.iter().map(Cell::with_width).collect() };
+
+        let model = AnyModel::from_bytes(&bincode::serialize(&legacy).unwrap()).unwrap();
+
+        let AnyModel::Degree2(Model::Float32(field)) = model else {
+            panic!("legacy field is read as {:?} {:?}", model.sh_degree(), model.storage());
+        };
+
+        assert_eq!(field, RadianceField::from(legacy));
+        assert_eq!(field.size, [3; 3]);
+
+        let truncated = LegacyField { size: 3, cells: vec![Cell::zeroed(); 26] };
+
+        assert!(matches!(
+            AnyModel::from_bytes(&bincode::serialize(&truncated).unwrap()),
+            Err(ModelError::CellCount { expected: 27, actual: 26, .. }),
+        ));
+    }
+
+    #[test]
+    fn non_cubic_models_round_trip_in_every_storage() {
+        let field = Procedural::Noise { density: 10.0, frequency: 2, seed: 5 }.generate([5, 3, 11]);
+
+        for storage in [Storage::Float32, Storage::Float16, Storage::Quantized8, Storage::Sparse] {
+            let model = AnyModel::encode(&field, 1, storage, 0.0).unwrap();
+            let bytes = model.to_bytes().unwrap();
+
+            assert!(bytes.starts_with(&MAGIC));
+            assert_eq!(AnyModel::from_bytes(&bytes).unwrap(), model, "{storage} model");
+        }
+    }
+
+    #[test]
+    fn degree_is_stored_in_the_header() {
+        let field = Procedural::ShSpheres { density: 10.0, count: 2, radius: 0.2 }.generate([4; 3]);
+
+        let model = AnyModel::encode(&field, 0, Storage::Float32, 0.0).unwrap();
+        let read = AnyModel::from_bytes(&model.to_bytes().unwrap()).unwrap();
+
+        let AnyModel::Degree0(Model::Float32(read)) = read else {
+            panic!("degree 0 model is read as degree {}", read.sh_degree());
+        };
+
+        // higher degrees are truncated
+        for (read, cell) in read.cells.iter().zip(&field.cells) {
+            assert_eq!(*read, cell.with_width::<1>());
+        }
+
+        let header = |version: u32, sh_degree: u32| {
+            bincode::serialize(&ModelHeader { magic: MAGIC, version, storage: Storage::Float32, sh_degree }).unwrap()
+        };
+
+        assert!(matches!(AnyModel::from_bytes(&header(FORMAT_VERSION, 4)), Err(ModelError::UnsupportedDegree(4))));
+        assert!(matches!(AnyModel::from_bytes(&header(FORMAT_VERSION + 1, 2)), Err(ModelError::UnsupportedVersion(_))));
+    }
+}
@@ -0,0 +1,391 @@
+use half::f16;
+use std::{collections::HashMap, io::{Read, Seek}, path::Path};
+use thiserror::Error;
+
+
+
+/// First bytes of every `.npy` file
+pub const NPY_MAGIC: [u8; 6] = *b"\x93NUMPY";
+
+
+
+/// Element type of a NumPy array, byte order is kept separately in [`NpyArray::big_endian`].
+#[derive(Clone, Debug, PartialEq, Copy, Eq, Hash)]
+pub enum Dtype {
+    Bool,
+    I8, I16, I32, I64,
+    U8, U16, U32, U64,
+    F16, F32, F64,
+}
+
+impl Dtype {
+    pub const fn size(self) -> usize {
+        match self {
+            Self::Bool | Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 | Self::F16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::I64 | Self::U64 | Self::F64 => 8,
+        }
+    }
+
+    /// Parses NumPy type description such as `<f4`, returns the type and whether it is big endian
+    pub fn parse(descr: &str) -> Result<(Self, bool), NpyError> {
+        let unsupported = || NpyError::UnsupportedDtype(descr.to_owned());
+
+        let (order, kind) = descr.split_at_checked(1).ok_or_else(unsupported)?;
+
+        let big_endian = match order {
+            ">" => true,
+            "<" | "|" | "=" => false,
+            _ => return Err(unsupported()),
+        };
+
+        let dtype = match kind {
+            "b1" => Self::Bool,
+            "i1" => Self::I8,
+            "i2" => Self::I16,
+            "i4" => Self::I32,
+            "i8" => Self::I64,
+            "u1" => Self::U8,
+            "u2" => Self::U16,
+            "u4" => Self::U32,
+            "u8" => Self::U64,
+            "f2" => Self::F16,
+            "f4" => Self::F32,
+            "f8" => Self::F64,
+            _ => return Err(unsupported()),
+        };
+
+        Ok((dtype, big_endian))
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+pub enum NpyError {
+    #[error("not a NumPy array file")]
+    BadMagic,
+
+    #[error("malformed NumPy array header: {0}")]
+    BadHeader(String),
+
+    #[error("unsupported NumPy data type '{0}'")]
+    UnsupportedDtype(String),
+
+    #[error("Fortran ordered arrays are not supported")]
+    FortranOrder,
+
+    #[error("array '{0}' is missing")]
+    MissingArray(String),
+
+    #[error("array data is {actual} bytes long, expected {expected}")]
+    Truncated { expected: usize, actual: usize },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+}
+
+
+
+/// C-ordered NumPy array as stored in a `.npy` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyArray {
+    pub dtype: Dtype,
+    pub big_endian: bool,
+    pub shape: Vec<usize>,
+    /// Raw elements in the file byte order
+    pub data: Vec<u8>,
+}
+
+impl NpyArray {
+    /// Number of elements
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Self, NpyError> {
+        let mut preamble = [0; 8];
+        reader.read_exact(&mut preamble)?;
+
+        if preamble[..6] != NPY_MAGIC {
+            return Err(NpyError::BadMagic);
+        }
+
+        // version 1.0 has a 2-byte header length, later versions have a 4-byte one
+        let header_len = match preamble[6] {
+            1 => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_le_bytes(len) as usize
+            },
+            2 | 3 => {
+                let mut len = [0; 4];
+                reader.read_exact(&mut len)?;
+                u32::from_le_bytes(len) as usize
+            },
+            version => return Err(NpyError::BadHeader(format!("unknown version {version}"))),
+        };
+
+        let mut header = vec![0; header_len];
+        reader.read_exact(&mut header)?;
+
+        let header = String::from_utf8_lossy(&header);
+        let (dtype, big_endian, shape) = Self::parse_header(&header)?;
+
+        let expected = shape.iter().product::<usize>() * dtype.size();
+        let mut data = Vec::with_capacity(expected);
+        reader.take(expected as u64).read_to_end(&mut data)?;
+
+        if data.len() != expected {
+            return Err(NpyError::Truncated { expected, actual: data.len() });
+        }
+
+        Ok(Self { dtype, big_endian, shape, data })
+    }
+
+    /// Parses the header dictionary, e.g. `{'descr': '<f4', 'fortran_order': False, 'shape': (3, 4), }`
+    fn parse_header(header: &str) -> Result<(Dtype, bool, Vec<usize>), NpyError> {
+        let bad_header = || NpyError::BadHeader(header.trim().to_owned());
+
+        let value_of = |key: &str| {
+            let start = header.find(&format!("'{key}'"))? + key.len() + 2;
+            let rest = header[start..].trim_start().strip_prefix(':')?.trim_start();
+            Some(rest)
+        };
+
+        let descr = value_of("descr")
+            .and_then(|rest| rest.strip_prefix('\''))
+            .and_then(|rest| rest.split('\'').next())
+            .ok_or_else(bad_header)?;
+
+        let fortran_order = value_of("fortran_order").ok_or_else(bad_header)?;
+
+        if fortran_order.starts_with("True") {
+            return Err(NpyError::FortranOrder);
+        }
+
+        let shape = value_of("shape")
+            .and_then(|rest| rest.strip_prefix('('))
+            .and_then(|rest| rest.split(')').next())
+            .ok_or_else(bad_header)?
+            .split(',')
+            .map(str::trim)
+            .filter(|dim| !dim.is_empty())
+            .map(|dim| dim.trim_end_matches('L').parse().map_err(|_| bad_header()))
+            .collect::<Result<_, _>>()?;
+
+        let (dtype, big_endian) = Dtype::parse(descr)?;
+
+        Ok((dtype, big_endian, shape))
+    }
+
+    fn elements<const N: usize>(&self) -> impl Iterator<Item = [u8; N]> + '_ {
+        self.data.chunks_exact(N).map(|bytes| {
+            let mut bytes: [u8; N] = bytes.try_into().unwrap();
+
+            if self.big_endian {
+                bytes.reverse();
+            }
+
+            bytes
+        })
+    }
+
+    /// Converts all elements to `f64`, the conversion is exact for every supported type but 64-bit integers
+    pub fn to_f64(&self) -> Vec<f64> {
+        match self.dtype {
+            Dtype::Bool | Dtype::U8 => self.data.iter().map(|&x| x as f64).collect(),
+            Dtype::I8 => self.data.iter().map(|&x| x as i8 as f64).collect(),
+            Dtype::I16 => self.elements().map(|x| i16::from_le_bytes(x) as f64).collect(),
+            Dtype::U16 => self.elements().map(|x| u16::from_le_bytes(x) as f64).collect(),
+            Dtype::I32 => self.elements().map(|x| i32::from_le_bytes(x) as f64).collect(),
+            Dtype::U32 => self.elements().map(|x| u32::from_le_bytes(x) as f64).collect(),
+            Dtype::I64 => self.elements().map(|x| i64::from_le_bytes(x) as f64).collect(),
+            Dtype::U64 => self.elements().map(|x| u64::from_le_bytes(x) as f64).collect(),
+            Dtype::F16 => self.elements().map(|x| f16::from_le_bytes(x).to_f64()).collect(),
+            Dtype::F32 => self.elements().map(|x| f32::from_le_bytes(x) as f64).collect(),
+            Dtype::F64 => self.elements().map(f64::from_le_bytes).collect(),
+        }
+    }
+
+    pub fn to_f32(&self) -> Vec<f32> {
+        match self.dtype {
+            Dtype::F16 => self.elements().map(|x| f16::from_le_bytes(x).to_f32()).collect(),
+            Dtype::F32 => self.elements().map(f32::from_le_bytes).collect(),
+            _ => self.to_f64().into_iter().map(|x| x as f32).collect(),
+        }
+    }
+
+    pub fn to_i64(&self) -> Vec<i64> {
+        match self.dtype {
+            Dtype::I32 => self.elements().map(|x| i32::from_le_bytes(x) as i64).collect(),
+            Dtype::I64 => self.elements().map(i64::from_le_bytes).collect(),
+            _ => self.to_f64().into_iter().map(|x| x as i64).collect(),
+        }
+    }
+}
+
+
+
+/// Named arrays of an `.npz` archive or of a directory with `.npy` files.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NpyArchive {
+    pub arrays: HashMap<String, NpyArray>,
+}
+
+impl NpyArchive {
+    /// Reads an `.npz` archive, both stored and deflated entries are supported
+    pub fn read_npz(reader: impl Read + Seek) -> Result<Self, NpyError> {
+        let mut zip = zip::ZipArchive::new(reader)?;
+        let mut arrays = HashMap::with_capacity(zip.len());
+
+        for i in 0..zip.len() {
+            let file = zip.by_index(i)?;
+
+            let Some(name) = file.name().strip_suffix(".npy") else {
+                continue;
+            };
+
+            let name = name.to_owned();
+            arrays.insert(name, NpyArray::read(file)?);
+        }
+
+        Ok(Self { arrays })
+    }
+
+    /// Whether the directory has `.npy` files in it
+    pub fn is_npy_dir(path: impl AsRef<Path>) -> bool {
+        std::fs::read_dir(path).is_ok_and(|entries| {
+            entries.flatten().any(|entry| entry.path().extension().is_some_and(|ext| ext == "npy"))
+        })
+    }
+
+    /// Reads all `.npy` files of the directory, arrays are named after the files
+    pub fn read_dir(path: impl AsRef<Path>) -> Result<Self, NpyError> {
+        let mut arrays = HashMap::new();
+
+        for entry in std::fs::read_dir(path)? {
+            let path = entry?.path();
+
+            if path.extension().is_none_or(|ext| ext != "npy") {
+                continue;
+            }
+
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+
+            let file = std::io::BufReader::new(std::fs::File::open(&path)?);
+            arrays.insert(name.to_owned(), NpyArray::read(file)?);
+        }
+
+        Ok(Self { arrays })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&NpyArray, NpyError> {
+        self.arrays.get(name).ok_or_else(|| NpyError::MissingArray(name.to_owned()))
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    /// Version 1.0 `.npy` file with the header padded as NumPy does
+    fn npy_bytes(descr: &str, shape: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = format!("{{'descr': '{descr}', 'fortran_order': False, 'shape': {shape}, }}");
+
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+
+        header.push('\n');
+
+        let mut bytes = NPY_MAGIC.to_vec();
+        bytes.extend([1, 0]);
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(data);
+
+        bytes
+    }
+
+    #[test]
+    fn arrays_are_read_in_their_byte_order() {
+        let data = [1.5f32, -2.0, 3.25, 0.0, 8.0, -0.5];
+
+        let little = NpyArray::read(npy_bytes("<f4", "(2, 3)", bytemuck::cast_slice(&data)).as_slice()).unwrap();
+        assert_eq!(little.shape, [2, 3]);
+        assert_eq!(little.to_f32(), data);
+
+        let big_data = data.iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+        let big = NpyArray::read(npy_bytes(">f4", "(6,)", &big_data).as_slice()).unwrap();
+        assert_eq!(big.to_f32(), data);
+
+        let links = [-1i64, 0, 7, 1 << 40];
+        let links_data = links.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+        let array = NpyArray::read(npy_bytes("<i8", "(2, 2)", &links_data).as_slice()).unwrap();
+        assert_eq!(array.to_i64(), links);
+
+        let halves = [half::f16::from_f32(0.25), half::f16::from_f32(-3.0)];
+        let array = NpyArray::read(npy_bytes("<f2", "(2,)", bytemuck::cast_slice(&halves)).as_slice()).unwrap();
+        assert_eq!(array.to_f32(), [0.25, -3.0]);
+
+        // scalars have an empty shape and a single element
+        let scalar = NpyArray::read(npy_bytes("<f8", "()", &2.5f64.to_le_bytes()).as_slice()).unwrap();
+        assert_eq!(scalar.to_f32(), [2.5]);
+    }
+
+    #[test]
+    fn malformed_arrays_are_rejected() {
+        let mut fortran = npy_bytes("<f4", "(2,)", &[0; 8]);
+        let order = fortran.windows(5).position(|bytes| bytes == b"False").unwrap();
+        fortran[order..order + 5].copy_from_slice(b"True ");
+
+        assert!(matches!(NpyArray::read(fortran.as_slice()), Err(NpyError::FortranOrder)));
+        assert!(matches!(
+            NpyArray::read(npy_bytes("<f4", "(3,)", &[0; 8]).as_slice()),
+            Err(NpyError::Truncated { expected: 12, actual: 8 }),
+        ));
+        assert!(matches!(
+            NpyArray::read(npy_bytes("<c8", "(1,)", &[0; 8]).as_slice()),
+            Err(NpyError::UnsupportedDtype(_)),
+        ));
+        assert!(matches!(NpyArray::read(&b"PK\x03\x04 not an array"[..]), Err(NpyError::BadMagic)));
+    }
+
+    #[test]
+    fn npz_entries_are_named_after_their_files() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+        for (name, method, value) in [
+            ("density_data.npy", CompressionMethod::Deflated, 1.0f32),
+            ("radius.npy", CompressionMethod::Stored, 2.0),
+        ] {
+            zip.start_file(name, FileOptions::default().compression_method(method)).unwrap();
+            zip.write_all(&npy_bytes("<f4", "(1,)", &value.to_le_bytes())).unwrap();
+        }
+
+        zip.start_file("readme.txt", FileOptions::default()).unwrap();
+        zip.write_all(b"not an array").unwrap();
+
+        let archive = NpyArchive::read_npz(zip.finish().unwrap()).unwrap();
+
+        assert_eq!(archive.arrays.len(), 2);
+        assert_eq!(archive.get("density_data").unwrap().to_f32(), [1.0]);
+        assert_eq!(archive.get("radius").unwrap().to_f32(), [2.0]);
+        assert!(matches!(archive.get("links"), Err(NpyError::MissingArray(_))));
+    }
+}
@@ -0,0 +1,487 @@
+use crate::{
+    geometry::Aabb,
+    npy::{Dtype, NpyArray},
+    spherical::{Cell, Channel, Grid, RadianceField, SH_C0},
+};
+use bytemuck::Zeroable;
+use glam::*;
+use rayon::prelude::*;
+use std::{io::Write, path::Path};
+use thiserror::Error;
+
+
+
+#[derive(Debug, Error)]
+pub enum NrrdError {
+    #[error("not a NRRD file")]
+    BadMagic,
+
+    #[error("malformed NRRD field '{0}'")]
+    BadField(String),
+
+    #[error("NRRD field '{0}' is missing")]
+    MissingField(&'static str),
+
+    #[error("unsupported NRRD {0}")]
+    Unsupported(String),
+
+    #[error("volume data is {actual} bytes long, expected {expected}")]
+    Truncated { expected: usize, actual: usize },
+
+    #[error("channel '{0}' is missing in the field")]
+    MissingChannel(Channel),
+
+    #[error("volume has {actual} components per voxel, expected {expected}")]
+    BadComponents { expected: usize, actual: usize },
+
+    #[error("color volume has size {actual:?}, expected {expected:?}")]
+    SizeMismatch { expected: [usize; 3], actual: [usize; 3] },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+
+
+/// Color of the cells of a field imported from a density volume
+#[derive(Clone, Debug, PartialEq)]
+pub enum VolumeColor {
+    Constant(Vec3),
+    /// Volume with 3 components per voxel
+    Volume(Volume),
+}
+
+impl Default for VolumeColor {
+    fn default() -> Self {
+        Self::Constant(Vec3::ONE)
+    }
+}
+
+
+
+/// Cell-centered volume of `f32` values placed into a bounding box.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Volume {
+    /// Number of voxels along X, Y and Z axes
+    pub size: [usize; 3],
+    /// Number of values per voxel
+    pub n_components: usize,
+    /// Values with components changing fastest, then X, then Y and Z slowest, as NRRD stores them
+    pub values: Vec<f32>,
+    pub bounding_box: Aabb,
+}
+
+impl Volume {
+    /// Extracts a single channel of every cell of the grid
+    pub fn from_grid<const W: usize>(
+        grid: &(impl Grid<W> + Sync), channel: Channel, bounding_box: Aabb,
+    ) -> Result<Self, NrrdError> {
+        channel.get(&Cell::<W>::zeroed()).ok_or(NrrdError::MissingChannel(channel))?;
+
+        let size = grid.size();
+        let [sx, sy, _] = size;
+
+        let values = (0..RadianceField::volume(size))
+            .into_par_iter()
+            .map(|i| {
+                let index = [i % sx, i / sx % sy, i / (sx * sy)];
+                channel.get(&grid.cell(index).unwrap()).unwrap()
+            })
+            .collect();
+
+        Ok(Self { size, n_components: 1, values, bounding_box })
+    }
+
+    /// Builds a degree 0 field with the volume as density
+    pub fn to_field(&self, color: &VolumeColor) -> Result<RadianceField<1>, NrrdError> {
+        if self.n_components != 1 {
+            return Err(NrrdError::BadComponents { expected: 1, actual: self.n_components });
+        }
+
+        if let VolumeColor::Volume(color) = color {
+            if color.n_components != 3 {
+                return Err(NrrdError::BadComponents { expected: 3, actual: color.n_components });
+            }
+
+            if color.size != self.size {
+                return Err(NrrdError::SizeMismatch { expected: self.size, actual: color.size });
+            }
+        }
+
+        let [sx, sy, _] = self.size;
+
+        let cells = (0..RadianceField::volume(self.size))
+            .into_par_iter()
+            .map(|i| {
+                let [x, y, z] = RadianceField::index_from_flat(self.size, i);
+                let voxel = x + sx * (y + sy * z);
+
+                let rgb = match color {
+                    VolumeColor::Constant(rgb) => *rgb,
+                    VolumeColor::Volume(color) => Vec3::from_slice(&color.values[3 * voxel..]),
+                };
+
+                Cell {
+                    density: self.values[voxel],
+                    sh_r: [rgb.x / SH_C0],
+                    sh_g: [rgb.y / SH_C0],
+                    sh_b: [rgb.z / SH_C0],
+                }
+            })
+            .collect();
+
+        Ok(RadianceField { size: self.size, cells })
+    }
+
+    /// Distance between the neighbouring voxel centers
+    pub fn spacing(&self) -> Vec3 {
+        (self.bounding_box.hi - self.bounding_box.lo) / RadianceField::size_vec(self.size)
+    }
+
+    /// Writes the volume as raw little-endian `f32`s next to a detached `.nhdr` header
+    pub fn write_nhdr(&self, path: impl AsRef<Path>) -> Result<(), NrrdError> {
+        let path = path.as_ref();
+        let data_path = path.with_extension("raw");
+
+        let mut header = self.header();
+        header += &format!(
+            "data file: {}\n",
+            data_path.file_name().unwrap_or_default().to_string_lossy(),
+        );
+
+        std::fs::write(path, header)?;
+
+        let mut data = std::io::BufWriter::new(std::fs::File::create(&data_path)?);
+        self.write_data(&mut data)?;
+        data.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes the volume as a `.nrrd` file with raw little-endian `f32`s attached after the header
+    pub fn write_nrrd(&self, path: impl AsRef<Path>) -> Result<(), NrrdError> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+        file.write_all(self.header().as_bytes())?;
+        file.write_all(b"\n")?;
+        self.write_data(&mut file)?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Header fields describing the volume, without the data file
+    fn header(&self) -> String {
+        let spacing = self.spacing();
+        let origin = self.bounding_box.lo + 0.5 * spacing;
+        let [sx, sy, sz] = self.size;
+
+        let mut header = String::from("NRRD0004\n");
+
+        let (dimension, sizes, components) = match self.n_components {
+            1 => (3, format!("{sx} {sy} {sz}"), ""),
+            n => (4, format!("{n} {sx} {sy} {sz}"), "none "),
+        };
+
+        header += "type: float\n";
+        header += &format!("dimension: {dimension}\n");
+        header += "space dimension: 3\n";
+        header += &format!("sizes: {sizes}\n");
+        header += &format!(
+            "space directions: {components}({},0,0) (0,{},0) (0,0,{})\n",
+            spacing.x, spacing.y, spacing.z,
+        );
+        header += &format!("space origin: ({},{},{})\n", origin.x, origin.y, origin.z);
+
+        if self.n_components == 1 {
+            header += "centerings: cell cell cell\n";
+            header += "kinds: domain domain domain\n";
+        } else {
+            header += "centerings: ??? cell cell cell\n";
+            header += "kinds: vector domain domain domain\n";
+        }
+
+        header += "endian: little\n";
+        header += "encoding: raw\n";
+
+        header
+    }
+
+    fn write_data(&self, writer: &mut impl Write) -> Result<(), NrrdError> {
+        for value in &self.values {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a volume from a `.nhdr` header with detached data or a `.nrrd` file
+    /// with attached data. Only axis-aligned raw volumes are supported.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, NrrdError> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+
+        if !bytes.starts_with(b"NRRD000") {
+            return Err(NrrdError::BadMagic);
+        }
+
+        // header ends with an empty line, attached data follows it
+        let mut fields = Vec::new();
+        let mut rest = bytes.as_slice();
+
+        while let Some(end) = rest.iter().position(|&byte| byte == b'\n') {
+            let line = String::from_utf8_lossy(&rest[..end]).trim_end_matches('\r').to_owned();
+            rest = &rest[end + 1..];
+
+            if line.is_empty() {
+                break;
+            }
+
+            if line.starts_with('#') || line.starts_with("NRRD") {
+                continue;
+            }
+
+            // `key:=value` pairs carry user data
+            if let Some((key, value)) = line.split_once(": ") {
+                fields.push((key.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+
+        let field = |key: &str| fields.iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str());
+
+        let bad_field = |key: &str| NrrdError::BadField(key.to_owned());
+
+        let dtype = match field("type").ok_or(NrrdError::MissingField("type"))? {
+            "float" => Dtype::F32,
+            "double" => Dtype::F64,
+            "uchar" | "unsigned char" | "uint8" | "uint8_t" => Dtype::U8,
+            "signed char" | "int8" | "int8_t" => Dtype::I8,
+            "short" | "short int" | "signed short" | "int16" | "int16_t" => Dtype::I16,
+            "ushort" | "unsigned short" | "uint16" | "uint16_t" => Dtype::U16,
+            "int" | "signed int" | "int32" | "int32_t" => Dtype::I32,
+            "uint" | "unsigned int" | "uint32" | "uint32_t" => Dtype::U32,
+            dtype => return Err(NrrdError::Unsupported(format!("type '{dtype}'"))),
+        };
+
+        match field("encoding").ok_or(NrrdError::MissingField("encoding"))? {
+            "raw" => {},
+            encoding => return Err(NrrdError::Unsupported(format!("encoding '{encoding}'"))),
+        }
+
+        let big_endian = field("endian") == Some("big");
+
+        let sizes = field("sizes")
+            .ok_or(NrrdError::MissingField("sizes"))?
+            .split_whitespace()
+            .map(|size| size.parse().map_err(|_| bad_field("sizes")))
+            .collect::<Result<Vec<usize>, _>>()?;
+
+        let (n_components, size) = match *sizes.as_slice() {
+            [sx, sy, sz] => (1, [sx, sy, sz]),
+            [n, sx, sy, sz] => (n, [sx, sy, sz]),
+            _ => return Err(NrrdError::Unsupported(format!("dimension {}", sizes.len()))),
+        };
+
+        let spacing = match (field("space directions"), field("spacings")) {
+            (Some(directions), _) => {
+                let vectors = directions.split_whitespace()
+                    .filter(|&vector| vector != "none")
+                    .map(|vector| {
+                        vector.trim_start_matches('(').trim_end_matches(')').split(',')
+                            .map(|x| x.trim().parse::<f32>().map_err(|_| bad_field("space directions")))
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let [x, y, z] = vectors.as_slice() else {
+                    return Err(bad_field("space directions"));
+                };
+
+                if [x, y, z].iter().any(|vector| vector.len() != 3)
+                    || [x[1], x[2], y[0], y[2], z[0], z[1]].iter().any(|&value| value != 0.0)
+                {
+                    return Err(NrrdError::Unsupported(String::from("non axis-aligned space directions")));
+                }
+
+                vec3(x[0], y[1], z[2])
+            },
+            (None, Some(spacings)) => {
+                let spacings = spacings.split_whitespace()
+                    .filter(|&spacing| spacing != "nan" && spacing != "NaN")
+                    .map(|spacing| spacing.parse::<f32>().map_err(|_| bad_field("spacings")))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                match *spacings.as_slice() {
+                    [x, y, z] => vec3(x, y, z),
+                    _ => return Err(bad_field("spacings")),
+                }
+            },
+            (None, None) => Vec3::ONE,
+        };
+
+        let origin = match field("space origin") {
+            Some(origin) => {
+                let origin = origin.trim_start_matches('(').trim_end_matches(')').split(',')
+                    .map(|x| x.trim().parse::<f32>().map_err(|_| bad_field("space origin")))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                match *origin.as_slice() {
+                    [x, y, z] => vec3(x, y, z),
+                    _ => return Err(bad_field("space origin")),
+                }
+            },
+            None => Vec3::ZERO,
+        };
+
+        let data = match field("data file").or(field("datafile")) {
+            Some(name) => {
+                if name.starts_with("LIST") || name.contains(' ') {
+                    return Err(NrrdError::Unsupported(String::from("multiple data files")));
+                }
+
+                std::fs::read(path.parent().unwrap_or(Path::new(".")).join(name))?
+            },
+            None => rest.to_vec(),
+        };
+
+        let skip = match field("byte skip") {
+            Some(skip) => skip.parse::<isize>().map_err(|_| bad_field("byte skip"))?,
+            None => 0,
+        };
+
+        if spacing.cmple(Vec3::ZERO).any() {
+            return Err(NrrdError::Unsupported(String::from("non-positive spacing")));
+        }
+
+        let n_values = n_components * RadianceField::volume(size);
+        let expected = n_values * dtype.size();
+
+        // negative skip means the data is at the end of the file
+        let start = match skip {
+            -1 => data.len().saturating_sub(expected),
+            skip => skip.max(0) as usize,
+        };
+
+        let data = data.get(start..start + expected)
+            .ok_or(NrrdError::Truncated { expected, actual: data.len().saturating_sub(start) })?;
+
+        let values = NpyArray {
+            dtype,
+            big_endian,
+            shape: vec![n_values],
+            data: data.to_vec(),
+        }.to_f32();
+
+        let lo = origin - 0.5 * spacing;
+        let hi = lo + spacing * RadianceField::size_vec(size);
+
+        Ok(Self {
+            size,
+            n_components,
+            values,
+            bounding_box: Aabb::new(lo, hi),
+        })
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procedural::Procedural;
+    use std::path::PathBuf;
+
+    /// Empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("radiance_fields_nrrd_{name}_{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn volumes_round_trip_with_attached_and_detached_data() {
+        let dir = test_dir("round_trip");
+        let field = Procedural::Noise { density: 10.0, frequency: 2, seed: 9 }.generate([5, 3, 4]);
+        let bounding_box = Aabb::new(vec3(-1.0, 0.0, 2.0), vec3(1.5, 0.75, 3.0));
+
+        let channel = Channel::Sh { color: 1, coefficient: 0 };
+        let volume = Volume::from_grid(&field, channel, bounding_box).unwrap();
+
+        // X changes fastest in the volume
+        assert_eq!(volume.values[1], field.cells[RadianceField::index_of([5, 3, 4], [1, 0, 0])].sh_g[0]);
+        assert_eq!(volume.values[5], field.cells[RadianceField::index_of([5, 3, 4], [0, 1, 0])].sh_g[0]);
+
+        volume.write_nrrd(dir.join("volume.nrrd")).unwrap();
+        volume.write_nhdr(dir.join("volume.nhdr")).unwrap();
+
+        for name in ["volume.nrrd", "volume.nhdr"] {
+            let read = Volume::read(dir.join(name)).unwrap();
+
+            assert_eq!(read.size, volume.size);
+            assert_eq!(read.values, volume.values);
+            assert!(read.bounding_box.lo.abs_diff_eq(bounding_box.lo, 1e-5), "{name} box {:?}", read.bounding_box);
+            assert!(read.bounding_box.hi.abs_diff_eq(bounding_box.hi, 1e-5), "{name} box {:?}", read.bounding_box);
+        }
+
+        assert!(matches!(
+            Volume::from_grid(&field, Channel::Sh { color: 0, coefficient: 4 }, bounding_box),
+            Err(NrrdError::MissingChannel(_)),
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn foreign_headers_are_read() {
+        let dir = test_dir("foreign");
+        let path = dir.join("ct.nrrd");
+
+        let values = (0..24u16).map(|i| 100 * i).collect::<Vec<_>>();
+        let mut bytes = b"NRRD0004\n# written elsewhere\ntype: unsigned short\ndimension: 3\n\
+            sizes: 2 3 4\nspacings: 0.5 1 2\nendian: big\nencoding: raw\nbyte skip: -1\n\n\
+            trailing bytes before the data\n".to_vec();
+        bytes.extend(values.iter().flat_map(|value| value.to_be_bytes()));
+        std::fs::write(&path, bytes).unwrap();
+
+        let volume = Volume::read(&path).unwrap();
+
+        assert_eq!(volume.size, [2, 3, 4]);
+        assert_eq!(volume.values, values.iter().map(|&value| value as f32).collect::<Vec<_>>());
+        assert_eq!(volume.bounding_box, Aabb::new(vec3(-0.25, -0.5, -1.0), vec3(0.75, 2.5, 7.0)));
+
+        let field = volume.to_field(&VolumeColor::Constant(vec3(1.0, 0.5, 0.0))).unwrap();
+
+        assert_eq!(field.get([1, 2, 3]).unwrap().density, volume.values[1 + 2 * (2 + 3 * 3)]);
+        assert!(field.get([0; 3]).unwrap().diffuse().abs_diff_eq(vec3(1.0, 0.5, 0.0), 1e-6));
+
+        std::fs::write(&path, b"NRRD0004\ntype: float\nsizes: 2 3 4\nencoding: gzip\n\n").unwrap();
+        assert!(matches!(Volume::read(&path), Err(NrrdError::Unsupported(_))));
+
+        std::fs::write(&path, b"NRRD0004\ntype: float\nsizes: 2 3 4\nencoding: raw\n\n\0\0\0\0").unwrap();
+        assert!(matches!(Volume::read(&path), Err(NrrdError::Truncated { expected: 96, actual: 4 })));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn color_volumes_must_match_the_density() {
+        let bounding_box = Aabb::new(Vec3::ZERO, Vec3::ONE);
+        let density = Volume { size: [2, 1, 1], n_components: 1, values: vec![1.0, 2.0], bounding_box };
+        let color = Volume {
+            size: [2, 1, 1], n_components: 3, values: vec![1.0, 0.0, 0.0, 0.0, 0.0, 1.0], bounding_box,
+        };
+
+        let field = density.to_field(&VolumeColor::Volume(color.clone())).unwrap();
+        assert!(field.get([1, 0, 0]).unwrap().diffuse().abs_diff_eq(vec3(0.0, 0.0, 1.0), 1e-6));
+
+        let small = Volume { size: [1; 3], values: vec![0.0; 3], ..color };
+        assert!(matches!(
+            density.to_field(&VolumeColor::Volume(small)),
+            Err(NrrdError::SizeMismatch { expected: [2, 1, 1], actual: [1, 1, 1] }),
+        ));
+    }
+}
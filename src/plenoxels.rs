@@ -0,0 +1,304 @@
+use crate::{
+    geometry::Aabb,
+    npy::{NpyArchive, NpyError},
+    spherical::{sh_degree, Cell, Grid, RadianceField, SH_C0},
+};
+use bytemuck::Zeroable;
+use glam::*;
+use thiserror::Error;
+
+
+
+#[derive(Debug, Error)]
+pub enum PlenoxelsError {
+    #[error(transparent)]
+    Npy(#[from] NpyError),
+
+    #[error("array '{name}' has shape {shape:?}, expected {expected}")]
+    BadShape { name: &'static str, shape: Vec<usize>, expected: &'static str },
+
+    #[error("'sh_data' holds {0} coefficients per color, which is not a number of \
+             spherical harmonics of degree up to 3")]
+    UnsupportedBasis(usize),
+
+    #[error("link {link} is out of range of {n_voxels} stored voxels")]
+    BadLink { link: i64, n_voxels: usize },
+}
+
+
+
+/// Plenoxels (svox2) checkpoint: a dense grid of `links` into the arrays of the occupied voxels.
+///
+/// Color of a voxel is stored without the `0.5` bias which svox2 adds on evaluation
+/// and its density without the ReLU, both are applied when the voxel is read.
+/// Empty cells are read as zero cells without the bias.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlenoxelsCheckpoint {
+    /// Number of cells along X, Y and Z axes
+    pub size: [usize; 3],
+    /// Index of each cell's voxel in the voxel arrays or `-1` for empty cells,
+    /// laid out the same way as cells in [`RadianceField`]
+    pub links: Vec<i32>,
+    /// Density of each voxel
+    pub density: Vec<f32>,
+    /// `3 * basis_dim` coefficients of each voxel, all red ones first, then green, then blue
+    pub sh: Vec<f32>,
+    /// Number of spherical harmonic coefficients per color
+    pub basis_dim: usize,
+    /// Region of the world covered by the grid, `center ± radius`
+    pub bounding_box: Aabb,
+}
+
+impl PlenoxelsCheckpoint {
+    pub fn from_archive(archive: &NpyArchive) -> Result<Self, PlenoxelsError> {
+        let links = archive.get("links")?;
+        let density = archive.get("density_data")?;
+        let sh = archive.get("sh_data")?;
+
+        let &[sx, sy, sz] = links.shape.as_slice() else {
+            return Err(PlenoxelsError::BadShape {
+                name: "links", shape: links.shape.clone(), expected: "[x, y, z]",
+            });
+        };
+
+        let n_voxels = density.shape.first().copied().unwrap_or_default();
+
+        if density.len() != n_voxels {
+            return Err(PlenoxelsError::BadShape {
+                name: "density_data", shape: density.shape.clone(), expected: "[n_voxels, 1]",
+            });
+        }
+
+        let (n_sh_voxels, n_coefficients) = match *sh.shape.as_slice() {
+            [n, m] => (n, m),
+            _ => (usize::MAX, 0),
+        };
+
+        if n_sh_voxels != n_voxels || n_coefficients % 3 != 0 {
+            return Err(PlenoxelsError::BadShape {
+                name: "sh_data", shape: sh.shape.clone(), expected: "[n_voxels, 3 * basis_dim]",
+            });
+        }
+
+        let basis_dim = n_coefficients / 3;
+
+        if sh_degree(basis_dim).is_none() {
+            return Err(PlenoxelsError::UnsupportedBasis(basis_dim));
+        }
+
+        let size = [sx, sy, sz];
+        let svox_links = links.to_i64();
+
+        if let Some(&link) = svox_links.iter().find(|&&link| link >= n_voxels as i64) {
+            return Err(PlenoxelsError::BadLink { link, n_voxels });
+        }
+
+        // svox2 lays the links out with X slowest and Z fastest
+        let links = (0..RadianceField::volume(size))
+            .map(|i| {
+                let [x, y, z] = RadianceField::index_from_flat(size, i);
+                svox_links[(x * sy + y) * sz + z].max(-1) as i32
+            })
+            .collect();
+
+        let bounding_box = {
+            let vector = |name: &'static str| -> Result<Vec3, PlenoxelsError> {
+                let array = archive.get(name)?;
+
+                match *array.to_f32().as_slice() {
+                    [value] => Ok(Vec3::splat(value)),
+                    [x, y, z] => Ok(vec3(x, y, z)),
+                    _ => Err(PlenoxelsError::BadShape {
+                        name, shape: array.shape.clone(), expected: "[3]",
+                    }),
+                }
+            };
+
+            let center = vector("center")?;
+            let radius = vector("radius")?;
+
+            Aabb::new(center - radius, center + radius)
+        };
+
+        Ok(Self {
+            size,
+            links,
+            density: density.to_f32(),
+            sh: sh.to_f32(),
+            basis_dim,
+            bounding_box,
+        })
+    }
+
+    /// Spherical harmonic degree of the stored coefficients
+    pub fn sh_degree(&self) -> u32 {
+        sh_degree(self.basis_dim).unwrap()
+    }
+
+    /// Number of occupied voxels
+    pub fn n_voxels(&self) -> usize {
+        self.density.len()
+    }
+}
+
+/// Coefficients beyond `basis_dim` are filled with zeros and the ones beyond `W` are dropped
+impl<const W: usize> Grid<W> for PlenoxelsCheckpoint {
+    fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    fn cell(&self, index: [usize; 3]) -> Option<Cell<W>> {
+        if !RadianceField::contains(self.size, index) {
+            return None;
+        }
+
+        // empty cells stay zero like the missing bricks of a sparse field, so the
+        // color fades to black instead of the svox2 grey toward the empty space
+        let Ok(voxel) = usize::try_from(self.links[RadianceField::index_of(self.size, index)]) else {
+            return Some(Cell::zeroed());
+        };
+
+        // svox2 adds 0.5 to the interpolated color
+        let mut cell = Cell::<W>::zeroed();
+        cell.sh_r[0] = 0.5 / SH_C0;
+        cell.sh_g[0] = 0.5 / SH_C0;
+        cell.sh_b[0] = 0.5 / SH_C0;
+
+        let len = W.min(self.basis_dim);
+        let coefficients = |channel: usize| {
+            let start = (3 * voxel + channel) * self.basis_dim;
+            &self.sh[start..start + len]
+        };
+
+        for (sh, channel) in [&mut cell.sh_r, &mut cell.sh_g, &mut cell.sh_b].into_iter().zip(0..) {
+            for (value, coefficient) in sh.iter_mut().zip(coefficients(channel)) {
+                *value += coefficient;
+            }
+        }
+
+        cell.density = self.density[voxel].max(0.0);
+
+        Some(cell)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{npy::{Dtype, NpyArray}, sparse::SparseField, spherical::BorderMode};
+
+    /// Checkpoint of two bricks along X with the voxels in the last cells of the first brick
+    fn border_checkpoint() -> PlenoxelsCheckpoint {
+        let size = [16, 8, 8];
+        let mut links = vec![-1; RadianceField::volume(size)];
+        let (mut density, mut sh) = (Vec::new(), Vec::new());
+
+        for y in 0..8 {
+            for z in 0..8 {
+                for x in 5..8 {
+                    links[RadianceField::index_of(size, [x, y, z])] = density.len() as i32;
+                    density.push(10.0);
+                    sh.extend([0.1 * x as f32, -0.2, 0.3 * (y + z) as f32]);
+                }
+            }
+        }
+
+        PlenoxelsCheckpoint {
+            size, links, density, sh, basis_dim: 1, bounding_box: Aabb::new(Vec3::ZERO, Vec3::ONE),
+        }
+    }
+
+    #[test]
+    fn only_occupied_voxels_get_the_bias() {
+        let checkpoint = border_checkpoint();
+
+        let empty: Cell<1> = checkpoint.cell([2, 3, 4]).unwrap();
+        assert_eq!(empty, Cell::zeroed());
+
+        let occupied: Cell<1> = checkpoint.cell([6, 3, 4]).unwrap();
+        assert_eq!(occupied.density, 10.0);
+        assert!(occupied.diffuse().abs_diff_eq(vec3(0.5, 0.5, 0.5) + SH_C0 * vec3(0.6, -0.2, 2.1), 1e-6));
+    }
+
+    #[test]
+    fn sparse_import_samples_like_dense_checkpoint_at_brick_borders() {
+        let checkpoint = border_checkpoint();
+        let dense = RadianceField::<1>::from_grid(&checkpoint);
+        let sparse = SparseField::<1>::from_grid(&checkpoint, 0.0);
+
+        assert_eq!(sparse.n_occupied_bricks(), 1);
+
+        for i in 0..=64 {
+            // X crosses the border between the bricks at 0.5
+            let pos = vec3(0.25 + 0.5 * i as f32 / 64.0, 0.3, 0.7);
+
+            for border in [BorderMode::Zero, BorderMode::Clamp] {
+                assert_eq!(sparse.sample_trilinear(pos, border), dense.sample_trilinear(pos, border), "at {pos}");
+            }
+        }
+    }
+
+    fn f32_array(shape: &[usize], values: &[f32]) -> NpyArray {
+        NpyArray {
+            dtype: Dtype::F32, big_endian: false, shape: shape.to_vec(), data: bytemuck::cast_slice(values).to_vec(),
+        }
+    }
+
+    #[test]
+    fn archive_links_are_read_with_x_slowest() {
+        let size = [2, 3, 4];
+
+        // every third cell is occupied, in the svox2 order
+        let svox_links = (0..24).map(|i| if i % 3 == 0 { i / 3 } else { -1 }).collect::<Vec<i32>>();
+        let density = [1.0, -2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+        let sh = (0..8 * 12).map(|i| i as f32).collect::<Vec<_>>();
+
+        let archive = NpyArchive {
+            arrays: [
+                ("links", NpyArray {
+                    dtype: Dtype::I32, big_endian: false, shape: size.to_vec(),
+                    data: bytemuck::cast_slice(&svox_links).to_vec(),
+                }),
+                ("density_data", f32_array(&[8, 1], &density)),
+                ("sh_data", f32_array(&[8, 12], &sh)),
+                ("radius", f32_array(&[1], &[2.0])),
+                ("center", f32_array(&[3], &[1.0, 0.0, -1.0])),
+            ].map(|(name, array)| (name.to_owned(), array)).into(),
+        };
+
+        let checkpoint = PlenoxelsCheckpoint::from_archive(&archive).unwrap();
+
+        assert_eq!(checkpoint.sh_degree(), 1);
+        assert_eq!(checkpoint.bounding_box, Aabb::new(vec3(-1.0, -2.0, -3.0), vec3(3.0, 2.0, 1.0)));
+
+        for (i, &link) in svox_links.iter().enumerate() {
+            let index = [i / 12, i / 4 % 3, i % 4];
+            let cell: Cell<9> = checkpoint.cell(index).unwrap();
+
+            let Ok(voxel) = usize::try_from(link) else {
+                assert_eq!(cell, Cell::zeroed(), "empty cell {index:?}");
+                continue;
+            };
+
+            let coefficient = |channel: usize, j: usize| sh[12 * voxel + 4 * channel + j];
+
+            assert_eq!(cell.density, density[voxel].max(0.0), "density of {index:?}");
+            assert_eq!(cell.sh_r[0], coefficient(0, 0) + 0.5 / SH_C0);
+            assert_eq!(cell.sh_g[0], coefficient(1, 0) + 0.5 / SH_C0);
+            assert_eq!(cell.sh_g[1..4], [coefficient(1, 1), coefficient(1, 2), coefficient(1, 3)]);
+            assert_eq!(cell.sh_b[3], coefficient(2, 3));
+            // coefficients beyond the stored degree are zero
+            assert_eq!(cell.sh_b[4..], [0.0; 5]);
+        }
+
+        let mut bad_links = archive;
+        bad_links.arrays.get_mut("links").unwrap().data[..4].copy_from_slice(&8i32.to_le_bytes());
+
+        assert!(matches!(
+            PlenoxelsCheckpoint::from_archive(&bad_links),
+            Err(PlenoxelsError::BadLink { link: 8, n_voxels: 8 }),
+        ));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::npy::Dtype;
+use half::f16;
+use std::{collections::HashMap, io::BufRead};
+use thiserror::Error;
+
+
+
+#[derive(Debug, Error)]
+pub enum PlyError {
+    #[error("not a PLY file")]
+    BadMagic,
+
+    #[error("malformed PLY header line '{0}'")]
+    BadHeader(String),
+
+    #[error("unsupported PLY {0}")]
+    Unsupported(String),
+
+    #[error("PLY data of element '{0}' is truncated or malformed")]
+    Truncated(String),
+
+    #[error("PLY element '{0}' is missing")]
+    MissingElement(String),
+
+    #[error("PLY property '{property}' of element '{element}' is missing")]
+    MissingProperty { element: String, property: String },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+
+
+#[derive(Clone, Debug, PartialEq, Copy, Eq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlyProperty {
+    pub name: String,
+    pub dtype: Dtype,
+    /// Type of the item count if the property is a list
+    pub count_dtype: Option<Dtype>,
+}
+
+
+
+/// Element of a PLY file with its values. Scalar properties are read as `f32`
+/// and items of list properties as `i64`, which suits vertex attributes and indices.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlyElement {
+    pub name: String,
+    pub count: usize,
+    pub properties: Vec<PlyProperty>,
+    pub scalars: HashMap<String, Vec<f32>>,
+    pub lists: HashMap<String, Vec<Vec<i64>>>,
+}
+
+impl PlyElement {
+    pub fn scalar(&self, name: &str) -> Result<&[f32], PlyError> {
+        self.scalars.get(name).map(Vec::as_slice).ok_or_else(|| self.missing(name))
+    }
+
+    pub fn list(&self, name: &str) -> Result<&[Vec<i64>], PlyError> {
+        self.lists.get(name).map(Vec::as_slice).ok_or_else(|| self.missing(name))
+    }
+
+    fn missing(&self, property: &str) -> PlyError {
+        PlyError::MissingProperty { element: self.name.clone(), property: property.to_owned() }
+    }
+}
+
+
+
+/// Contents of an ASCII or binary PLY file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ply {
+    pub elements: Vec<PlyElement>,
+}
+
+impl Ply {
+    pub fn element(&self, name: &str) -> Result<&PlyElement, PlyError> {
+        self.elements.iter()
+            .find(|element| element.name == name)
+            .ok_or_else(|| PlyError::MissingElement(name.to_owned()))
+    }
+
+    pub fn read(mut reader: impl BufRead) -> Result<Self, PlyError> {
+        let mut line = String::new();
+
+        let mut next_line = |line: &mut String| -> Result<(), PlyError> {
+            line.clear();
+
+            match reader.read_line(line)? {
+                0 => Err(PlyError::BadHeader(String::from("end_header"))),
+                _ => Ok(()),
+            }
+        };
+
+        next_line(&mut line)?;
+
+        if line.trim_end() != "ply" {
+            return Err(PlyError::BadMagic);
+        }
+
+        let mut format = None;
+        let mut elements = Vec::<PlyElement>::new();
+
+        loop {
+            next_line(&mut line)?;
+
+            let bad_header = || PlyError::BadHeader(line.trim_end().to_owned());
+            let words = line.split_whitespace().collect::<Vec<_>>();
+
+            match words.as_slice() {
+                ["end_header"] => break,
+                ["comment" | "obj_info", ..] | [] => continue,
+                ["format", name, _version] => format = Some(match *name {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::BinaryLittleEndian,
+                    "binary_big_endian" => PlyFormat::BinaryBigEndian,
+                    name => return Err(PlyError::Unsupported(format!("format '{name}'"))),
+                }),
+                ["element", name, count] => elements.push(PlyElement {
+                    name: name.to_string(),
+                    count: count.parse().map_err(|_| bad_header())?,
+                    ..Default::default()
+                }),
+                ["property", "list", count_dtype, dtype, name] => {
+                    elements.last_mut().ok_or_else(bad_header)?.properties.push(PlyProperty {
+                        name: name.to_string(),
+                        dtype: parse_dtype(dtype)?,
+                        count_dtype: Some(parse_dtype(count_dtype)?),
+                    });
+                },
+                ["property", dtype, name] => {
+                    elements.last_mut().ok_or_else(bad_header)?.properties.push(PlyProperty {
+                        name: name.to_string(),
+                        dtype: parse_dtype(dtype)?,
+                        count_dtype: None,
+                    });
+                },
+                _ => return Err(bad_header()),
+            }
+        }
+
+        let format = format.ok_or(PlyError::BadHeader(String::from("format")))?;
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        match format {
+            PlyFormat::Ascii => read_ascii(&mut elements, &data)?,
+            PlyFormat::BinaryLittleEndian => read_binary(&mut elements, &data, false)?,
+            PlyFormat::BinaryBigEndian => read_binary(&mut elements, &data, true)?,
+        }
+
+        Ok(Self { elements })
+    }
+}
+
+fn parse_dtype(name: &str) -> Result<Dtype, PlyError> {
+    Ok(match name {
+        "char" | "int8" => Dtype::I8,
+        "uchar" | "uint8" => Dtype::U8,
+        "short" | "int16" => Dtype::I16,
+        "ushort" | "uint16" => Dtype::U16,
+        "int" | "int32" => Dtype::I32,
+        "uint" | "uint32" => Dtype::U32,
+        "float" | "float32" => Dtype::F32,
+        "double" | "float64" => Dtype::F64,
+        name => return Err(PlyError::Unsupported(format!("property type '{name}'"))),
+    })
+}
+
+fn decode(dtype: Dtype, bytes: &[u8], big_endian: bool) -> f64 {
+    let mut buffer = [0; 8];
+    buffer[..bytes.len()].copy_from_slice(bytes);
+
+    if big_endian {
+        buffer[..bytes.len()].reverse();
+    }
+
+    let [b0, b1, b2, b3, ..] = buffer;
+
+    match dtype {
+        Dtype::Bool | Dtype::U8 => b0 as f64,
+        Dtype::I8 => b0 as i8 as f64,
+        Dtype::I16 => i16::from_le_bytes([b0, b1]) as f64,
+        Dtype::U16 => u16::from_le_bytes([b0, b1]) as f64,
+        Dtype::F16 => f16::from_le_bytes([b0, b1]).to_f64(),
+        Dtype::I32 => i32::from_le_bytes([b0, b1, b2, b3]) as f64,
+        Dtype::U32 => u32::from_le_bytes([b0, b1, b2, b3]) as f64,
+        Dtype::F32 => f32::from_le_bytes([b0, b1, b2, b3]) as f64,
+        Dtype::I64 => i64::from_le_bytes(buffer) as f64,
+        Dtype::U64 => u64::from_le_bytes(buffer) as f64,
+        Dtype::F64 => f64::from_le_bytes(buffer),
+    }
+}
+
+fn read_binary(elements: &mut [PlyElement], data: &[u8], big_endian: bool) -> Result<(), PlyError> {
+    let mut cursor = 0;
+
+    for element in elements {
+        let truncated = || PlyError::Truncated(element.name.clone());
+
+        let mut scalars = vec![Vec::new(); element.properties.len()];
+        let mut lists = vec![Vec::new(); element.properties.len()];
+
+        let mut take = |size: usize| -> Option<&[u8]> {
+            let bytes = data.get(cursor..cursor + size)?;
+            cursor += size;
+            Some(bytes)
+        };
+
+        for _ in 0..element.count {
+            for (i, property) in element.properties.iter().enumerate() {
+                let size = property.dtype.size();
+
+                match property.count_dtype {
+                    None => {
+                        let bytes = take(size).ok_or_else(truncated)?;
+                        scalars[i].push(decode(property.dtype, bytes, big_endian) as f32);
+                    },
+                    Some(count_dtype) => {
+                        let bytes = take(count_dtype.size()).ok_or_else(truncated)?;
+                        let count = decode(count_dtype, bytes, big_endian) as usize;
+
+                        let bytes = take(count * size).ok_or_else(truncated)?;
+
+                        lists[i].push(bytes.chunks_exact(size)
+                            .map(|item| decode(property.dtype, item, big_endian) as i64)
+                            .collect());
+                    },
+                }
+            }
+        }
+
+        store(element, scalars, lists);
+    }
+
+    Ok(())
+}
+
+fn read_ascii(elements: &mut [PlyElement], data: &[u8]) -> Result<(), PlyError> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+
+    for element in elements {
+        let truncated = || PlyError::Truncated(element.name.clone());
+
+        let mut scalars = vec![Vec::new(); element.properties.len()];
+        let mut lists = vec![Vec::new(); element.properties.len()];
+
+        for _ in 0..element.count {
+            let mut values = lines.next()
+                .ok_or_else(truncated)?
+                .split_whitespace()
+                .map(|value| value.parse::<f64>().map_err(|_| truncated()));
+
+            let mut next_value = || values.next().unwrap_or_else(|| Err(truncated()));
+
+            for (i, property) in element.properties.iter().enumerate() {
+                match property.count_dtype {
+                    None => scalars[i].push(next_value()? as f32),
+                    Some(_) => {
+                        let count = next_value()? as usize;
+
+                        lists[i].push((0..count)
+                            .map(|_| next_value().map(|value| value as i64))
+                            .collect::<Result<_, _>>()?);
+                    },
+                }
+            }
+        }
+
+        store(element, scalars, lists);
+    }
+
+    Ok(())
+}
+
+fn store(element: &mut PlyElement, scalars: Vec<Vec<f32>>, lists: Vec<Vec<Vec<i64>>>) {
+    for ((property, scalars), lists) in element.properties.iter().zip(scalars).zip(lists) {
+        if property.count_dtype.is_some() {
+            element.lists.insert(property.name.clone(), lists);
+        } else {
+            element.scalars.insert(property.name.clone(), scalars);
+        }
+    }
+}
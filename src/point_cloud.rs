@@ -0,0 +1,155 @@
+use crate::{
+    geometry::Aabb,
+    mesh::to_rgb8,
+    spherical::{Channel, Grid, RadianceField},
+};
+use glam::*;
+use rayon::prelude::*;
+use std::io::{BufWriter, Write};
+
+
+
+/// Points at the centers of the occupied cells of a field.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PointCloud {
+    pub positions: Vec<Vec3>,
+    /// Colors of the degree 0 spherical harmonic term in the [0, 1] range
+    pub colors: Vec<Vec3>,
+    pub densities: Vec<f32>,
+    /// Spherical harmonic coefficients of every point in the order of [`Channel::from_index`],
+    /// empty if they are not exported
+    pub sh: Vec<f32>,
+    /// Number of spherical harmonic coefficients per point
+    pub n_sh_coefficients: usize,
+}
+
+impl PointCloud {
+    /// Collects cells with density above the threshold, optionally with all of their coefficients
+    pub fn from_grid<const W: usize>(
+        grid: &(impl Grid<W> + Sync), bounding_box: Aabb, density_threshold: f32, with_sh: bool,
+    ) -> Self {
+        let size = grid.size();
+        let cell_size = (bounding_box.hi - bounding_box.lo) / RadianceField::size_vec(size);
+
+        let cells = (0..RadianceField::volume(size))
+            .into_par_iter()
+            .filter_map(|i| {
+                let index = RadianceField::index_from_flat(size, i);
+                let cell = grid.cell(index).unwrap();
+
+                (cell.density > density_threshold).then_some((index, cell))
+            })
+            .collect::<Vec<_>>();
+
+        let n_sh_coefficients = if with_sh { 3 * W } else { 0 };
+
+        Self {
+            positions: cells.iter()
+                .map(|(index, _)| {
+                    let index = Vec3::from_array(index.map(|i| i as f32));
+                    bounding_box.lo + cell_size * (index + 0.5)
+                })
+                .collect(),
+            colors: cells.iter()
+                .map(|(_, cell)| cell.diffuse().clamp(Vec3::ZERO, Vec3::ONE))
+                .collect(),
+            densities: cells.iter().map(|(_, cell)| cell.density).collect(),
+            sh: cells.iter()
+                .flat_map(|(_, cell)| (1..1 + n_sh_coefficients).map(|i| {
+                    Channel::from_index(W, i).get(cell).unwrap()
+                }))
+                .collect(),
+            n_sh_coefficients,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Keeps `count` points chosen at random, in their original order
+    pub fn subsampled(&self, count: usize, seed: u64) -> Self {
+        if count >= self.len() {
+            return self.clone();
+        }
+
+        // partial Fisher-Yates shuffle with a SplitMix64 generator
+        let mut state = seed;
+        let mut next_random = || {
+            state = state.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            z ^ (z >> 31)
+        };
+
+        let mut indices = (0..self.len()).collect::<Vec<_>>();
+
+        for i in 0..count {
+            let j = i + (next_random() % (self.len() - i) as u64) as usize;
+            indices.swap(i, j);
+        }
+
+        indices.truncate(count);
+        indices.sort_unstable();
+
+        let n = self.n_sh_coefficients;
+
+        Self {
+            positions: indices.iter().map(|&i| self.positions[i]).collect(),
+            colors: indices.iter().map(|&i| self.colors[i]).collect(),
+            densities: indices.iter().map(|&i| self.densities[i]).collect(),
+            sh: indices.iter().flat_map(|&i| self.sh[n * i..n * (i + 1)].iter().copied()).collect(),
+            n_sh_coefficients: n,
+        }
+    }
+
+    /// Writes the points as binary little-endian PLY. Coefficients are stored as float
+    /// properties named like `sh_r_0` after the density.
+    pub fn write_ply(&self, writer: impl Write) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+
+        write!(
+            writer,
+            "ply\n\
+             format binary_little_endian 1.0\n\
+             element vertex {}\n\
+             property float x\n\
+             property float y\n\
+             property float z\n\
+             property uchar red\n\
+             property uchar green\n\
+             property uchar blue\n\
+             property float density\n",
+            self.len(),
+        )?;
+
+        let width = self.n_sh_coefficients / 3;
+
+        for i in 0..self.n_sh_coefficients {
+            let name = match Channel::from_index(width, 1 + i) {
+                Channel::Sh { color, coefficient } => format!("sh_{}_{coefficient}", Channel::COLORS[color]),
+                Channel::Density => unreachable!("coefficients follow the density"),
+            };
+
+            writeln!(writer, "property float {name}")?;
+        }
+
+        writeln!(writer, "end_header")?;
+
+        for i in 0..self.len() {
+            writer.write_all(bytemuck::bytes_of(&self.positions[i]))?;
+            writer.write_all(&to_rgb8(self.colors[i]))?;
+            writer.write_all(&self.densities[i].to_le_bytes())?;
+
+            let n = self.n_sh_coefficients;
+            writer.write_all(bytemuck::cast_slice(&self.sh[n * i..n * (i + 1)]))?;
+        }
+
+        writer.flush()
+    }
+}
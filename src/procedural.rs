@@ -0,0 +1,417 @@
+use crate::{
+    benchmark::Bench,
+    geometry::{intersect_ray_box, Aabb, Ray},
+    graphics::RenderConfiguration,
+    render_cpu,
+    spherical::{Cell, RadianceField, SH_C0, SH_C1},
+};
+use bytemuck::Zeroable;
+use glam::*;
+use rayon::prelude::*;
+use std::f32::consts::PI;
+use thiserror::Error;
+
+
+
+/// Analytic test field defined over the [0, 1]^3 cube of its bounding box.
+/// Densities are given per unit of length in model space, as the renderers take them.
+#[derive(Clone, Debug, PartialEq, Copy)]
+pub enum Procedural {
+    /// Constant density and color filling the whole box
+    Cube { density: f32, color: Vec3 },
+    /// Gaussian density bump in the center with the standard deviation in box units
+    SoftSphere { density: f32, sigma: f32, color: Vec3 },
+    /// Every other block of a `blocks`^3 grid is filled and colored by its position
+    Checkerboard { density: f32, blocks: usize },
+    /// `count`^3 spheres of constant density on a lattice. Their degree 1 harmonics make
+    /// the red, green and blue channels grow along X, Y and Z components of the view direction.
+    ShSpheres { density: f32, count: usize, radius: f32 },
+    /// Horizontal slab of the thickness through the center, its density grows linearly along X
+    GradientSlab { density: f32, thickness: f32, color: Vec3 },
+    /// Smooth value noise of density and color with `frequency` features along every side
+    Noise { density: f32, frequency: usize, seed: u64 },
+}
+
+impl Procedural {
+    pub const fn sh_degree(&self) -> u32 {
+        match self {
+            Self::ShSpheres { .. } => 1,
+            _ => 0,
+        }
+    }
+
+    /// Value of the field at the position in the [0, 1]^3 cube
+    pub fn sample(&self, position: Vec3) -> Cell<4> {
+        let mut cell = Cell::<4>::zeroed();
+
+        let set_color = |cell: &mut Cell<4>, color: Vec3| {
+            cell.sh_r[0] = color.x / SH_C0;
+            cell.sh_g[0] = color.y / SH_C0;
+            cell.sh_b[0] = color.z / SH_C0;
+        };
+
+        match *self {
+            Self::Cube { density, color } => {
+                cell.density = density;
+                set_color(&mut cell, color);
+            },
+            Self::SoftSphere { density, sigma, color } => {
+                let distance_squared = position.distance_squared(Vec3::splat(0.5));
+
+                cell.density = density * f32::exp(-0.5 * distance_squared / (sigma * sigma));
+                set_color(&mut cell, color);
+            },
+            Self::Checkerboard { density, blocks } => {
+                let block = (position * blocks as f32).floor().clamp(Vec3::ZERO, Vec3::splat(blocks as f32 - 1.0));
+
+                if ((block.x + block.y + block.z) as u32).is_multiple_of(2) {
+                    cell.density = density;
+                    set_color(&mut cell, 0.2 + 0.8 * block / (blocks as f32 - 1.0).max(1.0));
+                }
+            },
+            Self::ShSpheres { density, count, radius } => {
+                let lattice = (position * count as f32).floor().clamp(Vec3::ZERO, Vec3::splat(count as f32 - 1.0));
+                let center = (lattice + 0.5) / count as f32;
+
+                if position.distance(center) <= radius {
+                    cell.density = density;
+                }
+
+                // gray with the view direction added to it
+                set_color(&mut cell, Vec3::splat(0.5));
+                cell.sh_r[3] = -0.4 / SH_C1;
+                cell.sh_g[1] = -0.4 / SH_C1;
+                cell.sh_b[2] = 0.4 / SH_C1;
+            },
+            Self::GradientSlab { density, thickness, color } => {
+                if (position.y - 0.5).abs() <= 0.5 * thickness {
+                    cell.density = density * position.x;
+                }
+
+                set_color(&mut cell, color);
+            },
+            Self::Noise { density, frequency, seed } => {
+                let noise = |seed: u64| value_noise(position * frequency as f32, seed);
+
+                cell.density = density * noise(seed);
+                set_color(&mut cell, vec3(noise(seed + 1), noise(seed + 2), noise(seed + 3)));
+            },
+        }
+
+        cell
+    }
+
+    /// Samples the field at the cell centers
+    pub fn generate(&self, size: [usize; 3]) -> RadianceField<4> {
+        let cells = (0..RadianceField::volume(size))
+            .into_par_iter()
+            .map(|i| {
+                let index = RadianceField::index_from_flat(size, i);
+                let position = (Vec3::from_array(index.map(|i| i as f32)) + 0.5) / RadianceField::size_vec(size);
+
+                self.sample(position)
+            })
+            .collect();
+
+        RadianceField { size, cells }
+    }
+
+    /// Whether [`Procedural::expected_color`] is known in closed form
+    pub const fn has_expected_render(&self) -> bool {
+        !matches!(self, Self::Noise { .. })
+    }
+
+    /// Color of the ray in model space through the exact field placed into the bounding box,
+    /// `None` if it has no closed form. Renders of generated fields approach it
+    /// as their resolution and the number of raymarching steps grow.
+    pub fn expected_color(&self, ray: &Ray, bounding_box: Aabb) -> Option<Vec3> {
+        if !self.has_expected_render() {
+            return None;
+        }
+
+        let Aabb { lo, hi } = bounding_box;
+
+        let Some((near, far)) = intersect_ray_box(ray.origin, ray.direction, lo, hi) else {
+            return Some(Vec3::ZERO);
+        };
+
+        let near = near.max(0.0);
+
+        if near >= far {
+            return Some(Vec3::ZERO);
+        }
+
+        // the ray in the [0, 1]^3 cube, its parameter is still the model space distance
+        let origin = (ray.origin - lo) / (hi - lo);
+        let velocity = ray.direction / (hi - lo);
+        let at = |t: f32| origin + t * velocity;
+
+        let color = |cell: Cell<4>| cell.eval(ray.direction).color.clamp(Vec3::ZERO, Vec3::ONE);
+        let opacity = |optical_depth: f32| 1.0 - f32::exp(-optical_depth);
+
+        Some(match *self {
+            Self::Cube { density, color } => color * opacity(density * (far - near)),
+            Self::SoftSphere { density, sigma, color } => {
+                let offset = origin - 0.5;
+                let (a, b, c) = (velocity.dot(velocity), offset.dot(velocity), offset.dot(offset));
+
+                // the squared distance to the center is a(t + b/a)^2 + c - b^2/a
+                let k = f32::sqrt(a / (2.0 * sigma * sigma));
+                let shift = b / a;
+
+                let integral = f32::exp(-(c - b * shift) / (2.0 * sigma * sigma))
+                    * 0.5 * PI.sqrt() / k
+                    * (erf(k * (far + shift)) - erf(k * (near + shift)));
+
+                color * opacity(density * integral)
+            },
+            Self::Checkerboard { blocks, .. } => {
+                // the density is constant between the block boundaries
+                let mut distances = vec![near, far];
+
+                for axis in 0..3 {
+                    if velocity[axis] == 0.0 {
+                        continue;
+                    }
+
+                    for i in 1..blocks {
+                        let t = (i as f32 / blocks as f32 - origin[axis]) / velocity[axis];
+
+                        if near < t && t < far {
+                            distances.push(t);
+                        }
+                    }
+                }
+
+                distances.sort_unstable_by(f32::total_cmp);
+
+                let mut transmittance = 1.0;
+                let mut result = Vec3::ZERO;
+
+                for segment in distances.windows(2) {
+                    let cell = self.sample(at(0.5 * (segment[0] + segment[1])));
+                    let segment_opacity = opacity(cell.density * (segment[1] - segment[0]));
+
+                    result += transmittance * segment_opacity * color(cell);
+                    transmittance *= 1.0 - segment_opacity;
+                }
+
+                result
+            },
+            Self::ShSpheres { density, count, radius } => {
+                let a = velocity.dot(velocity);
+                let mut chord = 0.0;
+
+                for i in 0..count * count * count {
+                    let lattice = vec3((i % count) as f32, (i / count % count) as f32, (i / (count * count)) as f32);
+                    let offset = origin - (lattice + 0.5) / count as f32;
+
+                    let (b, c) = (offset.dot(velocity), offset.dot(offset) - radius * radius);
+                    let discriminant = b * b - a * c;
+
+                    if discriminant > 0.0 {
+                        let root = discriminant.sqrt();
+                        let (enter, exit) = ((-b - root) / a, (-b + root) / a);
+
+                        chord += (exit.min(far) - enter.max(near)).max(0.0);
+                    }
+                }
+
+                // all of the spheres have the same color
+                color(self.sample(Vec3::splat(0.5))) * opacity(density * chord)
+            },
+            Self::GradientSlab { density, thickness, color } => {
+                let (bottom, top) = (0.5 - 0.5 * thickness, 0.5 + 0.5 * thickness);
+
+                let (enter, exit) = match velocity.y {
+                    0.0 if (bottom..=top).contains(&origin.y) => (near, far),
+                    0.0 => (far, far),
+                    y => {
+                        let (t0, t1) = ((bottom - origin.y) / y, (top - origin.y) / y);
+                        (t0.min(t1).max(near), t0.max(t1).min(far))
+                    },
+                };
+
+                let length = (exit - enter).max(0.0);
+                let mean_x = 0.5 * (at(enter).x + at(exit).x);
+
+                color * opacity(density * mean_x * length)
+            },
+            Self::Noise { .. } => unreachable!("noise has no closed form"),
+        })
+    }
+
+    /// Image of the expected colors seen by the camera of the configuration, laid out
+    /// as the CPU renderers output it, or `None` if there is no closed form
+    pub fn expected_image(
+        &self, screen_width: usize, screen_height: usize, cfg: &RenderConfiguration,
+    ) -> Option<Vec<u8>> {
+        if !self.has_expected_render() {
+            return None;
+        }
+
+        let aspect_ratio = screen_height as f32 / screen_width as f32;
+
+        Some(render_cpu::render_parallel(screen_width, screen_height, &mut Bench::new(), |coord| {
+            let ray = cfg.transform.ray_to_model(&cfg.camera.shoot_ray(coord, aspect_ratio));
+            self.expected_color(&ray, cfg.bounding_box).unwrap_or_default()
+        }))
+    }
+}
+
+
+
+/// Kind of a procedural field selected in the command line
+#[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
+pub enum ProceduralKind {
+    #[default]
+    Cube,
+    Sphere,
+    Checkerboard,
+    ShSpheres,
+    Slab,
+    Noise,
+}
+
+impl ProceduralKind {
+    /// Standard deviation of the soft sphere or radius of the view-dependent spheres
+    /// used when it isn't given, small enough for the spheres not to overlap at the default count
+    pub const fn default_radius(&self) -> f32 {
+        match self {
+            Self::ShSpheres => 0.1,
+            _ => 0.15,
+        }
+    }
+}
+
+impl std::str::FromStr for ProceduralKind {
+    type Err = ProceduralKindParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "cube" => Self::Cube,
+            "sphere" => Self::Sphere,
+            "checkerboard" => Self::Checkerboard,
+            "sh-spheres" => Self::ShSpheres,
+            "slab" => Self::Slab,
+            "noise" => Self::Noise,
+            _ => return Err(ProceduralKindParseError(s.to_owned())),
+        })
+    }
+}
+
+impl std::fmt::Display for ProceduralKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Cube => "cube",
+            Self::Sphere => "sphere",
+            Self::Checkerboard => "checkerboard",
+            Self::ShSpheres => "sh-spheres",
+            Self::Slab => "slab",
+            Self::Noise => "noise",
+        })
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid procedural field '{0}', valid values are: \
+         'cube', 'sphere', 'checkerboard', 'sh-spheres', 'slab' and 'noise'")]
+pub struct ProceduralKindParseError(pub String);
+
+
+
+/// Smoothly interpolated random values in the [0, 1] range at the integer points
+fn value_noise(position: Vec3, seed: u64) -> f32 {
+    let lattice = position.floor();
+    let t = position - lattice;
+    let t = t * t * (3.0 - 2.0 * t);
+
+    let corner = |offset: IVec3| {
+        let [x, y, z] = (lattice.as_ivec3() + offset).to_array().map(|i| i as u32 as u64);
+        hash(seed ^ hash(x ^ hash(y ^ hash(z)))) as f32 / u64::MAX as f32
+    };
+
+    let lerp_x = |y, z| f32::lerp(corner(ivec3(0, y, z)), corner(ivec3(1, y, z)), t.x);
+    let lerp_y = |z| f32::lerp(lerp_x(0, z), lerp_x(1, z), t.y);
+
+    f32::lerp(lerp_y(0), lerp_y(1), t.z)
+}
+
+/// SplitMix64 finalizer
+fn hash(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Error function with the absolute error below 1.5e-7, Abramowitz and Stegun 7.1.26
+fn erf(x: f32) -> f32 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t * (0.2548296 + t * (-0.28449672 + t * (1.4214138 + t * (-1.4531521 + t * 1.0614054))));
+
+    (1.0 - polynomial * f32::exp(-x * x)).copysign(x)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spherical::Filtering;
+
+    const SCREEN_SIZE: usize = 64;
+
+    /// Mean absolute difference in 8-bit levels between the color channels of the
+    /// CPU render of the generated field and its closed form render
+    fn render_error(procedural: Procedural, filtering: Filtering) -> f32 {
+        let cfg = RenderConfiguration { filtering, ..Default::default() };
+        let field = procedural.generate([64; 3]);
+
+        let render = render_cpu::render_multicpu(SCREEN_SIZE, SCREEN_SIZE, &field, &cfg, &mut Bench::new());
+        let expected = procedural.expected_image(SCREEN_SIZE, SCREEN_SIZE, &cfg).unwrap();
+
+        let sum = render.chunks(4).zip(expected.chunks(4))
+            .flat_map(|(lhs, rhs)| (0..3).map(|i| lhs[i].abs_diff(rhs[i]) as f32))
+            .sum::<f32>();
+
+        sum / (3 * SCREEN_SIZE * SCREEN_SIZE) as f32
+    }
+
+    #[test]
+    fn cube_matches_expected_render() {
+        let error = render_error(Procedural::Cube { density: 20.0, color: Vec3::ONE }, Filtering::Trilinear);
+        assert!(error < 1.0, "mean error {error}");
+    }
+
+    #[test]
+    fn sphere_matches_expected_render() {
+        let procedural = Procedural::SoftSphere { density: 20.0, sigma: 0.15, color: Vec3::ONE };
+        let error = render_error(procedural, Filtering::Trilinear);
+        assert!(error < 0.25, "mean error {error}");
+    }
+
+    #[test]
+    fn checkerboard_matches_expected_render() {
+        // trilinear filtering blurs the block boundaries, which the closed form keeps sharp
+        let error = render_error(Procedural::Checkerboard { density: 20.0, blocks: 4 }, Filtering::Nearest);
+        assert!(error < 0.5, "mean error {error}");
+    }
+
+    #[test]
+    fn sh_spheres_match_expected_render() {
+        // the silhouettes of the small spheres are only a few cells across
+        let radius = ProceduralKind::ShSpheres.default_radius();
+        let error = render_error(Procedural::ShSpheres { density: 20.0, count: 4, radius }, Filtering::Trilinear);
+        assert!(error < 3.0, "mean error {error}");
+    }
+
+    #[test]
+    fn slab_matches_expected_render() {
+        let procedural = Procedural::GradientSlab { density: 20.0, thickness: 0.5, color: Vec3::ONE };
+        let error = render_error(procedural, Filtering::Trilinear);
+        assert!(error < 1.5, "mean error {error}");
+    }
+}
@@ -1,29 +1,49 @@
 use crate::{
-    benchmark::Bench, geometry::Intersect as _, graphics::{
-        Color, RaymarchSettings, RenderConfiguration, RENDER_TARGET_COLOR, RENDER_TARGET_DENSITY
-    }, spherical::{CellValue, Filtering, RadianceField}
+    benchmark::Bench, geometry::{self, Aabb, Ray, Transform}, graphics::{
+        Color, DirectionalLight, Integration, Light, RaymarchSettings, RenderConfiguration, Scattering,
+        RENDER_TARGET_COLOR, RENDER_TARGET_DENSITY,
+    }, spherical::{BorderMode, CellValue, Filtering, Grid}
 };
 use glam::*;
 use rayon::prelude::*;
 
 
 
-pub fn raymarch(
-    ro: Vec3, rd: Vec3, near: f32, far: f32,
-    mut get_info: impl FnMut(Vec3, Vec3) -> CellValue,
-    settings: RaymarchSettings,
-) -> Vec3 {
-    let step_size = (far - near) / settings.n_steps as f32;
+/// Distances of the samples along every interval of a ray and the lengths of the steps between them.
+/// Steps are distributed proportionally to the lengths of the intervals.
+fn interval_samples(
+    intervals: &[(f32, f32)], settings: RaymarchSettings,
+) -> impl Iterator<Item = impl Iterator<Item = (f32, f32)>> + '_ {
+    let length = intervals.iter().map(|(near, far)| far - near).sum::<f32>();
+
+    intervals.iter().map(move |&(near, far)| {
+        let n_steps = ((settings.n_steps as f32 * (far - near) / length).ceil() as u32).max(2);
+        let step_size = (far - near) / n_steps as f32;
+
+        (0..n_steps).map(move |i| (near.lerp(far, i as f32 / (n_steps - 1) as f32), step_size))
+    })
+}
 
-    let positions = (0..settings.n_steps).map(|i| ro + rd * near.lerp(
-        far, i as f32 / (settings.n_steps - 1) as f32,
-    ));
+/// Samples of all of the intervals, see [`interval_samples`]
+fn sample_distances(
+    intervals: &[(f32, f32)], settings: RaymarchSettings,
+) -> impl Iterator<Item = (f32, f32)> + '_ {
+    interval_samples(intervals, settings).flatten()
+}
 
+/// Accumulates color along the intervals of a ray, `get_info` is called with distances along it.
+/// Transmittance is shared by all of the intervals, so the farther ones are occluded by the nearer.
+/// Returns the color and the transmittance of the intervals.
+pub fn raymarch(
+    intervals: &[(f32, f32)],
+    mut get_info: impl FnMut(f32) -> CellValue,
+    settings: RaymarchSettings,
+) -> Vec4 {
     let mut color = Vec3::ZERO;
     let mut density_sum = 0.0;
 
-    for pos in positions {
-        let cell = get_info(pos, rd);
+    for (distance, step_size) in sample_distances(intervals, settings) {
+        let cell = get_info(distance);
 
         color += cell.color
             * f32::exp(-density_sum)
@@ -32,44 +52,406 @@ pub fn raymarch(
         density_sum += step_size * cell.density;
     }
 
-    color
+    color.extend(f32::exp(-density_sum))
+}
+
+/// Reduces the density along the intervals of a ray to a single value by the projection,
+/// `get_density` is called with distances along it and returns `None` outside of the field.
+/// Returns `None` if there are no samples.
+pub fn project(
+    intervals: &[(f32, f32)],
+    mut get_density: impl FnMut(f32) -> Option<f32>,
+    integration: Integration,
+    settings: RaymarchSettings,
+) -> Option<f32> {
+    let samples = sample_distances(intervals, settings)
+        .filter_map(|(distance, step_size)| Some((get_density(distance)?, step_size)));
+
+    match integration {
+        Integration::Maximum => samples.map(|(density, _)| density).reduce(f32::max),
+        Integration::Minimum => samples.map(|(density, _)| density).reduce(f32::min),
+        Integration::Average => {
+            let (sum, length) = samples.fold((0.0, 0.0), |(sum, length), (density, step_size)| {
+                (sum + density * step_size, length + step_size)
+            });
+
+            (length > 0.0).then(|| sum / length)
+        },
+        Integration::Composite | Integration::Isosurface | Integration::Scattering => {
+            panic!("Integration '{integration}' is not a projection")
+        },
+    }
+}
+
+/// Distance of the first point where the density along the intervals of a ray reaches the level,
+/// refined by bisection between the samples around it. `get_density` is called with distances
+/// along the ray and returns `None` outside of the field. A sample above the level right at
+/// the start of an interval is a hit on the boundary of the field or on a cut surface.
+pub fn find_crossing(
+    intervals: &[(f32, f32)],
+    mut get_density: impl FnMut(f32) -> Option<f32>,
+    level: f32,
+    refinement_steps: u32,
+    settings: RaymarchSettings,
+) -> Option<f32> {
+    for samples in interval_samples(intervals, settings) {
+        // the last sample below the level
+        let mut below = None;
+
+        for (distance, _) in samples {
+            match get_density(distance) {
+                None => below = None,
+                Some(density) if density < level => below = Some(distance),
+                Some(_) => {
+                    let Some(mut lo) = below else {
+                        return Some(distance);
+                    };
+
+                    let mut hi = distance;
+
+                    for _ in 0..refinement_steps {
+                        let mid = 0.5 * (lo + hi);
+
+                        match get_density(mid) {
+                            Some(density) if density >= level => hi = mid,
+                            _ => lo = mid,
+                        }
+                    }
+
+                    return Some(hi);
+                },
+            }
+        }
+    }
+
+    None
+}
+
+
+
+/// Field placed into the scene. `eval` samples it by a position in the [0, 1]^3 cube
+/// of the bounding box and a direction, both in model space, into the value seen from
+/// the direction and the diffuse color of the sample, and `gradient` computes the gradient
+/// of the density by the position in the cube.
+pub struct SceneVolume<F, G> {
+    pub bounding_box: Aabb,
+    pub transform: Transform,
+    pub eval: F,
+    pub gradient: G,
 }
 
+/// Volume of a field of any spherical harmonic degree
+pub type DynSceneVolume<'f> = SceneVolume<
+    Box<dyn Fn(Vec3, Vec3) -> Option<(CellValue, Vec3)> + Sync + 'f>,
+    Box<dyn Fn(Vec3) -> Option<Vec3> + Sync + 'f>,
+>;
 
+impl<'f> DynSceneVolume<'f> {
+    pub fn new<const W: usize>(
+        field: &'f (impl Grid<W> + Sync), bounding_box: Aabb, transform: Transform,
+        filtering: Filtering, border: BorderMode,
+    ) -> Self {
+        Self {
+            bounding_box,
+            transform,
+            eval: Box::new(move |pos, direction| sample(field, pos, direction, filtering, border)),
+            gradient: Box::new(move |pos| field.density_gradient(pos, filtering, border)),
+        }
+    }
+}
+
+
+
+/// Value of the filtered cell seen from the direction and its diffuse color
+fn sample<const W: usize>(
+    field: &impl Grid<W>, pos: Vec3, direction: Vec3, filtering: Filtering, border: BorderMode,
+) -> Option<(CellValue, Vec3)> {
+    let cell = field.sample(pos, filtering, border)?;
+    Some((cell.eval(direction), cell.diffuse()))
+}
 
-pub fn get_color(
+pub fn get_color<const W: usize>(
     screen_coord: Vec2, screen_width: usize, screen_height: usize,
-    field: &RadianceField, cfg: &RenderConfiguration,
+    field: &impl Grid<W>, cfg: &RenderConfiguration,
 ) -> Vec3 {
-    let aspect_ratio = screen_height as f32 / screen_width as f32;
+    let volume = SceneVolume {
+        bounding_box: cfg.bounding_box,
+        transform: cfg.transform,
+        eval: |pos, direction| sample(field, pos, direction, cfg.filtering, cfg.border),
+        gradient: |pos| field.density_gradient(pos, cfg.filtering, cfg.border),
+    };
+
+    get_scene_color(screen_coord, screen_width, screen_height, &[volume], cfg)
+}
 
+/// Composites the volumes along the camera ray. Samples of overlapping volumes
+/// are merged: densities add up and colors are weighted by them. For the density render
+/// target the samples are mapped by the transfer function before merging. Projections
+/// reduce the summed density of the volumes and color the result by the transfer function,
+/// isosurfaces are found on the summed density as well. All of the modes take the density
+/// per world space unit.
+pub fn get_scene_color<F, G>(
+    screen_coord: Vec2, screen_width: usize, screen_height: usize,
+    volumes: &[SceneVolume<F, G>], cfg: &RenderConfiguration,
+) -> Vec3
+where
+    F: Fn(Vec3, Vec3) -> Option<(CellValue, Vec3)>,
+    G: Fn(Vec3) -> Option<Vec3>,
+{
+    let aspect_ratio = screen_height as f32 / screen_width as f32;
     let ray = cfg.camera.shoot_ray(screen_coord, aspect_ratio);
 
-    let Some((near, far)) = cfg.bounding_box.intersect(&ray) else {
+    let hits = geometry::intersect_ray_volumes(
+        &ray, volumes.iter().map(|volume| (volume.bounding_box, volume.transform)),
+    );
+
+    if hits.is_empty() {
         return Vec3::ZERO;
+    }
+
+    // spherical harmonics are evaluated in model space as well
+    let model_rays = hits.iter()
+        .map(|hit| {
+            let volume = &volumes[hit.index];
+            (volume, volume.transform.ray_to_model(&ray))
+        })
+        .collect::<Vec<_>>();
+
+    let intervals = geometry::clip_intervals(
+        &ray,
+        geometry::merge_intervals(hits.iter().map(|hit| (hit.near, hit.far))),
+        &cfg.clipping.primitives,
+    );
+
+    // positions of the point at the distance in the [0, 1]^3 cubes of the volumes
+    let positions = |distance: f32| model_rays.iter().map(move |&(volume, model_ray)| {
+        let Aabb { lo: box_lo, hi: box_hi } = volume.bounding_box;
+
+        let model_pos = model_ray.origin + model_ray.direction * (distance / volume.transform.scale);
+
+        (volume, (model_pos - box_lo) / (box_hi - box_lo), model_ray.direction)
+    });
+
+    // density per world space unit at the distance, the one every integration mode works with
+    let density_fn = |distance| positions(distance)
+        .filter_map(|(volume, pos, direction)| {
+            Some((volume.eval)(pos, direction)?.0.density.max(0.0) / volume.transform.scale)
+        })
+        .reduce(|a, b| a + b);
+
+    // projections and isosurfaces see through the cut surfaces
+    let all_intervals = intervals.iter()
+        .map(|interval| (interval.near, interval.far))
+        .collect::<Vec<_>>();
+
+    match cfg.integration {
+        Integration::Composite | Integration::Scattering => {},
+        Integration::Isosurface => {
+            let iso = &cfg.isosurface;
+
+            let Some(distance) = find_crossing(
+                &all_intervals, density_fn, iso.level, iso.refinement_steps, cfg.rm_settings,
+            ) else {
+                return Vec3::ZERO;
+            };
+
+            let (mut density, mut color, mut gradient) = (0.0, Vec3::ZERO, Vec3::ZERO);
+
+            for (volume, pos, direction) in positions(distance) {
+                if let Some((value, _)) = (volume.eval)(pos, direction) {
+                    let value_density = value.density.max(0.0) / volume.transform.scale;
+
+                    density += value_density;
+                    color += value_density * value.color.clamp(Vec3::ZERO, Vec3::ONE);
+                }
+
+                // the gradient by the position in the cube to the one by the world position
+                if let Some(volume_gradient) = (volume.gradient)(pos) {
+                    let Aabb { lo: box_lo, hi: box_hi } = volume.bounding_box;
+                    let model_gradient = volume_gradient / (box_hi - box_lo);
+
+                    gradient += volume.transform.rotation_quat() * model_gradient / volume.transform.scale;
+                }
+            }
+
+            let albedo = if density > 0.0 { color / density } else { Vec3::ZERO };
+
+            // the density grows inside of the surface, flat fields face the camera
+            let normal = match -gradient.normalize_or_zero() {
+                Vec3::ZERO => -ray.direction,
+                normal if normal.dot(ray.direction) > 0.0 => -normal,
+                normal => normal,
+            };
+
+            return iso.shade(albedo, normal, ray.direction);
+        },
+        _ => {
+            return project(&all_intervals, density_fn, cfg.integration, cfg.rm_settings)
+                .map_or(Vec3::ZERO, |density| cfg.transfer.eval(density).0);
+        },
+    }
+
+    // density per world space unit at the point
+    let world_density = |point: Vec3| volumes.iter()
+        .filter_map(|volume| {
+            let Aabb { lo: box_lo, hi: box_hi } = volume.bounding_box;
+            let pos = (volume.transform.to_model(point) - box_lo) / (box_hi - box_lo);
+
+            Some((volume.eval)(pos, Vec3::Z)?.0.density.max(0.0) / volume.transform.scale)
+        })
+        .sum::<f32>();
+
+    // fraction of the light reaching the point from the direction, the cut away volume casts no shadows
+    let transmittance = |point: Vec3, to_light: Vec3, max_distance: f32| -> f32 {
+        let shadow_ray = Ray { origin: point, direction: to_light };
+
+        let hits = geometry::intersect_ray_volumes(
+            &shadow_ray, volumes.iter().map(|volume| (volume.bounding_box, volume.transform)),
+        );
+
+        let intervals = geometry::clip_intervals(
+            &shadow_ray,
+            geometry::merge_intervals(hits.iter().map(|hit| (hit.near, hit.far.min(max_distance)))),
+            &cfg.clipping.primitives,
+        );
+
+        let intervals = intervals.iter()
+            .filter(|interval| interval.near < interval.far)
+            .map(|interval| (interval.near, interval.far))
+            .collect::<Vec<_>>();
+
+        let settings = RaymarchSettings { n_steps: cfg.scattering.shadow_steps };
+
+        let optical_depth = sample_distances(&intervals, settings)
+            .map(|(distance, step_size)| step_size * world_density(shadow_ray.origin + to_light * distance))
+            .sum::<f32>();
+
+        f32::exp(-optical_depth)
+    };
+
+    let headlight = [Light::Directional(DirectionalLight {
+        direction: -ray.direction,
+        color: Scattering::HEADLIGHT_COLOR,
+    })];
+
+    let lights = match cfg.scattering.lights.is_empty() {
+        true => &headlight[..],
+        false => &cfg.scattering.lights[..],
     };
 
-    let color_fn = |ro: Vec3, rd: Vec3| -> CellValue {
-        let mut value = field.eval(ro + 0.5, rd, Filtering::Trilinear).unwrap_or_default();
+    let color_fn = |distance: f32| -> CellValue {
+        let mut density = 0.0;
+        let mut color = Vec3::ZERO;
+        let mut albedo = Vec3::ZERO;
+
+        for (volume, pos, direction) in positions(distance) {
+            let Some((mut value, diffuse)) = (volume.eval)(pos, direction) else {
+                continue;
+            };
+
+            if cfg.render_target == RENDER_TARGET_DENSITY {
+                (value.color, value.density) = cfg.transfer.eval(value.density.max(0.0));
+            }
+
+            // density is given per model space unit
+            let value_density = value.density.max(0.0) / volume.transform.scale;
+
+            density += value_density;
+            color += value_density * value.color.clamp(Vec3::ZERO, Vec3::ONE);
+
+            if cfg.integration == Integration::Scattering {
+                albedo += value_density * diffuse.clamp(Vec3::ZERO, Vec3::ONE);
+            }
+        }
+
+        if cfg.integration == Integration::Scattering && density > 0.0 {
+            let point = ray.origin + ray.direction * distance;
+
+            let in_scattered = lights.iter()
+                .map(|light| {
+                    let (to_light, light_distance, light_color) = light.illuminate(point);
 
-        value.density = value.density.max(0.0);
-        value.color = match cfg.render_target {
-            RENDER_TARGET_COLOR
-                => value.color.clamp(Vec3::ZERO, Vec3::ONE),
-            RENDER_TARGET_DENSITY
-                => Vec3::splat(value.density),
+                    light_color
+                        * transmittance(point, to_light, light_distance)
+                        * cfg.scattering.phase(to_light.dot(ray.direction))
+                })
+                .sum::<Vec3>();
+
+            color = albedo * in_scattered + cfg.scattering.emission * color;
+        }
+
+        let color = match cfg.render_target {
+            RENDER_TARGET_COLOR | RENDER_TARGET_DENSITY if density > 0.0 => color / density,
+            RENDER_TARGET_COLOR | RENDER_TARGET_DENSITY => Vec3::ZERO,
             _ => panic!("Invalid render target '{}'", cfg.render_target),
         };
 
-        value
+        CellValue::new(color, density)
     };
 
-    raymarch(ray.origin, ray.direction, near.max(0.0), far, color_fn, cfg.rm_settings)
+    // the first cut through nonempty volume is opaque and hides the rest of the ray
+    let cap = cfg.clipping.cap_color.and_then(|cap_color| {
+        let index = intervals.iter().position(|interval| {
+            interval.cut && color_fn(interval.near).density > cfg.clipping.cap_density
+        })?;
+
+        Some((index, cap_color))
+    });
+
+    let n_visible = cap.map_or(intervals.len(), |(index, _)| index);
+
+    let visible = intervals[..n_visible].iter()
+        .map(|interval| (interval.near, interval.far))
+        .collect::<Vec<_>>();
+
+    let result = raymarch(&visible, color_fn, cfg.rm_settings);
+
+    match cap {
+        Some((_, cap_color)) => result.xyz() + result.w * cap_color,
+        None => result.xyz(),
+    }
+}
+
+pub fn render_multicpu<const W: usize>(
+    screen_width: usize, screen_height: usize,
+    field: &(impl Grid<W> + Sync), cfg: &RenderConfiguration, bench: &mut Bench,
+) -> Vec<u8> {
+    render_parallel(screen_width, screen_height, bench, |coord| get_color(
+        coord, screen_width, screen_height, field, cfg,
+    ))
+}
+
+pub fn render_singlecpu<const W: usize>(
+    screen_width: usize, screen_height: usize,
+    field: &(impl Grid<W> + Sync), cfg: &RenderConfiguration, bench: &mut Bench,
+) -> Vec<u8> {
+    render_sequential(screen_width, screen_height, bench, |coord| get_color(
+        coord, screen_width, screen_height, field, cfg,
+    ))
+}
+
+pub fn render_scene_multicpu(
+    screen_width: usize, screen_height: usize,
+    volumes: &[DynSceneVolume<'_>], cfg: &RenderConfiguration, bench: &mut Bench,
+) -> Vec<u8> {
+    render_parallel(screen_width, screen_height, bench, |coord| get_scene_color(
+        coord, screen_width, screen_height, volumes, cfg,
+    ))
 }
 
-pub fn render_multicpu(
+pub fn render_scene_singlecpu(
     screen_width: usize, screen_height: usize,
-    field: &RadianceField, cfg: &RenderConfiguration, bench: &mut Bench,
+    volumes: &[DynSceneVolume<'_>], cfg: &RenderConfiguration, bench: &mut Bench,
+) -> Vec<u8> {
+    render_sequential(screen_width, screen_height, bench, |coord| get_scene_color(
+        coord, screen_width, screen_height, volumes, cfg,
+    ))
+}
+
+/// Colors every pixel of the screen in parallel into RGBA bytes
+pub fn render_parallel(
+    screen_width: usize, screen_height: usize, bench: &mut Bench,
+    get_color: impl Fn(Vec2) -> Vec3 + Sync,
 ) -> Vec<u8> {
     let mut image = Vec::with_capacity(screen_width * screen_height);
 
@@ -81,9 +463,7 @@ pub fn render_multicpu(
             ((2 * x) as f32 + 0.5) / (screen_width  - 1) as f32 - 1.0,
             ((2 * y) as f32 + 0.5) / (screen_height - 1) as f32 - 1.0,
         ))
-        .map(|coord| get_color(
-            coord, screen_width, screen_height, field, cfg,
-        ).extend(1.0))
+        .map(|coord| get_color(coord).extend(1.0))
         .map(Color::from_vec4)
         .collect_into_vec(&mut image);
     
@@ -94,9 +474,9 @@ pub fn render_multicpu(
     bytemuck::allocation::cast_vec(image)
 }
 
-pub fn render_singlecpu(
-    screen_width: usize, screen_height: usize,
-    field: &RadianceField, cfg: &RenderConfiguration, bench: &mut Bench,
+fn render_sequential(
+    screen_width: usize, screen_height: usize, bench: &mut Bench,
+    get_color: impl Fn(Vec2) -> Vec3,
 ) -> Vec<u8> {
     bench.render.start();
 
@@ -106,9 +486,7 @@ pub fn render_singlecpu(
             ((2 * x) as f32 + 0.5) / (screen_width  - 1) as f32 - 1.0,
             ((2 * y) as f32 + 0.5) / (screen_height - 1) as f32 - 1.0,
         ))
-        .map(|coord| get_color(
-            coord, screen_width, screen_height, field, cfg,
-        ).extend(1.0))
+        .map(|coord| get_color(coord).extend(1.0))
         .map(Color::from_vec4)
         .collect();
 
@@ -117,4 +495,267 @@ pub fn render_singlecpu(
     bench.render.end();
 
     bytemuck::allocation::cast_vec(image)
-}
\ No newline at end of file
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        geometry::{Intersect, Sphere},
+        graphics::{Isosurface, PointLight, Shading},
+        procedural::Procedural,
+        transfer::TransferFunction,
+    };
+
+    /// Points on the screen around the center, where the default camera sees the default box
+    fn screen_coords() -> impl Iterator<Item = Vec2> {
+        (0..25).map(|i| vec2((i % 5) as f32 - 2.0, (i / 5) as f32 - 2.0) * 0.15)
+    }
+
+    fn volume<'f, const W: usize>(
+        field: &'f (impl Grid<W> + Sync), transform: Transform, cfg: &RenderConfiguration,
+    ) -> DynSceneVolume<'f> {
+        SceneVolume::new(field, cfg.bounding_box, transform, cfg.filtering, cfg.border)
+    }
+
+    #[test]
+    fn scene_of_one_field_renders_like_the_field() {
+        let cfg = RenderConfiguration::default();
+        let field = Procedural::Noise { density: 10.0, frequency: 3, seed: 7 }.generate([16; 3]);
+        let volumes = [volume(&field, Transform::IDENTITY, &cfg)];
+
+        for coord in screen_coords() {
+            assert_eq!(get_scene_color(coord, 1, 1, &volumes, &cfg), get_color(coord, 1, 1, &field, &cfg));
+        }
+    }
+
+    #[test]
+    fn overlapping_fields_add_densities_and_blend_colors() {
+        let cfg = RenderConfiguration::default();
+
+        let red = Procedural::Cube { density: 2.0, color: Vec3::X }.generate([4; 3]);
+        let blue = Procedural::Cube { density: 2.0, color: Vec3::Z }.generate([4; 3]);
+        let purple = Procedural::Cube { density: 4.0, color: vec3(0.5, 0.0, 0.5) }.generate([4; 3]);
+
+        let volumes = [volume(&red, Transform::IDENTITY, &cfg), volume(&blue, Transform::IDENTITY, &cfg)];
+
+        for coord in screen_coords() {
+            let color = get_scene_color(coord, 1, 1, &volumes, &cfg);
+            let expected = get_color(coord, 1, 1, &purple, &cfg);
+
+            assert!(color.abs_diff_eq(expected, 1e-5), "{color} != {expected} at {coord}");
+        }
+    }
+
+    #[test]
+    fn nearer_field_hides_the_farther_one_in_any_order() {
+        // the default camera looks along -X at the origin
+        let cfg = RenderConfiguration::default();
+
+        let red = Procedural::Cube { density: 100.0, color: Vec3::X }.generate([4; 3]);
+        let blue = Procedural::Cube { density: 100.0, color: Vec3::Z }.generate([4; 3]);
+
+        let near = Transform { translation: vec3(0.5, 0.0, 0.0), scale: 0.4, ..Transform::IDENTITY };
+        let far = Transform { translation: vec3(-0.5, 0.0, 0.0), scale: 0.4, ..Transform::IDENTITY };
+
+        let scenes = [
+            [volume(&red, near, &cfg), volume(&blue, far, &cfg)],
+            [volume(&blue, far, &cfg), volume(&red, near, &cfg)],
+        ];
+
+        for volumes in &scenes {
+            let color = get_scene_color(Vec2::ZERO, 1, 1, volumes, &cfg);
+            assert!(color.abs_diff_eq(Vec3::X, 1e-3), "{color}");
+        }
+
+        // the far field alone is seen past the near one
+        let color = get_scene_color(Vec2::ZERO, 1, 1, &scenes[0][1..], &cfg);
+        assert!(color.abs_diff_eq(Vec3::Z, 1e-3), "{color}");
+    }
+
+    #[test]
+    fn projections_reduce_the_samples_of_all_intervals() {
+        let intervals = [(0.0, 1.0), (2.0, 4.0)];
+        let settings = RaymarchSettings { n_steps: 30 };
+
+        let density = |distance: f32| Some(distance);
+        let reduce = |integration| project(&intervals, density, integration, settings).unwrap();
+
+        assert_eq!(reduce(Integration::Maximum), 4.0);
+        assert_eq!(reduce(Integration::Minimum), 0.0);
+
+        // weighted by the lengths of the intervals
+        let average = reduce(Integration::Average);
+        assert!((average - (0.5 * 1.0 + 3.0 * 2.0) / 3.0).abs() < 1e-4, "{average}");
+
+        // samples outside of the field are skipped
+        let outside = |distance: f32| (distance < 3.0).then_some(distance);
+        assert!(project(&intervals, outside, Integration::Maximum, settings).unwrap() < 3.0);
+        assert_eq!(project(&intervals, |_| None, Integration::Average, settings), None);
+    }
+
+    #[test]
+    fn projections_of_a_slab_are_colored_by_the_transfer_function() {
+        // the density grows along X from the far side of the box to the camera
+        let field = Procedural::GradientSlab { density: 20.0, thickness: 0.5, color: Vec3::ONE }.generate([64; 3]);
+
+        let transfer = TransferFunction { range: Some([0.0, 20.0]), ..Default::default() };
+        let expected = [
+            (Integration::Maximum, 63.5 / 64.0),
+            (Integration::Minimum, 0.5 / 64.0),
+            (Integration::Average, 0.5),
+        ];
+
+        for (integration, expected) in expected {
+            let cfg = RenderConfiguration { integration, transfer: transfer.clone(), ..Default::default() };
+            let color = get_color(Vec2::ZERO, 1, 1, &field, &cfg);
+
+            assert!(color.abs_diff_eq(Vec3::splat(expected), 1e-3), "{integration}: {color}");
+
+            // rays missing the slab see no density
+            assert!(get_color(vec2(0.0, 0.9), 1, 1, &field, &cfg).abs_diff_eq(Vec3::ZERO, 1e-6));
+        }
+    }
+
+    #[test]
+    fn crossings_are_refined_between_the_samples() {
+        let intervals = [(0.0, 10.0)];
+        let settings = RaymarchSettings { n_steps: 10 };
+
+        let density = |distance: f32| Some(distance);
+
+        let crossing = find_crossing(&intervals, density, 3.3, 20, settings).unwrap();
+        assert!((crossing - 3.3).abs() < 1e-4, "{crossing}");
+
+        // without refinement the sample after the crossing is taken
+        let crossing = find_crossing(&intervals, density, 3.3, 0, settings).unwrap();
+        assert!((crossing - 30.0 / 9.0).abs() < 1e-5, "{crossing}");
+
+        // the field is entered above the level
+        assert_eq!(find_crossing(&[(2.0, 10.0)], density, 1.0, 20, settings), Some(2.0));
+        assert_eq!(find_crossing(&intervals, density, 11.0, 20, settings), None);
+
+        // a crossing from outside of the field is a hit on its boundary
+        let entered = |distance: f32| (distance > 5.0).then_some(distance);
+        let crossing = find_crossing(&intervals, entered, 3.3, 20, settings).unwrap();
+        assert!((crossing - 50.0 / 9.0).abs() < 1e-5, "{crossing}");
+    }
+
+    #[test]
+    fn isosurface_of_a_sphere_is_lit_by_its_normal() {
+        let color = vec3(0.5, 0.25, 1.0);
+        let field = Procedural::SoftSphere { density: 20.0, sigma: 0.15, color }.generate([32; 3]);
+
+        let level = 5.0;
+        let radius = 0.15 * f32::sqrt(2.0 * f32::ln(20.0 / level));
+
+        let isosurface = Isosurface { level, refinement_steps: 16, ..Default::default() };
+        let cfg = RenderConfiguration { integration: Integration::Isosurface, isosurface, ..Default::default() };
+
+        let sphere = Sphere { center: Vec3::ZERO, radius };
+
+        // the light from the camera falls off with the angle to the normal
+        for coord in screen_coords() {
+            let ray = cfg.camera.shoot_ray(coord, 1.0);
+            let actual = get_color(coord, 1, 1, &field, &cfg);
+
+            let expected = match sphere.intersect(&ray) {
+                Some((near, _)) => {
+                    let normal = (ray.origin + ray.direction * near).normalize();
+                    color * (cfg.isosurface.ambient + normal.dot(-ray.direction))
+                },
+                None => Vec3::ZERO,
+            };
+
+            assert!(actual.abs_diff_eq(expected, 3e-2), "{actual} != {expected} at {coord}");
+        }
+
+        // the camera looks at the side of the sphere facing +X
+        let light = DirectionalLight { direction: Vec3::Y, color: Vec3::ONE };
+        let from_above = Isosurface { lights: vec![light], ..cfg.isosurface.clone() };
+        let cfg_above = RenderConfiguration { isosurface: from_above, ..cfg.clone() };
+
+        let actual = get_color(Vec2::ZERO, 1, 1, &field, &cfg_above);
+        assert!(actual.abs_diff_eq(0.1 * color, 1e-2), "{actual}");
+
+        // the highlight of the light from the camera is in the center
+        let shiny = Isosurface { shading: Shading::BlinnPhong, ..cfg.isosurface.clone() };
+        let cfg_shiny = RenderConfiguration { isosurface: shiny, ..cfg.clone() };
+
+        let actual = get_color(Vec2::ZERO, 1, 1, &field, &cfg_shiny);
+        assert!(actual.abs_diff_eq(1.1 * color + 0.5, 1e-2), "{actual}");
+    }
+
+    #[test]
+    fn phase_function_is_normalized_and_peaks_forward() {
+        let integral = |scattering: &Scattering| {
+            let n = 10000;
+
+            // over the sphere of directions, by the cosine of the angle
+            (0..n).map(|i| -1.0 + (2 * i + 1) as f32 / n as f32)
+                .map(|cos_theta| 2.0 * std::f32::consts::PI * scattering.phase(cos_theta) * 2.0 / n as f32)
+                .sum::<f32>()
+        };
+
+        for anisotropy in [-0.5, 0.0, 0.3, 0.8] {
+            let scattering = Scattering { anisotropy, ..Default::default() };
+            let integral = integral(&scattering);
+
+            assert!((integral - 1.0).abs() < 1e-3, "{anisotropy}: {integral}");
+        }
+
+        let isotropic = Scattering::default();
+        assert!((isotropic.phase(0.3) * Scattering::HEADLIGHT_COLOR.x - 1.0).abs() < 1e-6);
+
+        let forward = Scattering { anisotropy: 0.5, ..Default::default() };
+        assert!(forward.phase(1.0) > forward.phase(0.0) && forward.phase(0.0) > forward.phase(-1.0));
+    }
+
+    #[test]
+    fn scattering_of_a_cube_is_shadowed_by_its_density() {
+        // the default camera looks along -X
+        let albedo = vec3(0.8, 0.4, 0.2);
+        let field = Procedural::Cube { density: 20.0, color: albedo }.generate([4; 3]);
+
+        let render = |scattering: Scattering| {
+            let cfg = RenderConfiguration { integration: Integration::Scattering, scattering, ..Default::default() };
+            get_color(Vec2::ZERO, 1, 1, &field, &cfg)
+        };
+
+        let light = |light: Light| Scattering { lights: vec![light], ..Default::default() };
+        let directional = |direction, color| Light::Directional(DirectionalLight { direction, color });
+
+        // the light from the camera crosses the same density as the ray, which halves the scattered light
+        let lit = render(Scattering::default());
+        assert!(lit.abs_diff_eq(0.5 * albedo, 2e-2), "{lit}");
+
+        let from_camera = render(light(directional(Vec3::X, Scattering::HEADLIGHT_COLOR)));
+        assert!(from_camera.abs_diff_eq(lit, 1e-6), "{from_camera}");
+
+        let point = Light::Point(PointLight {
+            position: vec3(2.5, 0.0, 0.0),
+            color: Scattering::HEADLIGHT_COLOR * 2.0 * 2.0,
+        });
+        let from_point = render(light(point));
+        assert!(from_point.abs_diff_eq(lit, 2e-2), "{from_point}");
+
+        // the light from behind doesn't get through the cube
+        let from_behind = render(light(directional(-Vec3::X, Scattering::HEADLIGHT_COLOR)));
+        assert!(from_behind.abs_diff_eq(Vec3::ZERO, 1e-3), "{from_behind}");
+
+        // forward scattering sends less of the light from the camera back to it
+        let forward = Scattering { anisotropy: 0.5, ..Scattering::default() };
+        let back_scattered = render(forward.clone());
+        let ratio = forward.phase(-1.0) / Scattering::default().phase(-1.0);
+
+        assert!(back_scattered.abs_diff_eq(ratio * lit, 1e-3), "{back_scattered}");
+
+        // the baked radiance is added by its weight
+        let emission = Scattering { emission: 1.0, ..light(directional(Vec3::X, Vec3::ZERO)) };
+        let composite = get_color(Vec2::ZERO, 1, 1, &field, &RenderConfiguration::default());
+
+        assert!(render(emission).abs_diff_eq(composite, 1e-6));
+    }
+}
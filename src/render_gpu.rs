@@ -1,9 +1,11 @@
 use crate::{
-    benchmark::Bench, graphics::RenderConfiguration, spherical::RadianceField
+    benchmark::Bench, geometry::{Aabb, ClipPrimitive, ClipShape, Plane, Sphere},
+    graphics::RenderConfiguration, spherical::{BorderMode, Cell, Grid},
 };
-use std::{str::FromStr, sync::Arc};
+use std::{ops::Range, str::FromStr, sync::Arc};
 use bytemuck::{Pod, Zeroable};
 use glam::*;
+use half::f16;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use rayon::prelude::*;
@@ -51,6 +53,48 @@ impl FromStr for GpuContextMode {
 #[error("invalid GPU context mode '{0}', valid values are: 'debug', 'validation', 'silent'")]
 pub struct ParseGpuContextModeError(pub String);
 
+/// Way the GPU filters the model between the cells.
+#[derive(Clone, Debug, PartialEq, Default, Copy, Eq, PartialOrd, Ord, Hash)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GpuSampling {
+    /// Cells are read from storage textures and blended in the shader
+    #[default]
+    Manual,
+    /// Trilinear samples are taken by the texture units from sampled textures.
+    /// Interpolation weights have limited precision, usually 8 bits.
+    Hardware,
+}
+
+impl std::fmt::Display for GpuSampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Manual => "manual",
+            Self::Hardware => "hardware",
+        })
+    }
+}
+
+impl FromStr for GpuSampling {
+    type Err = ParseGpuSamplingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "manual" => Self::Manual,
+            "hardware" => Self::Hardware,
+            _ => return Err(ParseGpuSamplingError(s.to_owned())),
+        })
+    }
+}
+
+
+
+#[derive(Clone, Debug, Error)]
+#[error("invalid GPU sampling '{0}', valid values are: 'manual', 'hardware'")]
+pub struct ParseGpuSamplingError(pub String);
+
+
+
 impl From<GpuContextMode> for wgpu::InstanceFlags {
     fn from(value: GpuContextMode) -> Self {
         use GpuContextMode::*;
@@ -90,6 +134,18 @@ impl GpuContext {
         &self.instance
     }
 
+    /// Whether the texture units can filter the model in the format with the border mode
+    pub fn supports_hardware_filtering(&self, format: ModelTextureFormat, border: BorderMode) -> bool {
+        let features = self.device.features();
+
+        let filterable = matches!(
+            wgpu::TextureFormat::from(format).sample_type(None, Some(features)),
+            Some(wgpu::TextureSampleType::Float { filterable: true }),
+        );
+
+        filterable && (border != BorderMode::Zero || features.contains(wgpu::Features::ADDRESS_MODE_CLAMP_TO_ZERO))
+    }
+
     pub async fn new(mode: GpuContextMode)
         -> Result<Self, wgpu::RequestDeviceError>
     {
@@ -107,9 +163,13 @@ impl GpuContext {
 
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
+                // filtering of 32-bit textures and zero borders are used when available
                 required_features: wgpu::Features::TIMESTAMP_QUERY
                     | wgpu::Features::PUSH_CONSTANTS
-                    | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+                    | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+                    | adapter.features() & (
+                        wgpu::Features::FLOAT32_FILTERABLE | wgpu::Features::ADDRESS_MODE_CLAMP_TO_ZERO
+                    ),
                 label: None,
                 required_limits: adapter.limits(),
             },
@@ -134,38 +194,142 @@ impl GpuContext {
 
 
 pub const BATCH_SIZE: usize = 32;
-pub const N_TEXTURE_SLICES: usize = 9;
 
+/// Number of Y layers uploaded before and after every batch, so the filters
+/// read the neighbouring batches and the border cells the same way as on CPU
+pub const BATCH_HALO: usize = 2;
+
+
+
+/// Texel format of the textures the model is uploaded to the GPU in.
+#[derive(Clone, Debug, PartialEq, Default, Copy, Eq, PartialOrd, Ord, Hash)]
+pub enum ModelTextureFormat {
+    #[default]
+    Rgba32Float,
+    Rgba16Float,
+}
+
+impl ModelTextureFormat {
+    pub const fn texel_size(self) -> usize {
+        match self {
+            Self::Rgba32Float => std::mem::size_of::<[f32; 4]>(),
+            Self::Rgba16Float => std::mem::size_of::<[f16; 4]>(),
+        }
+    }
+
+    pub fn encode(self, texel: [f32; 4], bytes: &mut Vec<u8>) {
+        match self {
+            Self::Rgba32Float => bytes.extend_from_slice(bytemuck::bytes_of(&texel)),
+            Self::Rgba16Float => bytes.extend_from_slice(
+                bytemuck::bytes_of(&texel.map(f16::from_f32)),
+            ),
+        }
+    }
+}
 
+impl From<ModelTextureFormat> for wgpu::TextureFormat {
+    fn from(value: ModelTextureFormat) -> Self {
+        match value {
+            ModelTextureFormat::Rgba32Float => Self::Rgba32Float,
+            ModelTextureFormat::Rgba16Float => Self::Rgba16Float,
+        }
+    }
+}
 
-pub fn radiance_field_to_textures(field: &RadianceField) -> Vec<Vec<[f32; 4]>> {
-    assert!(field.size() % BATCH_SIZE == 0, "radiance field size should be divisible by {BATCH_SIZE}");
 
-    let batch_volume = field.size().pow(2) * BATCH_SIZE;
 
-    (0..field.size() / BATCH_SIZE)
-        .map(|batch_index| {
-            let batch = &field.cells[batch_index * batch_volume
-                                  ..(batch_index + 1) * batch_volume];
+/// Ranges of Y layers of the model which are uploaded as separate textures,
+/// each one is at most [`BATCH_SIZE`] layers thick.
+pub fn batch_ranges(size_y: usize) -> Vec<Range<usize>> {
+    (0..size_y.div_ceil(BATCH_SIZE))
+        .map(|i| i * BATCH_SIZE..usize::min((i + 1) * BATCH_SIZE, size_y))
+        .collect()
+}
 
-            (0..N_TEXTURE_SLICES)
+/// Splits the model into textures with [`BATCH_SIZE`] Y layers and [`BATCH_HALO`]
+/// layers around them resolved by the border mode, every texture contains one slice
+/// per spherical harmonic coefficient. The density is stored once, unweighted, in the
+/// alpha of the first slice.
+pub fn radiance_field_to_textures<const W: usize>(
+    field: &(impl Grid<W> + Sync), format: ModelTextureFormat, border: BorderMode,
+) -> Vec<Vec<u8>> {
+    let [size_x, size_y, size_z] = field.size();
+
+    batch_ranges(size_y).into_iter()
+        .map(|batch| {
+            let halo = BATCH_HALO as isize;
+
+            let batch_cells = (batch.start as isize - halo..batch.end as isize + halo)
+                .into_par_iter()
+                .flat_map_iter(|y| {
+                    let y = border.resolve(y, size_y);
+
+                    (0..size_z).flat_map(move |z| (0..size_x).map(move |x| match y {
+                        Some(y) => field.cell([x, y, z]).unwrap(),
+                        None => Cell::splat(0.0),
+                    }))
+                })
+                .collect::<Vec<_>>();
+
+            (0..W)
                 .into_par_iter()
-                .flat_map_iter(|i| {
-                    batch.iter()
-                        .map(move |cell| [
+                .map(|i| {
+                    let mut bytes = Vec::with_capacity(batch_cells.len() * format.texel_size());
+
+                    for cell in &batch_cells {
+                        format.encode([
                             cell.sh_r[i],
                             cell.sh_g[i],
                             cell.sh_b[i],
-                            cell.density / N_TEXTURE_SLICES as f32,
-                        ])
+                            if i == 0 { cell.density } else { 0.0 },
+                        ], &mut bytes);
+                    }
+
+                    bytes
                 })
                 .collect::<Vec<_>>()
+                .concat()
         })
         .collect()
 }
 
 
 
+/// Number of clipping primitives the GPU renderer supports, the rest are ignored
+pub const MAX_CLIP_PRIMITIVES: usize = 8;
+
+/// Number of entries of the transfer function table sampled for the density render target
+pub const TRANSFER_TABLE_SIZE: usize = 64;
+
+
+
+/// Clipping primitive in the layout of the shader uniform. Plane is given by a point
+/// and a normal, sphere by a center and a radius in `a.w` and box by its corners.
+#[repr(C)]
+#[derive(Clone, Debug, PartialEq, Default, Copy)]
+#[derive(Pod, Zeroable)]
+pub struct GpuClipPrimitive {
+    pub shape: u32,
+    pub invert: u32,
+    pub _pad: [u32; 2],
+    pub a: Vec4,
+    pub b: Vec4,
+}
+
+impl From<&ClipPrimitive> for GpuClipPrimitive {
+    fn from(value: &ClipPrimitive) -> Self {
+        let (shape, a, b) = match value.shape {
+            ClipShape::Plane(Plane { point, normal }) => (0, point.extend(0.0), normal.extend(0.0)),
+            ClipShape::Sphere(Sphere { center, radius }) => (1, center.extend(radius), Vec4::ZERO),
+            ClipShape::Box(Aabb { lo, hi }) => (2, lo.extend(0.0), hi.extend(0.0)),
+        };
+
+        Self { shape, invert: value.invert as u32, _pad: [0; 2], a, b }
+    }
+}
+
+
+
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq, Copy)]
 #[derive(Pod, Zeroable)]
@@ -177,9 +341,25 @@ pub struct GpuRenderCfg {
     pub camera_target_pos: Vec4,
     pub bounding_box_lo: Vec4,
     pub bounding_box_hi: Vec4,
+    /// Model to world rotation quaternion
+    pub transform_rotation: Vec4,
+    /// Model to world translation in `xyz` and scale in `w`
+    pub transform_translation_scale: Vec4,
     pub rm_settings_n_steps: u32,
     pub render_target: u32,
-    pub _pad: [u32; 2],
+    pub filtering: u32,
+    pub border: u32,
+    /// Cap color in `xyz` and 1 in `w` if the cut surfaces are drawn
+    pub clip_cap_color: Vec4,
+    pub clip_cap_density: f32,
+    pub clip_n_primitives: u32,
+    pub integration: u32,
+    pub _pad: u32,
+    pub clip_primitives: [GpuClipPrimitive; MAX_CLIP_PRIMITIVES],
+    /// Density range in `xy` and 1 in `z` if the opacity is read from the table
+    pub transfer_range: Vec4,
+    /// Colors in `xyz` and opacities in `w` evenly spaced over the range
+    pub transfer_table: [Vec4; TRANSFER_TABLE_SIZE],
 }
 
 impl From<&RenderConfiguration> for GpuRenderCfg {
@@ -193,34 +373,79 @@ impl From<&RenderConfiguration> for GpuRenderCfg {
             rm_settings_n_steps: value.rm_settings.n_steps,
             bounding_box_lo: value.bounding_box.lo.extend(0.0),
             bounding_box_hi: value.bounding_box.hi.extend(0.0),
+            transform_rotation: Vec4::from(value.transform.rotation_quat()),
+            transform_translation_scale: value.transform.translation.extend(value.transform.scale),
             render_target: value.render_target,
-            _pad: [0; 2],
+            filtering: value.filtering as u32,
+            border: value.border as u32,
+            clip_cap_color: value.clipping.cap_color.map_or(Vec4::ZERO, |color| color.extend(1.0)),
+            clip_cap_density: value.clipping.cap_density,
+            clip_n_primitives: value.clipping.primitives.len().min(MAX_CLIP_PRIMITIVES) as u32,
+            integration: value.integration as u32,
+            _pad: 0,
+            clip_primitives: std::array::from_fn(|i| value.clipping.primitives.get(i)
+                .map(GpuClipPrimitive::from)
+                .unwrap_or_default()),
+            transfer_range: {
+                let [lo, hi] = value.transfer.range();
+                vec4(lo, hi, !value.transfer.opacity.is_empty() as u32 as f32, 0.0)
+            },
+            transfer_table: value.transfer.table(TRANSFER_TABLE_SIZE).try_into().unwrap(),
         }
     }
 }
 
 
 
-pub fn render_gpu(
+pub fn render_gpu<const W: usize>(
     screen_width: usize, screen_height: usize, ctx: &GpuContext,
-    field: &RadianceField, cfg: &RenderConfiguration, bench: &mut Bench,
+    field: &(impl Grid<W> + Sync), format: ModelTextureFormat,
+    cfg: &RenderConfiguration, bench: &mut Bench,
 ) -> Vec<u8> {
     use wgpu::*;
     use wgpu::util::*;
 
     bench.copy.start();
 
+    let (border, sampling) = (cfg.border, cfg.gpu_sampling);
+
+    if cfg.clipping.primitives.len() > MAX_CLIP_PRIMITIVES {
+        eprintln!("Only the first {MAX_CLIP_PRIMITIVES} clipping primitives are used on GPU");
+    }
     let cfg = GpuRenderCfg::from(cfg);
 
-    assert!(screen_width % 8 == 0);
-    assert!(screen_height % 8 == 0);
+    let hardware_filtering = sampling == GpuSampling::Hardware
+        && ctx.supports_hardware_filtering(format, border);
+
+    if sampling == GpuSampling::Hardware && !hardware_filtering {
+        eprintln!("The GPU can't filter {format:?} textures with {border} border, filtering them manually");
+    }
+
+    assert!(screen_width.is_multiple_of(8));
+    assert!(screen_height.is_multiple_of(8));
+
+    let model_format = TextureFormat::from(format);
+
+    let mut defines = naga::FastHashMap::default();
+
+    defines.insert(String::from("SH_WIDTH"), W.to_string());
+    defines.insert(String::from("MAX_CLIP_PRIMITIVES"), MAX_CLIP_PRIMITIVES.to_string());
+    defines.insert(String::from("TRANSFER_TABLE_SIZE"), TRANSFER_TABLE_SIZE.to_string());
+
+    if format == ModelTextureFormat::Rgba16Float {
+        defines.insert(String::from("MODEL_HALF"), String::new());
+    }
+
+    if hardware_filtering {
+        defines.insert(String::from("HARDWARE_FILTERING"), String::new());
+    }
 
     let shader = ctx.device().create_shader_module(ShaderModuleDescriptor {
         label: Some("model_shader"),
         source: ShaderSource::Glsl {
             shader: include_str!("radiance.comp").into(),
             stage: naga::ShaderStage::Compute,
-            defines: Default::default(),
+            defines,
         },
     });
 
@@ -260,36 +485,40 @@ pub fn render_gpu(
 
     eprintln!("Turning model into texture slices...");
 
-    let field_texture_data = radiance_field_to_textures(field);
+    let field_texture_data = radiance_field_to_textures(field, format, border);
 
-    let field_texture_size = Extent3d {
-        width: field.size() as u32,
-        height: field.size() as u32,
-        depth_or_array_layers: (BATCH_SIZE * N_TEXTURE_SLICES) as u32,
-    };
+    let [size_x, size_y, size_z] = field.size();
+    let batches = batch_ranges(size_y);
 
-    let model_textures = field_texture_data.iter().map(|texture_data| {
+    let model_textures = field_texture_data.iter().zip(&batches).map(|(texture_data, batch)| {
         ctx.device().create_texture_with_data(
             ctx.queue(),
             &TextureDescriptor {
                 label: Some("model_texture"),
-                size: field_texture_size,
+                size: Extent3d {
+                    width: size_x as u32,
+                    height: size_z as u32,
+                    depth_or_array_layers: ((batch.len() + 2 * BATCH_HALO) * W) as u32,
+                },
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: TextureDimension::D3,
-                format: TextureFormat::Rgba32Float,
-                usage: TextureUsages::STORAGE_BINDING,
+                format: model_format,
+                usage: match hardware_filtering {
+                    true => TextureUsages::TEXTURE_BINDING,
+                    false => TextureUsages::STORAGE_BINDING,
+                },
                 view_formats: &[],
             },
             util::TextureDataOrder::LayerMajor,
-            bytemuck::cast_slice(texture_data),
+            texture_data,
         )
     }).collect::<Vec<_>>();
 
     let model_views = model_textures.iter().map(|texture| {
         texture.create_view(&TextureViewDescriptor {
             label: Some("mode_view"),
-            format: Some(TextureFormat::Rgba32Float),
+            format: Some(model_format),
             dimension: Some(TextureViewDimension::D3),
             aspect: TextureAspect::All,
             ..Default::default()
@@ -319,54 +548,91 @@ pub fn render_gpu(
         usage: BufferUsages::UNIFORM,
     });
 
+    let mut layout_entries = vec![
+        BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::StorageTexture {
+                access: StorageTextureAccess::ReadWrite,
+                format: TextureFormat::Rgba32Float,
+                view_dimension: TextureViewDimension::D2,
+            },
+            count: None,
+        },
+        BindGroupLayoutEntry {
+            binding: 1,
+            visibility: ShaderStages::COMPUTE,
+            ty: match hardware_filtering {
+                true => BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D3,
+                    multisampled: false,
+                },
+                false => BindingType::StorageTexture {
+                    access: StorageTextureAccess::ReadOnly,
+                    format: model_format,
+                    view_dimension: TextureViewDimension::D3,
+                },
+            },
+            count: None,
+        },
+        BindGroupLayoutEntry {
+            binding: 2,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        BindGroupLayoutEntry {
+            binding: 3,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+    ];
+
+    if hardware_filtering {
+        layout_entries.push(BindGroupLayoutEntry {
+            binding: 4,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Sampler(SamplerBindingType::Filtering),
+            count: None,
+        });
+    }
+
     let bind_group_layout = ctx.device().create_bind_group_layout(
         &BindGroupLayoutDescriptor {
             label: Some("bind_group_layout"),
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::StorageTexture {
-                        access: StorageTextureAccess::ReadWrite,
-                        format: TextureFormat::Rgba32Float,
-                        view_dimension: TextureViewDimension::D2,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::StorageTexture {
-                        access: StorageTextureAccess::ReadOnly,
-                        format: TextureFormat::Rgba32Float,
-                        view_dimension: TextureViewDimension::D3,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
+            entries: &layout_entries,
         },
     );
 
+    let address_mode = match border {
+        BorderMode::Zero => AddressMode::ClampToBorder,
+        BorderMode::Clamp => AddressMode::ClampToEdge,
+        BorderMode::Mirror => AddressMode::MirrorRepeat,
+        BorderMode::Wrap => AddressMode::Repeat,
+    };
+
+    let model_sampler = ctx.device().create_sampler(&SamplerDescriptor {
+        label: Some("model_sampler"),
+        address_mode_u: address_mode,
+        address_mode_v: address_mode,
+        // the layers around the batches handle the borders along the model Y axis
+        address_mode_w: AddressMode::ClampToEdge,
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        border_color: (border == BorderMode::Zero).then_some(SamplerBorderColor::Zero),
+        ..Default::default()
+    });
+
     #[repr(C)]
     #[derive(Clone, Debug, PartialEq, Default, Copy)]
     #[derive(Pod, Zeroable)]
@@ -376,7 +642,11 @@ pub fn render_gpu(
         index: u32,
         n_passes: u32,
         render_target: u32,
-        _pad: u32,
+        /// Number of Y layers of the model
+        size_y: u32,
+        /// Y index of the first layer of the batch texture, negative for the first batch
+        batch_first: i32,
+        _pad: [u32; 3],
     }
 
     let pipeline_layout = ctx.device().create_pipeline_layout(&PipelineLayoutDescriptor {
@@ -407,27 +677,36 @@ pub fn render_gpu(
     for (i, model_view) in model_views.iter().enumerate() {
         bench.copy.start();
 
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&screen_view),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(model_view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: render_cfg_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: pass_cfg_buffer.as_entire_binding(),
+            },
+        ];
+
+        if hardware_filtering {
+            entries.push(BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::Sampler(&model_sampler),
+            });
+        }
+
         let bind_group = ctx.device().create_bind_group(&BindGroupDescriptor {
             label: Some("bind_group"),
             layout: &bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(&screen_view),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(model_view),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: render_cfg_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: pass_cfg_buffer.as_entire_binding(),
-                },
-            ],
+            entries: &entries,
         });
 
         let mut encoder = ctx.device().create_command_encoder(&Default::default());
@@ -439,17 +718,24 @@ pub fn render_gpu(
         {
             let mut pass = encoder.begin_compute_pass(&Default::default());
 
+            let batch_lo = batches[i].start as f32 / size_y as f32;
+            let batch_hi = batches[i].end as f32 / size_y as f32;
+
+            let (box_lo, box_hi) = (cfg.bounding_box_lo, cfg.bounding_box_hi);
+
             let push = PushConst {
                 bounds_lo: Vec4::new(
-                    -0.5, i as f32 / n_passes as f32 - 0.5, -0.5, 0.0,
+                    box_lo.x, box_lo.y.lerp(box_hi.y, batch_lo), box_lo.z, 0.0,
                 ),
                 bounds_hi: Vec4::new(
-                    0.5, (i + 1) as f32 / n_passes as f32 - 0.5, 0.5, 0.0,
+                    box_hi.x, box_lo.y.lerp(box_hi.y, batch_hi), box_hi.z, 0.0,
                 ),
                 index: i as u32,
                 n_passes: n_passes as u32,
                 render_target: cfg.render_target,
-                _pad: 0,
+                size_y: size_y as u32,
+                batch_first: batches[i].start as i32 - BATCH_HALO as i32,
+                _pad: [0; 3],
             };
 
             pass.set_bind_group(0, &bind_group, &[]);
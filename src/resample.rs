@@ -0,0 +1,385 @@
+use crate::{
+    geometry::Aabb,
+    spherical::{Cell, Grid, RadianceField, ShRotation},
+};
+use bytemuck::Zeroable;
+use glam::*;
+use rayon::prelude::*;
+use std::{array, f32::consts::PI};
+use thiserror::Error;
+
+
+
+/// Reconstruction filter used by [`RadianceField::resampled`]. Box and Lanczos
+/// filters suit downsampling, trilinear and tricubic ones suit upsampling.
+#[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
+pub enum ResampleFilter {
+    /// Average of the covered cells
+    #[default]
+    Box,
+    /// Windowed sinc with 3 lobes
+    Lanczos,
+    Trilinear,
+    /// Catmull-Rom cubic
+    Tricubic,
+}
+
+impl ResampleFilter {
+    /// Half-width of the kernel in source cells when the scale is 1
+    pub const fn radius(self) -> f32 {
+        match self {
+            Self::Box => 0.5,
+            Self::Lanczos => 3.0,
+            Self::Trilinear => 1.0,
+            Self::Tricubic => 2.0,
+        }
+    }
+
+    pub fn kernel(self, x: f32) -> f32 {
+        let x = x.abs();
+
+        match self {
+            // cells on the edge of the box are half covered, so that a box
+            // centered on the border between two cells still takes both
+            Self::Box => match x {
+                x if x < 0.5 => 1.0,
+                0.5 => 0.5,
+                _ => 0.0,
+            },
+            Self::Lanczos => match x {
+                0.0 => 1.0,
+                x if x < 3.0 => 3.0 * f32::sin(PI * x) * f32::sin(PI * x / 3.0) / (PI * PI * x * x),
+                _ => 0.0,
+            },
+            Self::Trilinear => (1.0 - x).max(0.0),
+            Self::Tricubic => match x {
+                x if x < 1.0 => 1.5 * x * x * x - 2.5 * x * x + 1.0,
+                x if x < 2.0 => -0.5 * x * x * x + 2.5 * x * x - 4.0 * x + 2.0,
+                _ => 0.0,
+            },
+        }
+    }
+
+    /// Source cells and their weights for every cell of an axis resampled from `src_len` to `dst_len`
+    pub fn weights(self, src_len: usize, dst_len: usize) -> Vec<Vec<(usize, f32)>> {
+        let ratio = src_len as f32 / dst_len as f32;
+
+        // the kernel is stretched when downsampling so that it covers all of the source cells
+        let scale = ratio.max(1.0);
+        let radius = self.radius() * scale;
+
+        (0..dst_len)
+            .map(|i| {
+                let center = (i as f32 + 0.5) * ratio - 0.5;
+                let lo = (center - radius).floor() as isize;
+                let hi = (center + radius).ceil() as isize;
+
+                let mut weights = Vec::<(usize, f32)>::new();
+
+                for j in lo..=hi {
+                    let weight = self.kernel((j as f32 - center) / scale);
+
+                    if weight == 0.0 {
+                        continue;
+                    }
+
+                    // cells outside of the field repeat the border ones
+                    let j = j.clamp(0, src_len as isize - 1) as usize;
+
+                    match weights.iter_mut().find(|(index, _)| *index == j) {
+                        Some((_, sum)) => *sum += weight,
+                        None => weights.push((j, weight)),
+                    }
+                }
+
+                let sum = weights.iter().map(|(_, weight)| weight).sum::<f32>();
+
+                for (_, weight) in &mut weights {
+                    *weight /= sum;
+                }
+
+                weights
+            })
+            .collect()
+    }
+}
+
+impl std::str::FromStr for ResampleFilter {
+    type Err = ResampleFilterParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "box" => Self::Box,
+            "lanczos" => Self::Lanczos,
+            "trilinear" => Self::Trilinear,
+            "tricubic" => Self::Tricubic,
+            _ => return Err(ResampleFilterParseError(s.to_owned())),
+        })
+    }
+}
+
+impl std::fmt::Display for ResampleFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Box => "box",
+            Self::Lanczos => "lanczos",
+            Self::Trilinear => "trilinear",
+            Self::Tricubic => "tricubic",
+        })
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid resample filter '{0}', valid values are: \
+         'box', 'lanczos', 'trilinear' and 'tricubic'")]
+pub struct ResampleFilterParseError(pub String);
+
+
+
+#[derive(Debug, Error)]
+pub enum ResampleError {
+    #[error("crop region {lo:?}..{hi:?} is empty or exceeds the field of size {size:?}")]
+    BadCrop { lo: [usize; 3], hi: [usize; 3], size: [usize; 3] },
+
+    #[error("target size {0:?} has a zero dimension")]
+    EmptySize([usize; 3]),
+}
+
+
+
+impl<const W: usize> RadianceField<W> {
+    /// Copies cells of the grid from `lo` inclusive to `hi` exclusive
+    pub fn cropped(
+        grid: &(impl Grid<W> + Sync), lo: [usize; 3], hi: [usize; 3],
+    ) -> Result<Self, ResampleError> {
+        let size = grid.size();
+
+        if (0..3).any(|i| lo[i] >= hi[i] || hi[i] > size[i]) {
+            return Err(ResampleError::BadCrop { lo, hi, size });
+        }
+
+        let new_size = [hi[0] - lo[0], hi[1] - lo[1], hi[2] - lo[2]];
+
+        let cells = (0..RadianceField::volume(new_size))
+            .into_par_iter()
+            .map(|i| {
+                let [x, y, z] = RadianceField::index_from_flat(new_size, i);
+                grid.cell([lo[0] + x, lo[1] + y, lo[2] + z]).unwrap()
+            })
+            .collect();
+
+        Ok(Self { size: new_size, cells })
+    }
+
+    /// Resamples the grid to the size with a separable filter, one axis at a time.
+    /// Negative densities caused by filter overshoot are clamped to zero.
+    pub fn resampled(
+        grid: &(impl Grid<W> + Sync), size: [usize; 3], filter: ResampleFilter,
+    ) -> Result<Self, ResampleError> {
+        if size.contains(&0) {
+            return Err(ResampleError::EmptySize(size));
+        }
+
+        let mut field = Self::resampled_axis(grid, 0, size[0], filter);
+
+        for (axis, &len) in size.iter().enumerate().skip(1) {
+            if field.size[axis] != len {
+                field = Self::resampled_axis(&field, axis, len, filter);
+            }
+        }
+
+        field.cells.par_iter_mut().for_each(|cell| cell.density = cell.density.max(0.0));
+
+        Ok(field)
+    }
+
+    /// Bakes a rotation about the model space origin into the grid placed into the bounding box.
+    /// The new grid covers the rotated box with cells of about the same size, space outside of
+    /// the original box is empty. Returns the grid and its bounding box.
+    pub fn rotated(
+        grid: &(impl Grid<W> + Sync), bounding_box: Aabb, rotation: Quat,
+    ) -> (Self, Aabb) {
+        let src_size = grid.size();
+        let cell_size = (bounding_box.hi - bounding_box.lo) / RadianceField::size_vec(src_size);
+
+        let new_box = Aabb::from_points(bounding_box.corners().map(|corner| rotation * corner));
+        let size = ((new_box.hi - new_box.lo) / cell_size)
+            .ceil()
+            .to_array()
+            .map(|len| (len as usize).max(1));
+        let new_cell_size = (new_box.hi - new_box.lo) / RadianceField::size_vec(size);
+
+        let sh_rotation = ShRotation::new(rotation);
+        let inverse = rotation.inverse();
+
+        let cells = (0..RadianceField::volume(size))
+            .into_par_iter()
+            .map(|i| {
+                let index = RadianceField::index_from_flat(size, i);
+                let center = new_box.lo + new_cell_size * (Vec3::from_array(index.map(|i| i as f32)) + 0.5);
+
+                // continuous index in the source grid, cell centers are at integers
+                let pos = (inverse * center - bounding_box.lo) / cell_size - 0.5;
+                let lo = pos.floor();
+
+                let corners = array::from_fn::<_, 8, _>(|corner| {
+                    let offset = vec3(
+                        (corner >> 2 & 1) as f32,
+                        (corner >> 1 & 1) as f32,
+                        (corner & 1) as f32,
+                    );
+                    let index = lo + offset;
+
+                    if index.cmplt(Vec3::ZERO).any() {
+                        return Cell::zeroed();
+                    }
+
+                    grid.cell(index.to_array().map(|i| i as usize)).unwrap_or(Cell::zeroed())
+                });
+
+                Cell::trilerp(corners.each_ref(), (pos - lo).to_array()).rotated(&sh_rotation)
+            })
+            .collect();
+
+        (Self { size, cells }, new_box)
+    }
+
+    fn resampled_axis(
+        grid: &(impl Grid<W> + Sync), axis: usize, len: usize, filter: ResampleFilter,
+    ) -> Self {
+        let src_size = grid.size();
+        let weights = filter.weights(src_size[axis], len);
+
+        let mut size = src_size;
+        size[axis] = len;
+
+        let cells = (0..RadianceField::volume(size))
+            .into_par_iter()
+            .map(|i| {
+                let index = RadianceField::index_from_flat(size, i);
+
+                weights[index[axis]].iter().fold(Cell::zeroed(), |sum, &(j, weight)| {
+                    let mut src_index = index;
+                    src_index[axis] = j;
+
+                    sum + grid.cell(src_index).unwrap() * weight
+                })
+            })
+            .collect();
+
+        Self { size, cells }
+    }
+}
+
+/// Part of the bounding box covered by cells from `lo` inclusive to `hi` exclusive of a grid of the size
+pub fn crop_bounding_box(bounding_box: Aabb, size: [usize; 3], lo: [usize; 3], hi: [usize; 3]) -> Aabb {
+    let extent = bounding_box.hi - bounding_box.lo;
+    let size = RadianceField::size_vec(size);
+    let to_world = |index: [usize; 3]| {
+        bounding_box.lo + extent * Vec3::from_array(index.map(|i| i as f32)) / size
+    };
+
+    Aabb::new(to_world(lo), to_world(hi))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procedural::Procedural;
+
+    const FILTERS: [ResampleFilter; 4] = [
+        ResampleFilter::Box, ResampleFilter::Lanczos, ResampleFilter::Trilinear, ResampleFilter::Tricubic,
+    ];
+
+    fn noise_field() -> RadianceField<4> {
+        Procedural::Noise { density: 10.0, frequency: 2, seed: 11 }.generate([5, 3, 4])
+    }
+
+    #[test]
+    fn weights_are_normalized_and_keep_the_size() {
+        for filter in FILTERS {
+            for (src_len, dst_len) in [(8, 3), (3, 8), (7, 7), (1, 4)] {
+                for weights in filter.weights(src_len, dst_len) {
+                    let sum = weights.iter().map(|(_, weight)| weight).sum::<f32>();
+                    assert!((sum - 1.0).abs() < 1e-5, "{filter} weights sum to {sum}");
+                    assert!(weights.iter().all(|&(j, _)| j < src_len));
+                }
+            }
+
+            // the same size keeps the cells
+            for (i, weights) in filter.weights(7, 7).into_iter().enumerate() {
+                let weight = weights.iter().find(|&&(j, _)| j == i).map_or(0.0, |&(_, weight)| weight);
+                assert!((weight - 1.0).abs() < 1e-5, "{filter} weight {weight} of the same cell");
+            }
+        }
+    }
+
+    #[test]
+    fn box_filter_averages_the_covered_cells() {
+        let field = Procedural::GradientSlab { density: 8.0, thickness: 1.0, color: Vec3::ONE }.generate([4, 2, 2]);
+        let halved = RadianceField::resampled(&field, [2, 2, 2], ResampleFilter::Box).unwrap();
+
+        for x in 0..2 {
+            let expected = 0.5 * (field[[2 * x, 0, 0]].density + field[[2 * x + 1, 0, 0]].density);
+            assert!((halved[[x, 0, 0]].density - expected).abs() < 1e-5);
+        }
+
+        // constant fields stay constant for every filter
+        let cube = Procedural::Cube { density: 3.0, color: Vec3::ONE }.generate([4; 3]);
+
+        for filter in FILTERS {
+            for size in [[2, 7, 4], [9, 1, 3]] {
+                let resampled = RadianceField::resampled(&cube, size, filter).unwrap();
+
+                assert_eq!(resampled.size, size);
+                assert!(resampled.cells.iter().all(|cell| (cell.density - 3.0).abs() < 1e-4), "{filter} {size:?}");
+            }
+        }
+
+        assert!(matches!(
+            RadianceField::resampled(&cube, [2, 0, 2], ResampleFilter::Box),
+            Err(ResampleError::EmptySize(_)),
+        ));
+    }
+
+    #[test]
+    fn crop_copies_the_cells_and_the_box_part() {
+        let field = noise_field();
+        let cropped = RadianceField::cropped(&field, [1, 0, 2], [4, 2, 4]).unwrap();
+
+        assert_eq!(cropped.size, [3, 2, 2]);
+        assert_eq!(cropped[[0, 1, 1]], field[[1, 1, 3]]);
+        assert_eq!(cropped[[2, 0, 0]], field[[3, 0, 2]]);
+
+        for (lo, hi) in [([0, 0, 0], [6, 3, 4]), ([2, 0, 0], [2, 3, 4])] {
+            assert!(matches!(RadianceField::cropped(&field, lo, hi), Err(ResampleError::BadCrop { .. })));
+        }
+
+        let bounding_box = Aabb::new(vec3(-1.0, 0.0, 0.0), vec3(4.0, 3.0, 8.0));
+        let cropped_box = crop_bounding_box(bounding_box, [5, 3, 4], [1, 0, 2], [4, 2, 4]);
+
+        assert_eq!(cropped_box, Aabb::new(vec3(0.0, 0.0, 4.0), vec3(3.0, 2.0, 8.0)));
+    }
+
+    #[test]
+    fn quarter_turn_permutes_the_cells() {
+        let field = noise_field();
+        let bounding_box = Aabb::new(vec3(-2.5, -1.5, -2.0), vec3(2.5, 1.5, 2.0));
+        let (rotated, rotated_box) = RadianceField::rotated(&field, bounding_box, Quat::from_rotation_z(0.5 * PI));
+
+        assert_eq!(rotated.size, [3, 5, 4]);
+        assert!(rotated_box.lo.abs_diff_eq(vec3(-1.5, -2.5, -2.0), 1e-5));
+
+        for (i, cell) in rotated.cells.iter().enumerate() {
+            let [x, y, z] = RadianceField::index_from_flat(rotated.size, i);
+            let source = field[[y, 2 - x, z]];
+
+            assert!((cell.density - source.density).abs() < 1e-3, "density of [{x}, {y}, {z}]");
+            assert!(cell.diffuse().abs_diff_eq(source.diffuse(), 1e-3), "color of [{x}, {y}, {z}]");
+        }
+    }
+}
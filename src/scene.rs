@@ -0,0 +1,123 @@
+use crate::{
+    geometry::{Aabb, Transform},
+    graphics::{Camera, Clipping, Integration, Isosurface, RaymarchSettings, RenderConfiguration, Scattering},
+    spherical::{BorderMode, Filtering},
+    transfer::TransferFunction,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+
+
+/// Several fields rendered together, read from a `.toml` file with a `[[field]]` table per field.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SceneDescription {
+    pub camera: Camera,
+    #[serde(default)]
+    pub rm_settings: RaymarchSettings,
+    #[serde(default)]
+    pub filtering: Filtering,
+    #[serde(default)]
+    pub border: BorderMode,
+    #[serde(default)]
+    pub integration: Integration,
+    #[serde(default)]
+    pub clipping: Clipping,
+    #[serde(default)]
+    pub transfer: TransferFunction,
+    #[serde(default)]
+    pub isosurface: Isosurface,
+    #[serde(default)]
+    pub scattering: Scattering,
+    #[serde(rename = "field")]
+    pub fields: Vec<SceneField>,
+}
+
+impl SceneDescription {
+    /// Configuration with the camera and raymarching settings of the scene
+    pub fn configuration(&self) -> RenderConfiguration {
+        RenderConfiguration {
+            camera: self.camera,
+            rm_settings: self.rm_settings,
+            filtering: self.filtering,
+            border: self.border,
+            integration: self.integration,
+            clipping: self.clipping.clone(),
+            transfer: self.transfer.clone(),
+            isosurface: self.isosurface.clone(),
+            scattering: self.scattering.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Model paths of the fields relative to the scene file
+    pub fn model_paths(&self, scene_path: impl AsRef<Path>) -> Vec<PathBuf> {
+        let dir = scene_path.as_ref().parent().unwrap_or(Path::new("."));
+
+        self.fields.iter()
+            .map(|field| dir.join(&field.model))
+            .collect()
+    }
+}
+
+
+
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SceneField {
+    /// Model file, Plenoxels checkpoint or NRRD density volume, relative to the scene file
+    pub model: PathBuf,
+    /// Bounding box in model space. The imported or the default one is used if it is missing.
+    pub bounding_box: Option<Aabb>,
+    #[serde(default)]
+    pub transform: Transform,
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+
+    #[test]
+    fn fields_are_read_relative_to_the_scene() {
+        let scene = toml::from_str::<SceneDescription>(r#"
+            integration = "mip"
+
+            [camera]
+            distance = 2.0
+            theta = 0.0
+            phi = 1.0
+            vfov = 0.5
+            target_pos = [0.0, 0.0, 0.0]
+
+            [[field]]
+            model = "model.bin"
+            transform = { translation = [0.0, 0.0, -0.4], scale = 0.8 }
+
+            [[field]]
+            model = "volumes/density.nhdr"
+            bounding_box = { lo = [0.0, 0.0, 0.0], hi = [2.0, 1.0, 1.0] }
+        "#).unwrap();
+
+        assert_eq!(scene.fields.len(), 2);
+        assert_eq!(scene.fields[0].bounding_box, None);
+        assert_eq!(scene.fields[0].transform.translation, Vec3::new(0.0, 0.0, -0.4));
+        assert_eq!(scene.fields[0].transform.scale, 0.8);
+        assert_eq!(scene.fields[1].bounding_box, Some(Aabb::new(Vec3::ZERO, Vec3::new(2.0, 1.0, 1.0))));
+        assert_eq!(scene.fields[1].transform, Transform::IDENTITY);
+
+        let cfg = scene.configuration();
+        assert_eq!(cfg.camera, scene.camera);
+        assert_eq!(cfg.integration, Integration::Maximum);
+        assert_eq!(cfg.filtering, Filtering::default());
+
+        assert_eq!(scene.model_paths("assets/scene.toml"), [
+            PathBuf::from("assets/model.bin"),
+            PathBuf::from("assets/volumes/density.nhdr"),
+        ]);
+        assert_eq!(scene.model_paths("scene.toml")[0], PathBuf::from("model.bin"));
+    }
+}
@@ -0,0 +1,512 @@
+use crate::{
+    colormap::Colormap,
+    geometry::Aabb,
+    spherical::{Cell, RadianceField, SH_C0},
+};
+use bytemuck::Zeroable;
+use glam::*;
+use rayon::prelude::*;
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+
+
+#[derive(Debug, Error)]
+pub enum SliceError {
+    #[error("no PNG slices in '{0}'")]
+    NoSlices(PathBuf),
+
+    #[error("slice '{path}' has size {actual:?}, expected {expected:?}")]
+    SizeMismatch { path: PathBuf, expected: [usize; 2], actual: [usize; 2] },
+
+    #[error("failed to decode slice '{path}': {source}")]
+    Png { path: PathBuf, source: png::DecodingError },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+
+
+/// Axis along which the slices are stacked. Images are seen upright when looking
+/// against the axis, with Y pointing up for X and Z axes and -Z for Y axis.
+#[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
+pub enum SliceAxis {
+    /// Image columns go along Z and rows along Y
+    X,
+    /// Image columns go along X and rows along Z
+    Y,
+    /// Image columns go along X and rows along Y
+    #[default]
+    Z,
+}
+
+impl std::str::FromStr for SliceAxis {
+    type Err = SliceAxisParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "x" => Self::X,
+            "y" => Self::Y,
+            "z" => Self::Z,
+            _ => return Err(SliceAxisParseError(s.to_owned())),
+        })
+    }
+}
+
+impl std::fmt::Display for SliceAxis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::X => "x",
+            Self::Y => "y",
+            Self::Z => "z",
+        })
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid slice axis '{0}', valid values are: 'x', 'y' and 'z'")]
+pub struct SliceAxisParseError(pub String);
+
+
+
+/// Mapping of the pixel intensity in the [0, 1] range to the [0, 1] range,
+/// which is then multiplied by the density scale.
+#[derive(Clone, Debug, Default, PartialEq, Copy)]
+pub enum DensityCurve {
+    #[default]
+    Linear,
+    /// Intensity raised to the power
+    Gamma(f32),
+    /// Linear ramp from zero at `lo` to one at `hi`, e.g. a CT window
+    Window { lo: f32, hi: f32 },
+}
+
+impl DensityCurve {
+    pub fn apply(self, intensity: f32) -> f32 {
+        match self {
+            Self::Linear => intensity,
+            Self::Gamma(gamma) => intensity.powf(gamma),
+            Self::Window { lo, hi } => ((intensity - lo) / (hi - lo)).clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl std::str::FromStr for DensityCurve {
+    type Err = DensityCurveParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || DensityCurveParseError(s.to_owned());
+        let parse = |value: &str| value.trim().parse::<f32>().map_err(|_| error());
+
+        Ok(match s.split_once(':') {
+            None if s == "linear" => Self::Linear,
+            Some(("gamma", gamma)) => match parse(gamma)? {
+                gamma if gamma > 0.0 => Self::Gamma(gamma),
+                _ => return Err(error()),
+            },
+            Some(("window", range)) => {
+                let (lo, hi) = range.split_once(',').ok_or_else(error)?;
+                let (lo, hi) = (parse(lo)?, parse(hi)?);
+
+                if lo >= hi {
+                    return Err(error());
+                }
+
+                Self::Window { lo, hi }
+            },
+            _ => return Err(error()),
+        })
+    }
+}
+
+impl std::fmt::Display for DensityCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Linear => f.write_str("linear"),
+            Self::Gamma(gamma) => write!(f, "gamma:{gamma}"),
+            Self::Window { lo, hi } => write!(f, "window:{lo},{hi}"),
+        }
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid density curve '{0}', valid values are: 'linear', 'gamma:<exponent>' and 'window:<lo>,<hi>'")]
+pub struct DensityCurveParseError(pub String);
+
+
+
+/// Color of the cells of a field imported from slices
+#[derive(Clone, Debug, Default, PartialEq, Copy)]
+pub enum SliceColor {
+    /// Colors of the pixels, gray for grayscale slices
+    #[default]
+    Image,
+    /// Colormap of the curved intensity
+    Colormap(Colormap),
+    Constant(Vec3),
+}
+
+
+
+/// Parameters of the conversion of pixels into cells
+#[derive(Clone, Debug, Default, PartialEq, Copy)]
+pub struct SliceImport {
+    pub axis: SliceAxis,
+    /// Density of the cells with the full intensity
+    pub density_scale: f32,
+    pub curve: DensityCurve,
+    pub color: SliceColor,
+}
+
+
+
+/// Directory of numbered PNG slices of a volume.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SliceStack {
+    /// Slices in the natural order of their names, so `2.png` goes before `10.png`
+    pub paths: Vec<PathBuf>,
+    /// Width and height of every slice
+    pub image_size: [usize; 2],
+}
+
+impl SliceStack {
+    /// Whether the directory has PNG files in it
+    pub fn is_slice_dir(path: impl AsRef<Path>) -> bool {
+        std::fs::read_dir(path).is_ok_and(|entries| entries.flatten().any(|entry| is_png(&entry.path())))
+    }
+
+    /// Lists the slices of the directory and reads the size of the first one
+    pub fn find(dir: impl AsRef<Path>) -> Result<Self, SliceError> {
+        let dir = dir.as_ref();
+
+        let mut paths = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .filter(|path| path.as_ref().map_or(true, |path| is_png(path)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        paths.sort_by(|lhs, rhs| natural_cmp(&lhs.to_string_lossy(), &rhs.to_string_lossy()));
+
+        let first = paths.first().ok_or_else(|| SliceError::NoSlices(dir.to_owned()))?;
+
+        let decoder = png::Decoder::new(std::fs::File::open(first)?);
+        let reader = decoder.read_info().map_err(|source| SliceError::Png { path: first.clone(), source })?;
+        let info = reader.info();
+
+        Ok(Self { image_size: [info.width as usize, info.height as usize], paths })
+    }
+
+    /// Number of cells of the field along X, Y and Z axes
+    pub fn size(&self, axis: SliceAxis) -> [usize; 3] {
+        let [width, height] = self.image_size;
+        let n_slices = self.paths.len();
+
+        match axis {
+            SliceAxis::X => [n_slices, height, width],
+            SliceAxis::Y => [width, n_slices, height],
+            SliceAxis::Z => [width, height, n_slices],
+        }
+    }
+
+    /// Box centered at the origin with the longest side of 1. Spacing is the distance
+    /// between the neighbouring cells along X, Y and Z axes in arbitrary units.
+    pub fn bounding_box(&self, axis: SliceAxis, spacing: Vec3) -> Aabb {
+        let extent = spacing * RadianceField::size_vec(self.size(axis));
+        let half = 0.5 * extent / extent.max_element();
+
+        Aabb::new(-half, half)
+    }
+
+    /// Builds a degree 0 field reading one slice at a time, so only the field
+    /// and a single image are kept in memory
+    pub fn to_field(&self, import: &SliceImport) -> Result<RadianceField<1>, SliceError> {
+        let size = self.size(import.axis);
+        let [width, height] = self.image_size;
+
+        let mut cells = vec![Cell::<1>::zeroed(); RadianceField::volume(size)];
+
+        for (slice, path) in self.paths.iter().enumerate() {
+            let pixels = read_slice(path, self.image_size)?;
+
+            let slice_cells = pixels.par_iter()
+                .map(|&rgb| {
+                    let intensity = rgb.dot(vec3(0.2126, 0.7152, 0.0722));
+                    let value = import.curve.apply(intensity);
+
+                    let color = match import.color {
+                        SliceColor::Image => rgb,
+                        SliceColor::Colormap(colormap) => colormap.eval(value),
+                        SliceColor::Constant(color) => color,
+                    };
+
+                    Cell {
+                        density: import.density_scale * value,
+                        sh_r: [color.x / SH_C0],
+                        sh_g: [color.y / SH_C0],
+                        sh_b: [color.z / SH_C0],
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            for (i, cell) in slice_cells.into_iter().enumerate() {
+                let (column, row) = (i % width, i / width);
+
+                let index = match import.axis {
+                    SliceAxis::X => [slice, height - 1 - row, width - 1 - column],
+                    SliceAxis::Y => [column, slice, row],
+                    SliceAxis::Z => [column, height - 1 - row, slice],
+                };
+
+                cells[RadianceField::index_of(size, index)] = cell;
+            }
+        }
+
+        Ok(RadianceField { size, cells })
+    }
+}
+
+fn is_png(path: &Path) -> bool {
+    path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+}
+
+/// Reads the slice as colors in the [0, 1] range row by row from the top
+fn read_slice(path: &Path, expected: [usize; 2]) -> Result<Vec<Vec3>, SliceError> {
+    let png_error = |source| SliceError::Png { path: path.to_owned(), source };
+
+    let mut decoder = png::Decoder::new(std::io::BufReader::new(std::fs::File::open(path)?));
+
+    // palettes and low bit depths are expanded, 16-bit samples are kept
+    decoder.set_transformations(png::Transformations::EXPAND);
+
+    let mut reader = decoder.read_info().map_err(png_error)?;
+    let actual = [reader.info().width as usize, reader.info().height as usize];
+
+    if actual != expected {
+        return Err(SliceError::SizeMismatch { path: path.to_owned(), expected, actual });
+    }
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(png_error)?;
+    let buffer = &buffer[..frame.buffer_size()];
+
+    let samples = match frame.bit_depth {
+        png::BitDepth::Sixteen => buffer.chunks_exact(2)
+            .map(|sample| u16::from_be_bytes([sample[0], sample[1]]) as f32 / u16::MAX as f32)
+            .collect::<Vec<_>>(),
+        _ => buffer.iter().map(|&sample| sample as f32 / u8::MAX as f32).collect(),
+    };
+
+    // alpha is ignored
+    let n_channels = frame.color_type.samples();
+
+    Ok(samples.chunks_exact(n_channels)
+        .map(|pixel| match pixel {
+            [gray] | [gray, _] => Vec3::splat(*gray),
+            [r, g, b, ..] => vec3(*r, *g, *b),
+            [] => unreachable!("pixels have at least one sample"),
+        })
+        .collect())
+}
+
+/// Compares the strings treating runs of digits as numbers
+fn natural_cmp(lhs: &str, rhs: &str) -> Ordering {
+    let (mut lhs, mut rhs) = (lhs.chars().peekable(), rhs.chars().peekable());
+
+    loop {
+        match (lhs.peek().copied(), rhs.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(l), Some(r)) if l.is_ascii_digit() && r.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(digit);
+                    }
+
+                    digits
+                };
+
+                let (l, r) = (take_number(&mut lhs), take_number(&mut rhs));
+                let (l_trimmed, r_trimmed) = (l.trim_start_matches('0'), r.trim_start_matches('0'));
+
+                let ordering = l_trimmed.len().cmp(&r_trimmed.len())
+                    .then_with(|| l_trimmed.cmp(r_trimmed))
+                    .then_with(|| l.len().cmp(&r.len()));
+
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            },
+            (Some(l), Some(r)) => {
+                if l != r {
+                    return l.cmp(&r);
+                }
+
+                lhs.next();
+                rhs.next();
+            },
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spherical::Grid;
+
+    /// Empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("radiance_fields_slices_{name}_{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_png(path: PathBuf, [width, height]: [usize; 2], color_type: png::ColorType, data: &[u8]) {
+        let mut encoder = png::Encoder::new(std::fs::File::create(path).unwrap(), width as u32, height as u32);
+        encoder.set_color(color_type);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header().unwrap().write_image_data(data).unwrap();
+    }
+
+    /// Gray level of the pixel of a test slice, unique in the stack
+    fn gray(slice: usize, row: usize, column: usize) -> u8 {
+        (40 * slice + 10 * row + 3 * column) as u8
+    }
+
+    #[test]
+    fn slices_are_sorted_by_their_numbers() {
+        let mut names = ["slice_10.png", "slice_2.png", "slice_002.png", "slice_1.png", "a.png"];
+        names.sort_by(|lhs, rhs| natural_cmp(lhs, rhs));
+
+        assert_eq!(names, ["a.png", "slice_1.png", "slice_2.png", "slice_002.png", "slice_10.png"]);
+
+        let dir = test_dir("sorted");
+
+        for (slice, name) in ["10.png", "9.PNG", "100.png"].into_iter().enumerate() {
+            write_png(dir.join(name), [1, 1], png::ColorType::Grayscale, &[slice as u8]);
+        }
+
+        std::fs::write(dir.join("notes.txt"), "not a slice").unwrap();
+
+        let stack = SliceStack::find(&dir).unwrap();
+        let names = stack.paths.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(names, ["9.PNG", "10.png", "100.png"]);
+        assert_eq!(stack.image_size, [1, 1]);
+        assert!(SliceStack::is_slice_dir(&dir));
+        assert!(matches!(SliceStack::find(dir.join("missing")), Err(SliceError::Io(_))));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn density_curves_map_the_intensity() {
+        let curve = |s: &str| s.parse::<DensityCurve>().unwrap();
+
+        assert_eq!(curve("linear").apply(0.3), 0.3);
+        assert!((curve("gamma:2").apply(0.5) - 0.25).abs() < 1e-6);
+        assert_eq!(curve("window:0.2,0.6"), DensityCurve::Window { lo: 0.2, hi: 0.6 });
+        assert_eq!(curve("window:0.2,0.6").apply(0.1), 0.0);
+        assert!((curve("window:0.2,0.6").apply(0.3) - 0.25).abs() < 1e-6);
+        assert_eq!(curve("window:0.2,0.6").apply(0.9), 1.0);
+
+        for curve in [DensityCurve::Linear, DensityCurve::Gamma(2.2), DensityCurve::Window { lo: 0.1, hi: 0.5 }] {
+            assert_eq!(curve.to_string().parse::<DensityCurve>().unwrap(), curve);
+        }
+
+        for invalid in ["cubic", "gamma:0", "gamma:x", "window:0.5,0.5", "window:0.1"] {
+            assert!(invalid.parse::<DensityCurve>().is_err(), "'{invalid}' is accepted");
+        }
+    }
+
+    #[test]
+    fn slices_are_stacked_upright_along_the_axis() {
+        let dir = test_dir("stacked");
+        let [width, height, n_slices] = [2, 3, 4];
+
+        for slice in 0..n_slices {
+            let pixels = (0..width * height).map(|i| gray(slice, i / width, i % width)).collect::<Vec<_>>();
+            write_png(dir.join(format!("{slice}.png")), [width, height], png::ColorType::Grayscale, &pixels);
+        }
+
+        let stack = SliceStack::find(&dir).unwrap();
+
+        for axis in [SliceAxis::X, SliceAxis::Y, SliceAxis::Z] {
+            let import = SliceImport { axis, density_scale: 255.0, ..Default::default() };
+            let field = stack.to_field(&import).unwrap();
+
+            assert_eq!(field.size, stack.size(axis));
+
+            for slice in 0..n_slices {
+                for (row, column) in (0..height).flat_map(|row| (0..width).map(move |column| (row, column))) {
+                    let index = match axis {
+                        SliceAxis::X => [slice, height - 1 - row, width - 1 - column],
+                        SliceAxis::Y => [column, slice, row],
+                        SliceAxis::Z => [column, height - 1 - row, slice],
+                    };
+
+                    let density = field.cell(index).unwrap().density;
+                    let expected = gray(slice, row, column) as f32;
+
+                    assert!((density - expected).abs() < 1e-3, "{axis} {index:?}: {density} != {expected}");
+                }
+            }
+        }
+
+        // the longest side is 1, here along the stacking axis
+        let bounding_box = stack.bounding_box(SliceAxis::Z, Vec3::ONE);
+        assert!(bounding_box.hi.abs_diff_eq(vec3(0.25, 0.375, 0.5), 1e-6), "{bounding_box:?}");
+
+        // all of the slices should have the size of the first one
+        write_png(dir.join("9.png"), [width, width], png::ColorType::Grayscale, &[0; 4]);
+
+        assert!(matches!(
+            SliceStack::find(&dir).unwrap().to_field(&SliceImport::default()),
+            Err(SliceError::SizeMismatch { expected: [2, 3], actual: [2, 2], .. }),
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cells_are_colored_by_the_image_or_the_colormap() {
+        let dir = test_dir("colored");
+        write_png(dir.join("0.png"), [2, 1], png::ColorType::Rgb, &[255, 0, 0, 0, 51, 255]);
+
+        let stack = SliceStack::find(&dir).unwrap();
+
+        let colors = |color: SliceColor| {
+            let import = SliceImport { color, density_scale: 1.0, ..Default::default() };
+            let field = stack.to_field(&import).unwrap();
+
+            field.cells.iter().map(|cell| vec3(cell.sh_r[0], cell.sh_g[0], cell.sh_b[0]) * SH_C0).collect::<Vec<_>>()
+        };
+
+        let image = colors(SliceColor::Image);
+        assert!(image[0].abs_diff_eq(Vec3::X, 1e-6) && image[1].abs_diff_eq(vec3(0.0, 0.2, 1.0), 1e-6), "{image:?}");
+
+        // the colormap is evaluated at the luminance of the pixels
+        let colormapped = colors(SliceColor::Colormap(Colormap::Viridis));
+        let luminance = [0.2126, 0.2 * 0.7152 + 0.0722];
+
+        for (color, luminance) in colormapped.iter().zip(luminance) {
+            assert!(color.abs_diff_eq(Colormap::Viridis.eval(luminance), 1e-5), "{color}");
+        }
+
+        assert!(colors(SliceColor::Constant(Vec3::Y)).iter().all(|color| color.abs_diff_eq(Vec3::Y, 1e-6)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use crate::spherical::{Cell, Grid, RadianceField, SPHERICAL_HARMONIC_WIDTH};
+use bytemuck::Zeroable;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+
+
+/// Number of cells along each side of a brick
+pub const BRICK_SIZE: usize = 8;
+pub const BRICK_VOLUME: usize = BRICK_SIZE.pow(3);
+
+/// Marks a brick without any stored cells in [`SparseField::brick_index`]
+pub const EMPTY_BRICK: u32 = u32::MAX;
+
+
+
+/// Radiance field that stores only occupied `8x8x8` bricks of cells,
+/// cells of missing bricks are considered empty.
+#[derive(Clone, Default, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SparseField<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
+    /// Number of cells along X, Y and Z axes
+    pub size: [usize; 3],
+    /// Position of each brick in [`SparseField::bricks`] or [`EMPTY_BRICK`],
+    /// bricks are laid out the same way as cells in [`RadianceField`]
+    pub brick_index: Vec<u32>,
+    /// Cells of the occupied bricks, `BRICK_VOLUME` cells per brick
+    pub bricks: Vec<Cell<W>>,
+}
+
+impl<const W: usize> SparseField<W> {
+    /// Number of bricks along X, Y and Z axes of the field
+    pub fn n_bricks(&self) -> [usize; 3] {
+        self.size.map(|size| size.div_ceil(BRICK_SIZE))
+    }
+
+    pub const fn n_occupied_bricks(&self) -> usize {
+        self.bricks.len() / BRICK_VOLUME
+    }
+
+    /// Index of the cell inside of a brick
+    pub const fn index_in_brick([x, y, z]: [usize; 3]) -> usize {
+        RadianceField::index_of([BRICK_SIZE; 3], [x % BRICK_SIZE, y % BRICK_SIZE, z % BRICK_SIZE])
+    }
+
+    /// Collects all bricks of the grid containing at least one cell
+    /// with density above `density_threshold`
+    pub fn from_grid(grid: &(impl Grid<W> + Sync), density_threshold: f32) -> Self {
+        let size = grid.size();
+        let n_bricks = size.map(|size| size.div_ceil(BRICK_SIZE));
+
+        let bricks = (0..RadianceField::volume(n_bricks))
+            .into_par_iter()
+            .map(|brick| {
+                let [bx, by, bz] = RadianceField::index_from_flat(n_bricks, brick);
+
+                let cells = (0..BRICK_VOLUME)
+                    .map(|i| {
+                        let [x, y, z] = RadianceField::index_from_flat([BRICK_SIZE; 3], i);
+
+                        grid.cell([
+                            bx * BRICK_SIZE + x,
+                            by * BRICK_SIZE + y,
+                            bz * BRICK_SIZE + z,
+                        ]).unwrap_or_else(Cell::zeroed)
+                    })
+                    .collect::<Vec<_>>();
+
+                cells.iter()
+                    .any(|cell| cell.density > density_threshold)
+                    .then_some(cells)
+            })
+            .collect::<Vec<_>>();
+
+        let mut brick_index = Vec::with_capacity(bricks.len());
+        let mut occupied = Vec::new();
+
+        for brick in bricks {
+            match brick {
+                Some(cells) => {
+                    brick_index.push((occupied.len() / BRICK_VOLUME) as u32);
+                    occupied.extend(cells);
+                },
+                None => brick_index.push(EMPTY_BRICK),
+            }
+        }
+
+        Self { size, brick_index, bricks: occupied }
+    }
+
+    pub fn to_field(&self) -> RadianceField<W> {
+        RadianceField::from_grid(self)
+    }
+}
+
+impl<const W: usize> Grid<W> for SparseField<W> {
+    fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    fn cell(&self, index: [usize; 3]) -> Option<Cell<W>> {
+        if !RadianceField::contains(self.size, index) {
+            return None;
+        }
+
+        let brick = self.brick_index[RadianceField::index_of(
+            self.n_bricks(), index.map(|i| i / BRICK_SIZE),
+        )];
+
+        if brick == EMPTY_BRICK {
+            return Some(Cell::zeroed());
+        }
+
+        Some(self.bricks[brick as usize * BRICK_VOLUME + Self::index_in_brick(index)])
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{procedural::Procedural, spherical::BorderMode};
+    use glam::*;
+
+    /// Noise field with sizes that aren't multiples of the brick size, empty outside of
+    /// the cells with X below 6 and Z from 9
+    fn partial_field() -> RadianceField<4> {
+        let mut field = Procedural::Noise { density: 20.0, frequency: 3, seed: 3 }.generate([13, 9, 20]);
+
+        for (i, cell) in field.cells.iter_mut().enumerate() {
+            let [x, _, z] = RadianceField::index_from_flat([13, 9, 20], i);
+
+            if x >= 6 || z < 9 {
+                *cell = Cell::zeroed();
+            }
+        }
+
+        field
+    }
+
+    #[test]
+    fn bricks_are_looked_up_by_the_cell_index() {
+        let field = partial_field();
+        let sparse = SparseField::from_grid(&field, 0.0);
+
+        assert_eq!(sparse.n_bricks(), [2, 2, 3]);
+        // X brick 0, both Y bricks and Z bricks 1 and 2
+        assert_eq!(sparse.n_occupied_bricks(), 4);
+        assert_eq!(sparse.brick_index.iter().filter(|&&brick| brick == EMPTY_BRICK).count(), 8);
+
+        assert_eq!(sparse.to_field(), field);
+        assert_eq!(sparse.cell([13, 0, 0]), None);
+        assert_eq!(sparse.cell([12, 8, 19]), Some(Cell::zeroed()));
+    }
+
+    #[test]
+    fn bricks_below_the_threshold_are_dropped() {
+        let field = Procedural::SoftSphere { density: 20.0, sigma: 0.1, color: Vec3::ONE }.generate([24; 3]);
+        let sparse = SparseField::from_grid(&field, 1.0);
+
+        // the central brick and its face neighbours reach the threshold, the edge ones don't
+        assert_eq!(sparse.n_occupied_bricks(), 7);
+        assert_eq!(sparse.cell([12; 3]), field.cell([12; 3]));
+        assert_eq!(sparse.cell([0; 3]), Some(Cell::zeroed()));
+    }
+
+    #[test]
+    fn sparse_and_dense_samples_agree_at_brick_borders() {
+        let field = partial_field();
+        let sparse = SparseField::from_grid(&field, 0.0);
+
+        for i in 0..=40 {
+            let t = i as f32 / 40.0;
+
+            // lines through the borders of the bricks at 8 cells along every axis
+            let positions = [
+                vec3(t, 8.0 / 9.0, 9.5 / 20.0),
+                vec3(3.0 / 13.0, t, 8.0 / 20.0),
+                vec3(8.0 / 13.0, 0.5, t),
+            ];
+
+            for pos in positions {
+                for border in [BorderMode::Zero, BorderMode::Clamp, BorderMode::Mirror, BorderMode::Wrap] {
+                    assert_eq!(sparse.sample_trilinear(pos, border), field.sample_trilinear(pos, border), "at {pos}");
+                }
+            }
+        }
+    }
+}
@@ -1,55 +1,150 @@
 use serde::{Serialize, Deserialize};
 use bytemuck::{Pod, Zeroable};
 use glam::*;
+use rayon::prelude::*;
 use std::array;
+use thiserror::Error;
 
 
 
+/// Number of spherical harmonic coefficients of degree 2, which is the default degree
 pub const SPHERICAL_HARMONIC_WIDTH: usize = 9;
+pub const MAX_SPHERICAL_HARMONIC_DEGREE: u32 = 3;
 
 
 
+/// Number of spherical harmonic coefficients for the degree
+pub const fn sh_width(degree: u32) -> usize {
+    ((degree + 1) * (degree + 1)) as usize
+}
+
+/// Value of the constant spherical harmonic basis function, converts diffuse color to its coefficient
+pub const SH_C0: f32 = 0.28209479;
+/// Magnitude of the degree 1 spherical harmonic basis functions over the direction component
+pub const SH_C1: f32 = 0.48860251;
+
+/// Spherical harmonic degree for the number of coefficients
+pub const fn sh_degree(width: usize) -> Option<u32> {
+    match width {
+        1 => Some(0),
+        4 => Some(1),
+        9 => Some(2),
+        16 => Some(3),
+        _ => None,
+    }
+}
+
+
+
+/// (De)serializes arrays of any length the same way `serde` does for short arrays.
+pub mod serde_array {
+    use serde::{de, ser::SerializeTuple as _, Deserialize, Deserializer, Serialize, Serializer};
+    use std::{fmt, marker::PhantomData};
+
+    pub fn serialize<S, T, const N: usize>(array: &[T; N], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        let mut tuple = serializer.serialize_tuple(N)?;
+
+        for value in array {
+            tuple.serialize_element(value)?;
+        }
+
+        tuple.end()
+    }
+
+    pub fn deserialize<'de, D, T, const N: usize>(deserializer: D) -> Result<[T; N], D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        struct ArrayVisitor<T, const N: usize>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>, const N: usize> de::Visitor<'de> for ArrayVisitor<T, N> {
+            type Value = [T; N];
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an array of length {N}")
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let values = (0..N)
+                    .map(|i| seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self)))
+                    .collect::<Result<Vec<T>, _>>()?;
+
+                Ok(values.try_into().unwrap_or_else(|_| unreachable!()))
+            }
+        }
+
+        deserializer.deserialize_tuple(N, ArrayVisitor(PhantomData))
+    }
+}
+
+
+
+/// Cell of the grid with `W` spherical harmonic coefficients per color channel
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq, Copy)]
 #[derive(Serialize, Deserialize)]
-#[derive(Pod, Zeroable)]
-pub struct Cell {
+pub struct Cell<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
     pub density: f32,
-    pub sh_r: [f32; SPHERICAL_HARMONIC_WIDTH],
-    pub sh_g: [f32; SPHERICAL_HARMONIC_WIDTH],
-    pub sh_b: [f32; SPHERICAL_HARMONIC_WIDTH],
+    #[serde(with = "serde_array")]
+    pub sh_r: [f32; W],
+    #[serde(with = "serde_array")]
+    pub sh_g: [f32; W],
+    #[serde(with = "serde_array")]
+    pub sh_b: [f32; W],
 }
 
-impl Cell {
-    pub fn values_from_direction(direction: Vec3) -> [f32; SPHERICAL_HARMONIC_WIDTH] {
+// # Safety
+//
+// `Cell` is `repr(C)` and consists of `f32`s only, so it has no padding
+unsafe impl<const W: usize> Zeroable for Cell<W> {}
+unsafe impl<const W: usize> Pod for Cell<W> {}
+
+impl<const W: usize> Cell<W> {
+    /// Spherical harmonic degree of the cell
+    pub const DEGREE: u32 = match sh_degree(W) {
+        Some(degree) => degree,
+        None => panic!("number of spherical harmonic coefficients should be 1, 4, 9 or 16"),
+    };
+
+    /// Evaluates spherical harmonic basis functions of degrees 0 through 3
+    pub fn values_from_direction(direction: Vec3) -> [f32; W] {
         let [x, y, z] = direction.to_array();
+        let (xx, yy, zz) = (x * x, y * y, z * z);
 
-        [
-            0.28209479,
-            -0.48860251 * y,
-            0.48860251 * z,
-            -0.48860251 * x,
+        let values = [
+            SH_C0,
+            -SH_C1 * y,
+            SH_C1 * z,
+            -SH_C1 * x,
             1.0925484 * x * y,
             -1.0925484 * y * z,
-            0.31539157 * (2.0 * z * z - x * x - y * y),
+            0.31539157 * (2.0 * zz - xx - yy),
             -1.0925484 * x * z,
-            0.5462742 * (x * x - y * y),
-        ]
+            0.5462742 * (xx - yy),
+            -0.5900436 * y * (3.0 * xx - yy),
+            2.8906114 * x * y * z,
+            -0.4570458 * y * (4.0 * zz - xx - yy),
+            0.3731763 * z * (2.0 * zz - 3.0 * xx - 3.0 * yy),
+            -0.4570458 * x * (4.0 * zz - xx - yy),
+            1.4453057 * z * (xx - yy),
+            -0.5900436 * x * (xx - 3.0 * yy),
+        ];
+
+        // fails to compile for unsupported widths
+        let _ = Self::DEGREE;
+
+        array::from_fn(|i| values[i])
     }
 
-    pub fn eval_sh(
-        direction_values: &[f32; SPHERICAL_HARMONIC_WIDTH],
-        sh: &[f32; SPHERICAL_HARMONIC_WIDTH],
-    ) -> f32 {
-        let maybe_sum = sh.iter().zip(direction_values)
+    pub fn eval_sh(direction_values: &[f32; W], sh: &[f32; W]) -> f32 {
+        sh.iter().zip(direction_values)
             .map(|(&l, &r)| l * r)
-            .reduce(std::ops::Add::add);
-
-        // # Safety
-        // 
-        // `reduce` returns `None` only if iterator is empty,
-        // but it contains exactly `SPHERICAL_HARMONIC_WIDTH` (== 9) != 0 elements.
-        unsafe { maybe_sum.unwrap_unchecked() }
+            .sum()
     }
 
     pub fn eval(&self, direction: Vec3) -> CellValue {
@@ -62,6 +157,11 @@ impl Cell {
         ), self.density)
     }
 
+    /// Color of the constant spherical harmonic, which doesn't depend on the direction
+    pub fn diffuse(&self) -> Vec3 {
+        SH_C0 * vec3(self.sh_r[0], self.sh_g[0], self.sh_b[0])
+    }
+
     pub fn trilerp(values: [&Self; 8], [x, y, z]: [f32; 3]) -> Self {
         let (nx, ny, nz) = (1.0 - x, 1.0 - y, 1.0 - z);
 
@@ -70,9 +170,46 @@ impl Cell {
             + ((*values[0b100] * nz + *values[0b101] * z) * ny
             + (*values[0b110] * nz + *values[0b111] * z) * y) * x
     }
+
+    /// Cell with all channels set to the value
+    pub const fn splat(value: f32) -> Self {
+        Self { density: value, sh_r: [value; W], sh_g: [value; W], sh_b: [value; W] }
+    }
+
+    /// Combines channels of two cells one by one
+    pub fn zip_with(self, other: Self, mut f: impl FnMut(f32, f32) -> f32) -> Self {
+        Self {
+            density: f(self.density, other.density),
+            sh_r: array::from_fn(|i| f(self.sh_r[i], other.sh_r[i])),
+            sh_g: array::from_fn(|i| f(self.sh_g[i], other.sh_g[i])),
+            sh_b: array::from_fn(|i| f(self.sh_b[i], other.sh_b[i])),
+        }
+    }
+
+    /// Cell whose radiance in a direction is the radiance of this one in the inversely rotated direction
+    pub fn rotated(&self, rotation: &ShRotation) -> Self {
+        Self {
+            density: self.density,
+            sh_r: rotation.apply(&self.sh_r),
+            sh_g: rotation.apply(&self.sh_g),
+            sh_b: rotation.apply(&self.sh_b),
+        }
+    }
+
+    /// Changes the number of coefficients truncating higher degrees or padding them with zeros
+    pub fn with_width<const V: usize>(&self) -> Cell<V> {
+        let resize = |sh: &[f32; W]| array::from_fn(|i| sh.get(i).copied().unwrap_or(0.0));
+
+        Cell {
+            density: self.density,
+            sh_r: resize(&self.sh_r),
+            sh_g: resize(&self.sh_g),
+            sh_b: resize(&self.sh_b),
+        }
+    }
 }
 
-impl std::ops::Add for Cell {
+impl<const W: usize> std::ops::Add for Cell<W> {
     type Output = Self;
 
     fn add(self, rhs: Self) -> Self::Output {
@@ -85,7 +222,7 @@ impl std::ops::Add for Cell {
     }
 }
 
-impl std::ops::Mul<f32> for Cell {
+impl<const W: usize> std::ops::Mul<f32> for Cell<W> {
     type Output = Self;
 
     fn mul(self, rhs: f32) -> Self::Output {
@@ -98,16 +235,118 @@ impl std::ops::Mul<f32> for Cell {
     }
 }
 
-impl std::ops::Mul<Cell> for f32 {
-    type Output = Cell;
+impl<const W: usize> std::ops::Mul<Cell<W>> for f32 {
+    type Output = Cell<W>;
 
-    fn mul(self, rhs: Cell) -> Self::Output {
+    fn mul(self, rhs: Cell<W>) -> Self::Output {
         rhs.mul(self)
     }
 }
 
 
 
+/// Rotation of spherical harmonic coefficients of degrees 1 through 3.
+///
+/// Every band is rotated by its own matrix, found by fitting the rotated basis
+/// functions at fixed directions with least squares. A rotation maps every band onto itself,
+/// so the fit is exact up to rounding. It is used instead of the closed form band matrices,
+/// because it is built from [`Cell::values_from_direction`] itself and therefore follows
+/// the ordering and signs of the basis used by the renderers for any degree, which
+/// the closed form recurrences would have to restate band by band.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShRotation {
+    /// Block-diagonal matrix over all of the 16 coefficients
+    matrix: [[f32; 16]; 16],
+}
+
+impl ShRotation {
+    /// Number of directions the bands are fitted at
+    const N_DIRECTIONS: usize = 64;
+
+    pub fn new(rotation: Quat) -> Self {
+        // Fibonacci sphere
+        let directions = (0..Self::N_DIRECTIONS).map(|i| {
+            let z = 1.0 - (2 * i + 1) as f32 / Self::N_DIRECTIONS as f32;
+            let angle = i as f32 * std::f32::consts::PI * (3.0 - f32::sqrt(5.0));
+            let r = f32::sqrt(1.0 - z * z);
+
+            vec3(r * angle.cos(), r * angle.sin(), z)
+        }).collect::<Vec<_>>();
+
+        let basis = |direction| Cell::<16>::values_from_direction(direction).map(f64::from);
+
+        let original = directions.iter().map(|&d| basis(d)).collect::<Vec<_>>();
+        let rotated = directions.iter().map(|&d| basis(rotation.inverse() * d)).collect::<Vec<_>>();
+
+        let mut matrix = [[0.0; 16]; 16];
+        matrix[0][0] = 1.0;
+
+        for band in 1..=MAX_SPHERICAL_HARMONIC_DEGREE as usize {
+            let range = band * band..(band + 1) * (band + 1);
+            let n = range.len();
+
+            // normal equations `A^T A X = A^T B` for `A X = B`, where rows of `A` are
+            // the basis functions at a direction and rows of `B` are the rotated ones
+            let mut lhs = vec![vec![0.0; n]; n];
+            let mut rhs = vec![vec![0.0; n]; n];
+
+            for (a, b) in original.iter().zip(&rotated) {
+                let (a, b) = (&a[range.clone()], &b[range.clone()]);
+
+                for i in 0..n {
+                    for j in 0..n {
+                        lhs[i][j] += a[i] * a[j];
+                        rhs[i][j] += a[i] * b[j];
+                    }
+                }
+            }
+
+            // Gauss-Jordan elimination with partial pivoting
+            for column in 0..n {
+                let pivot = (column..n)
+                    .max_by(|&i, &j| lhs[i][column].abs().total_cmp(&lhs[j][column].abs()))
+                    .unwrap();
+
+                lhs.swap(column, pivot);
+                rhs.swap(column, pivot);
+
+                let scale = lhs[column][column].recip();
+
+                for j in 0..n {
+                    lhs[column][j] *= scale;
+                    rhs[column][j] *= scale;
+                }
+
+                for i in (0..n).filter(|&i| i != column) {
+                    let factor = lhs[i][column];
+
+                    for j in 0..n {
+                        lhs[i][j] -= factor * lhs[column][j];
+                        rhs[i][j] -= factor * rhs[column][j];
+                    }
+                }
+            }
+
+            // `Y(R^-1 d) = X^T Y(d)`, so `f(R^-1 d) = (X c)^T Y(d)` and the rotated coefficients are `X c`
+            for (i, row) in rhs.iter().enumerate() {
+                for (j, &value) in row.iter().enumerate() {
+                    matrix[range.start + i][range.start + j] = value as f32;
+                }
+            }
+        }
+
+        Self { matrix }
+    }
+
+    /// Rotates coefficients of a single color channel
+    pub fn apply<const W: usize>(&self, sh: &[f32; W]) -> [f32; W] {
+        array::from_fn(|i| (0..W).map(|j| self.matrix[i][j] * sh[j]).sum())
+    }
+}
+
+
+
+
 #[repr(C)]
 #[derive(Clone, Debug, Default, PartialEq, Copy)]
 #[derive(Serialize, Deserialize)]
@@ -125,131 +364,723 @@ impl CellValue {
 
 
 
+/// Single scalar channel of a [`Cell`], written as `density` or `sh_<color>[<coefficient>]`.
+#[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
+pub enum Channel {
+    #[default]
+    Density,
+    /// Spherical harmonic coefficient of the red (0), green (1) or blue (2) color
+    Sh { color: usize, coefficient: usize },
+}
+
+impl Channel {
+    pub const COLORS: [&'static str; 3] = ["r", "g", "b"];
+
+    /// Channel by its position in a cell with `width` spherical harmonic
+    /// coefficients: density first, then red, green and blue coefficients
+    pub const fn from_index(width: usize, index: usize) -> Self {
+        match index {
+            0 => Self::Density,
+            _ => Self::Sh { color: (index - 1) / width, coefficient: (index - 1) % width },
+        }
+    }
+
+    pub fn get<const W: usize>(self, cell: &Cell<W>) -> Option<f32> {
+        match self {
+            Self::Density => Some(cell.density),
+            Self::Sh { color, coefficient } => [&cell.sh_r, &cell.sh_g, &cell.sh_b]
+                .get(color)?.get(coefficient).copied(),
+        }
+    }
+
+    pub fn get_mut<const W: usize>(self, cell: &mut Cell<W>) -> Option<&mut f32> {
+        match self {
+            Self::Density => Some(&mut cell.density),
+            Self::Sh { color, coefficient } => [&mut cell.sh_r, &mut cell.sh_g, &mut cell.sh_b]
+                .into_iter().nth(color)?.get_mut(coefficient),
+        }
+    }
+}
+
+impl std::str::FromStr for Channel {
+    type Err = ChannelParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "density" {
+            return Ok(Self::Density);
+        }
+
+        let parse = || {
+            let (color, coefficient) = s.strip_prefix("sh_")?.strip_suffix(']')?.split_once('[')?;
+
+            Some(Self::Sh {
+                color: Self::COLORS.iter().position(|&name| name == color)?,
+                coefficient: coefficient.parse().ok()?,
+            })
+        };
+
+        parse().ok_or_else(|| ChannelParseError(s.to_owned()))
+    }
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Density => f.write_str("density"),
+            Self::Sh { color, coefficient } => write!(
+                f, "sh_{}[{coefficient}]", Self::COLORS.get(*color).unwrap_or(&"?"),
+            ),
+        }
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid channel '{0}', valid values are: 'density' and 'sh_<r|g|b>[<coefficient>]'")]
+pub struct ChannelParseError(pub String);
+
+
+
+/// Reconstruction filter of the grid. Cell values lie at the cell centers, cubic
+/// filters use the 4x4x4 cells around the position.
+#[repr(u32)]
 #[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Filtering {
-    Nearest,
+    Nearest = 0,
     #[default]
-    Trilinear,
+    Trilinear = 1,
+    /// Cubic B-spline, smooth but slightly blurring approximation of the cells
+    #[serde(rename = "bspline")]
+    BSpline = 2,
+    /// Catmull-Rom spline, which interpolates the cells but may overshoot
+    CatmullRom = 3,
 }
 
+impl Filtering {
+    /// Weights of the cells `floor(x) - 1` through `floor(x) + 2` for a coordinate
+    /// `x = pos * size - 0.5` with the fractional part `t`
+    pub fn weights(self, t: f32) -> [f32; 4] {
+        let (t2, t3) = (t * t, t * t * t);
 
+        match self {
+            Self::Nearest => [0.0, 1.0, 0.0, 0.0],
+            Self::Trilinear => [0.0, 1.0 - t, t, 0.0],
+            Self::BSpline => [
+                (1.0 - t).powi(3) / 6.0,
+                (3.0 * t3 - 6.0 * t2 + 4.0) / 6.0,
+                (-3.0 * t3 + 3.0 * t2 + 3.0 * t + 1.0) / 6.0,
+                t3 / 6.0,
+            ],
+            Self::CatmullRom => [
+                0.5 * (-t3 + 2.0 * t2 - t),
+                0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+                0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+                0.5 * (t3 - t2),
+            ],
+        }
+    }
 
-#[derive(Clone, Default, Debug, PartialEq)]
+    /// Derivatives of [`Filtering::weights`] by `t`
+    pub fn derivative_weights(self, t: f32) -> [f32; 4] {
+        let t2 = t * t;
+
+        match self {
+            Self::Nearest => [0.0; 4],
+            Self::Trilinear => [0.0, -1.0, 1.0, 0.0],
+            Self::BSpline => [
+                -0.5 * (1.0 - t) * (1.0 - t),
+                1.5 * t2 - 2.0 * t,
+                -1.5 * t2 + t + 0.5,
+                0.5 * t2,
+            ],
+            Self::CatmullRom => [
+                0.5 * (-3.0 * t2 + 4.0 * t - 1.0),
+                0.5 * (9.0 * t2 - 10.0 * t),
+                0.5 * (-9.0 * t2 + 8.0 * t + 1.0),
+                0.5 * (3.0 * t2 - 2.0 * t),
+            ],
+        }
+    }
+}
+
+impl std::str::FromStr for Filtering {
+    type Err = FilteringParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "nearest" => Self::Nearest,
+            "trilinear" => Self::Trilinear,
+            "bspline" => Self::BSpline,
+            "catmull_rom" => Self::CatmullRom,
+            _ => return Err(FilteringParseError(s.to_owned())),
+        })
+    }
+}
+
+impl std::fmt::Display for Filtering {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Nearest => "nearest",
+            Self::Trilinear => "trilinear",
+            Self::BSpline => "bspline",
+            Self::CatmullRom => "catmull_rom",
+        })
+    }
+}
+
+
+
+#[derive(Debug, Error)]
+#[error("invalid filtering '{0}', valid values are: 'nearest', 'trilinear', 'bspline' and 'catmull_rom'")]
+pub struct FilteringParseError(pub String);
+
+
+
+/// Cells read outside of the grid by the filters.
+#[repr(u32)]
+#[derive(Clone, Debug, Default, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
 #[derive(Serialize, Deserialize)]
-pub struct RadianceField {
-    pub size: usize,
-    pub cells: Vec<Cell>,
+#[serde(rename_all = "snake_case")]
+pub enum BorderMode {
+    /// Empty cells, so the field fades out over the half of the border cell
+    Zero = 0,
+    /// The nearest border cell
+    #[default]
+    Clamp = 1,
+    /// Grid reflected at its faces, the border cells are repeated
+    Mirror = 2,
+    /// Grid repeated periodically
+    Wrap = 3,
 }
 
-impl RadianceField {
-    pub const fn size(&self) -> usize {
-        self.size
+impl BorderMode {
+    /// Index of the cell read instead of the one at `index` along an axis
+    /// with `size` cells, `None` for an empty cell
+    pub fn resolve(self, index: isize, size: usize) -> Option<usize> {
+        let size = size as isize;
+
+        let index = match self {
+            Self::Zero => index,
+            Self::Clamp => index.clamp(0, size - 1),
+            Self::Mirror => match index.rem_euclid(2 * size) {
+                index if index < size => index,
+                index => 2 * size - 1 - index,
+            },
+            Self::Wrap => index.rem_euclid(size),
+        };
+
+        (0..size).contains(&index).then_some(index as usize)
     }
+}
 
-    /// Evaluates spherical harmonic by 3D index
-    pub fn eval_by_index(&self, index: [usize; 3], direction: Vec3) -> Option<CellValue> {
-        Some(self.get(index)?.eval(direction))
+impl std::str::FromStr for BorderMode {
+    type Err = BorderModeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "zero" => Self::Zero,
+            "clamp" => Self::Clamp,
+            "mirror" => Self::Mirror,
+            "wrap" => Self::Wrap,
+            _ => return Err(BorderModeParseError(s.to_owned())),
+        })
     }
+}
+
+impl std::fmt::Display for BorderMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Zero => "zero",
+            Self::Clamp => "clamp",
+            Self::Mirror => "mirror",
+            Self::Wrap => "wrap",
+        })
+    }
+}
+
 
-    /// Evaluates spherical harmonic by a position in the [0, 1]^3 cube
-    pub fn eval_near(&self, pos: Vec3, direction: Vec3) -> Option<CellValue> {
-        let index = (self.size as f32 * pos)
-            .to_array()
-            .map(|f| f as usize);
 
-        self.eval_by_index(index, direction)
+#[derive(Debug, Error)]
+#[error("invalid border mode '{0}', valid values are: 'zero', 'clamp', 'mirror' and 'wrap'")]
+pub struct BorderModeParseError(pub String);
+
+
+
+/// Lower corner of the 2x2x2 cell neighbourhood of the position in the [0, 1]^3 cube
+/// and the position in it. Cell centers lie at `(index + 0.5) / size`.
+fn filter_base(size: [usize; 3], pos: Vec3) -> Option<([isize; 3], Vec3)> {
+    if pos.cmplt(Vec3::ZERO).any() || pos.cmpgt(Vec3::ONE).any() {
+        return None;
     }
 
-    pub fn eval_trilinear(&self, mut pos: Vec3, direction: Vec3) -> Option<CellValue> {
-        const EPS: f32 = 0.01;
+    let pos = pos * RadianceField::size_vec(size) - 0.5;
 
-        if pos.x < EPS || pos.y < EPS || pos.z < EPS
-            || pos.x > 1.0 - EPS || pos.y > 1.0 - EPS || pos.z > 1.0 - EPS
-        {
-            return None;
+    Some((pos.floor().to_array().map(|i| i as isize), pos.fract_gl()))
+}
+
+/// Sums the 4x4x4 cells starting one cell before `base` with separable weights
+/// along X, Y and Z axes, empty cells outside of the grid are skipped
+fn filter_cells<G: Grid<W> + ?Sized, const W: usize>(
+    grid: &G, base: [isize; 3], weights: [[f32; 4]; 3], border: BorderMode,
+) -> Option<Cell<W>> {
+    let size = grid.size();
+    let mut sum = Cell::splat(0.0);
+
+    let indices: [[Option<usize>; 4]; 3] = array::from_fn(|axis| {
+        array::from_fn(|i| border.resolve(base[axis] + i as isize - 1, size[axis]))
+    });
+
+    for (y, weight_y) in weights[1].iter().enumerate() {
+        for (z, weight_z) in weights[2].iter().enumerate() {
+            for (x, weight_x) in weights[0].iter().enumerate() {
+                let weight = weight_x * weight_y * weight_z;
+
+                let (Some(ix), Some(iy), Some(iz)) = (indices[0][x], indices[1][y], indices[2][z]) else {
+                    continue;
+                };
+
+                if weight != 0.0 {
+                    sum = sum + grid.cell([ix, iy, iz])? * weight;
+                }
+            }
         }
+    }
+
+    Some(sum)
+}
 
-        pos *= self.size() as f32;
-        
-        let lo_index = [
-            pos.x.floor() as usize,
-            pos.y.floor() as usize,
-            pos.z.floor() as usize,
-        ];
 
-        if lo_index.iter().any(|&i| i + 1 >= self.size()) {
+
+/// Read access to a grid of [`Cell`]s independent of the way they are stored.
+pub trait Grid<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
+    /// Number of cells along X, Y and Z axes of the grid
+    fn size(&self) -> [usize; 3];
+
+    /// Decodes the cell at the 3D index
+    fn cell(&self, index: [usize; 3]) -> Option<Cell<W>>;
+
+    /// Evaluates spherical harmonic by 3D index
+    fn eval_by_index(&self, index: [usize; 3], direction: Vec3) -> Option<CellValue> {
+        Some(self.cell(index)?.eval(direction))
+    }
+
+    /// Cell containing a position in the [0, 1]^3 cube
+    fn sample_near(&self, pos: Vec3) -> Option<Cell<W>> {
+        if pos.cmplt(Vec3::ZERO).any() || pos.cmpgt(Vec3::ONE).any() {
             return None;
         }
 
+        let size = self.size();
+
+        let pos = (RadianceField::size_vec(size) * pos).to_array();
+
+        // the far faces belong to the last cells
+        let index = array::from_fn(|axis| (pos[axis] as usize).min(size[axis] - 1));
+
+        self.cell(index)
+    }
+
+    /// Cell blended from the 2x2x2 cells around a position in the [0, 1]^3 cube
+    fn sample_trilinear(&self, pos: Vec3, border: BorderMode) -> Option<Cell<W>> {
+        let size = self.size();
+        let (lo_index, fract) = filter_base(size, pos)?;
+
+        let [x, y, z]: [[Option<usize>; 2]; 3] = array::from_fn(|axis| {
+            [0, 1].map(|offset| border.resolve(lo_index[axis] + offset, size[axis]))
+        });
+
         let indices = [
-            [lo_index[0], lo_index[1], lo_index[2]],
-            [lo_index[0], lo_index[1], lo_index[2] + 1],
-            [lo_index[0], lo_index[1] + 1, lo_index[2]],
-            [lo_index[0], lo_index[1] + 1, lo_index[2] + 1],
-            [lo_index[0] + 1, lo_index[1], lo_index[2]],
-            [lo_index[0] + 1, lo_index[1], lo_index[2] + 1],
-            [lo_index[0] + 1, lo_index[1] + 1, lo_index[2]],
-            [lo_index[0] + 1, lo_index[1] + 1, lo_index[2] + 1],
+            [x[0], y[0], z[0]],
+            [x[0], y[0], z[1]],
+            [x[0], y[1], z[0]],
+            [x[0], y[1], z[1]],
+            [x[1], y[0], z[0]],
+            [x[1], y[0], z[1]],
+            [x[1], y[1], z[0]],
+            [x[1], y[1], z[1]],
         ];
 
-        // # Safety
-        // 
-        // We manually checked that index in bounds,
-        // so it is safe to get without checks
-        let values = indices.map(|i| unsafe { self.get_unchecked(i) });
-        let coeffs = pos.fract_gl().to_array();
-        let cell = Cell::trilerp(values, coeffs);
+        let mut values = [Cell::splat(0.0); 8];
 
-        Some(cell.eval(direction))
+        for (value, [x, y, z]) in values.iter_mut().zip(indices) {
+            if let (Some(x), Some(y), Some(z)) = (x, y, z) {
+                *value = self.cell([x, y, z])?;
+            }
+        }
+
+        Some(Cell::trilerp(values.each_ref(), fract.to_array()))
     }
 
-    /// Evaluates spherical harmonic on the 3D cube [0, 1]^3
-    pub fn eval(&self, pos: Vec3, direction: Vec3, filtering: Filtering) -> Option<CellValue> {
+    /// Cell filtered with a cubic filter, see [`Filtering::weights`]
+    fn sample_cubic(&self, pos: Vec3, filtering: Filtering, border: BorderMode) -> Option<Cell<W>> {
+        let (base, fract) = filter_base(self.size(), pos)?;
+        let weights = fract.to_array().map(|t| filtering.weights(t));
+
+        filter_cells(self, base, weights, border)
+    }
+
+    /// Filtered cell at a position in the [0, 1]^3 cube
+    fn sample(&self, pos: Vec3, filtering: Filtering, border: BorderMode) -> Option<Cell<W>> {
         match filtering {
-            Filtering::Nearest => self.eval_near(pos, direction),
-            Filtering::Trilinear => self.eval_trilinear(pos, direction),
+            Filtering::Nearest => self.sample_near(pos),
+            Filtering::Trilinear => self.sample_trilinear(pos, border),
+            Filtering::BSpline | Filtering::CatmullRom => self.sample_cubic(pos, filtering, border),
         }
     }
 
+    /// Evaluates spherical harmonic on the 3D cube [0, 1]^3
+    fn eval(&self, pos: Vec3, direction: Vec3, filtering: Filtering, border: BorderMode) -> Option<CellValue> {
+        Some(self.sample(pos, filtering, border)?.eval(direction))
+    }
+
+    /// Gradient of the filtered density by the position in the [0, 1]^3 cube,
+    /// zero for the nearest filtering
+    fn density_gradient(&self, pos: Vec3, filtering: Filtering, border: BorderMode) -> Option<Vec3> {
+        let size = self.size();
+        let (base, fract) = filter_base(size, pos)?;
+
+        let weights = fract.to_array().map(|t| filtering.weights(t));
+        let derivatives = fract.to_array().map(|t| filtering.derivative_weights(t));
+
+        let derivative_along = |axis: usize| {
+            let mut weights = weights;
+            weights[axis] = derivatives[axis];
+
+            Some(filter_cells(self, base, weights, border)?.density)
+        };
+
+        let gradient = vec3(derivative_along(0)?, derivative_along(1)?, derivative_along(2)?);
+
+        // derivatives are by the position in cells
+        Some(gradient * RadianceField::size_vec(size))
+    }
+}
+
+
+
+/// Grid adapter which changes the spherical harmonic degree of the cells on the fly,
+/// see [`Cell::with_width`]
+#[derive(Clone, Debug, PartialEq, Copy)]
+pub struct ResizedGrid<'g, G, const W: usize>(pub &'g G);
+
+impl<G: Grid<W>, const W: usize, const V: usize> Grid<V> for ResizedGrid<'_, G, W> {
+    fn size(&self) -> [usize; 3] {
+        self.0.size()
+    }
+
+    fn cell(&self, index: [usize; 3]) -> Option<Cell<V>> {
+        Some(self.0.cell(index)?.with_width())
+    }
+}
+
+
+
+#[derive(Clone, Default, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct RadianceField<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
+    /// Number of cells along X, Y and Z axes
+    pub size: [usize; 3],
+    pub cells: Vec<Cell<W>>,
+}
+
+impl RadianceField {
+    /// Total number of cells in a grid of the given size
+    pub const fn volume([x, y, z]: [usize; 3]) -> usize {
+        x * y * z
+    }
+
+    pub fn size_vec(size: [usize; 3]) -> Vec3 {
+        Vec3::from_array(size.map(|s| s as f32))
+    }
+
+    pub fn contains(size: [usize; 3], index: [usize; 3]) -> bool {
+        index.iter().zip(size).all(|(&i, size)| i < size)
+    }
+
     /// Calculates index in 3D array
-    pub const fn index_of(size: usize, [x, y, z]: [usize; 3]) -> usize {
-        x + size * z + size * size * y
+    pub const fn index_of([size_x, _, size_z]: [usize; 3], [x, y, z]: [usize; 3]) -> usize {
+        x + size_x * z + size_x * size_z * y
+    }
+
+    /// Inverse of [`RadianceField::index_of`]
+    pub const fn index_from_flat([size_x, _, size_z]: [usize; 3], index: usize) -> [usize; 3] {
+        [index % size_x, index / (size_x * size_z), index / size_x % size_z]
     }
 
-    pub fn get(&self, index: [usize; 3]) -> Option<&Cell> {
-        self.cells.get(Self::index_of(self.size(), index))
+}
+
+impl<const W: usize> RadianceField<W> {
+    /// Spherical harmonic degree of the field
+    pub const DEGREE: u32 = Cell::<W>::DEGREE;
+
+    pub const fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    /// Collects all cells of the grid
+    pub fn from_grid(grid: &(impl Grid<W> + Sync)) -> Self {
+        let size = grid.size();
+        let mut cells = Vec::with_capacity(RadianceField::volume(size));
+
+        (0..RadianceField::volume(size))
+            .into_par_iter()
+            .map(|i| grid.cell(RadianceField::index_from_flat(size, i)).unwrap())
+            .collect_into_vec(&mut cells);
+
+        Self { size, cells }
+    }
+
+    /// Changes the spherical harmonic degree truncating higher degrees or padding them with zeros
+    pub fn with_width<const V: usize>(&self) -> RadianceField<V> {
+        RadianceField {
+            size: self.size,
+            cells: self.cells.par_iter().map(Cell::with_width).collect(),
+        }
+    }
+
+    pub fn get(&self, index: [usize; 3]) -> Option<&Cell<W>> {
+        self.cells.get(RadianceField::index_of(self.size, index))
     }
 
     /// # Safety
     /// 
     /// See `std::slice::get_unchecked`.
-    pub unsafe fn get_unchecked(&self, index: [usize; 3]) -> &Cell {
-        self.cells.get_unchecked(Self::index_of(self.size(), index))
+    pub unsafe fn get_unchecked(&self, index: [usize; 3]) -> &Cell<W> {
+        self.cells.get_unchecked(RadianceField::index_of(self.size, index))
     }
 
-    pub fn get_mut(&mut self, index: [usize; 3]) -> Option<&mut Cell> {
-        self.cells.get_mut(Self::index_of(self.size, index))
+    pub fn get_mut(&mut self, index: [usize; 3]) -> Option<&mut Cell<W>> {
+        self.cells.get_mut(RadianceField::index_of(self.size, index))
     }
 
     /// # Safety
     /// 
     /// See `std::slice::get_unchecked_mut`
-    pub unsafe fn get_unchecked_mut(&mut self, index: [usize; 3]) -> &mut Cell {
-        self.cells.get_unchecked_mut(Self::index_of(self.size, index))
+    pub unsafe fn get_unchecked_mut(&mut self, index: [usize; 3]) -> &mut Cell<W> {
+        self.cells.get_unchecked_mut(RadianceField::index_of(self.size, index))
+    }
+}
+
+impl<const W: usize> Grid<W> for RadianceField<W> {
+    fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    fn cell(&self, index: [usize; 3]) -> Option<Cell<W>> {
+        if !RadianceField::contains(self.size, index) {
+            return None;
+        }
+
+        self.get(index).copied()
     }
 }
 
-impl std::ops::Index<[usize; 3]> for RadianceField {
-    type Output = Cell;
+impl<const W: usize> std::ops::Index<[usize; 3]> for RadianceField<W> {
+    type Output = Cell<W>;
 
     fn index(&self, index: [usize; 3]) -> &Self::Output {
         self.get(index).unwrap()
     }
 }
 
-impl std::ops::IndexMut<[usize; 3]> for RadianceField {
+impl<const W: usize> std::ops::IndexMut<[usize; 3]> for RadianceField<W> {
     fn index_mut(&mut self, index: [usize; 3]) -> &mut Self::Output {
         self.get_mut(index).unwrap()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_cell_matches_inversely_rotated_direction() {
+        let coefficients = |offset: f32| array::from_fn(|i| f32::sin(offset + 1.7 * i as f32));
+        let cell = Cell::<16> {
+            density: 1.0, sh_r: coefficients(0.0), sh_g: coefficients(1.0), sh_b: coefficients(2.0),
+        };
+
+        let rotations = [
+            Quat::from_rotation_x(0.3),
+            Quat::from_rotation_y(2.0),
+            Quat::from_axis_angle(vec3(1.0, -2.0, 0.5).normalize(), 4.0),
+        ];
+
+        for rotation in rotations {
+            let rotated = cell.rotated(&ShRotation::new(rotation));
+
+            for i in 0..32 {
+                let direction = vec3(f32::cos(i as f32), f32::sin(0.7 * i as f32), 0.3).normalize();
+
+                let expected = cell.eval(rotation.inverse() * direction).color;
+                let actual = rotated.eval(direction).color;
+
+                assert!(actual.abs_diff_eq(expected, 5e-3), "{actual} instead of {expected} in {direction}");
+            }
+        }
+    }
+
+    /// Field of the density growing along X and Y axes
+    fn linear_field(size: [usize; 3]) -> RadianceField<1> {
+        let cells = (0..RadianceField::volume(size))
+            .map(|i| {
+                let [x, y, _] = RadianceField::index_from_flat(size, i);
+                Cell { density: x as f32 + 2.0 * y as f32, ..Cell::zeroed() }
+            })
+            .collect();
+
+        RadianceField { size, cells }
+    }
+
+    #[test]
+    fn filter_weights_sum_to_one_and_match_their_derivatives() {
+        let h = 1e-3;
+
+        for filtering in [Filtering::Nearest, Filtering::Trilinear, Filtering::BSpline, Filtering::CatmullRom] {
+            for i in 0..=8 {
+                let t = i as f32 / 8.0;
+
+                let sum = filtering.weights(t).iter().sum::<f32>();
+                let derivative_sum = filtering.derivative_weights(t).iter().sum::<f32>();
+
+                assert!((sum - 1.0).abs() < 1e-6, "{filtering} weights at {t} sum to {sum}");
+                assert!(derivative_sum.abs() < 1e-6, "{filtering} derivatives at {t} sum to {derivative_sum}");
+
+                let (lo, hi) = (filtering.weights((t - h).max(0.0)), filtering.weights((t + h).min(1.0)));
+                let step = (t + h).min(1.0) - (t - h).max(0.0);
+
+                for k in 0..4 {
+                    let expected = (hi[k] - lo[k]) / step;
+                    let derivative = filtering.derivative_weights(t)[k];
+
+                    assert!(
+                        (derivative - expected).abs() < 1e-2,
+                        "{filtering} weight {k} at {t}: {derivative} != {expected}",
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cubic_filters_reproduce_cells_and_linear_fields() {
+        let field = linear_field([6; 3]);
+
+        // Catmull-Rom passes through the cells, the B-spline blurs them
+        let mut bumped = field.clone();
+        bumped[[3, 3, 3]].density += 6.0;
+
+        let center = Vec3::splat(3.5 / 6.0);
+        let sample = |filtering| bumped.sample(center, filtering, BorderMode::Clamp).unwrap().density;
+
+        assert!((sample(Filtering::CatmullRom) - 15.0).abs() < 1e-4);
+        assert!((sample(Filtering::BSpline) - (9.0 + 6.0 * (4.0f32 / 6.0).powi(3))).abs() < 1e-4);
+
+        // both of them keep linear functions between the cells away from the borders
+        for filtering in [Filtering::Trilinear, Filtering::BSpline, Filtering::CatmullRom] {
+            for i in 0..8 {
+                let pos = vec3(0.3 + 0.05 * i as f32, 0.62 - 0.03 * i as f32, 0.5);
+                let cells = pos * 6.0 - 0.5;
+
+                let density = field.sample(pos, filtering, BorderMode::Clamp).unwrap().density;
+                let expected = cells.x + 2.0 * cells.y;
+
+                assert!((density - expected).abs() < 1e-4, "{filtering} at {pos}: {density} != {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn density_gradient_matches_finite_differences() {
+        let field = crate::procedural::Procedural::Noise { density: 10.0, frequency: 3, seed: 3 }.generate([8; 3]);
+        let h = 1e-3;
+
+        // positions off the cell centers, where the trilinear gradient jumps
+        let positions = (0..16).map(|i| vec3(
+            0.2 + 0.037 * i as f32,
+            0.71 - 0.029 * i as f32,
+            0.13 + 0.047 * i as f32,
+        ));
+
+        for filtering in [Filtering::Trilinear, Filtering::BSpline, Filtering::CatmullRom] {
+            for pos in positions.clone() {
+                let gradient = field.density_gradient(pos, filtering, BorderMode::Clamp).unwrap();
+
+                let density = |pos| field.sample(pos, filtering, BorderMode::Clamp).unwrap().density;
+                let expected = Vec3::from_array(array::from_fn(|axis| {
+                    let offset = Vec3::AXES[axis] * h;
+                    (density(pos + offset) - density(pos - offset)) / (2.0 * h)
+                }));
+
+                let tolerance = 1e-2 * expected.length() + 0.1;
+                assert!(gradient.abs_diff_eq(expected, tolerance), "{filtering} at {pos}: {gradient} != {expected}");
+            }
+        }
+
+        // nearest filtering is piecewise constant
+        assert_eq!(field.density_gradient(Vec3::splat(0.3), Filtering::Nearest, BorderMode::Clamp), Some(Vec3::ZERO));
+    }
+
+    #[test]
+    fn border_modes_resolve_indices_outside_of_the_grid() {
+        let indices = [-5, -4, -1, 0, 3, 4, 7, 8];
+
+        let expected = [
+            (BorderMode::Zero, [None, None, None, Some(0), Some(3), None, None, None]),
+            (BorderMode::Clamp, [0, 0, 0, 0, 3, 3, 3, 3].map(Some)),
+            (BorderMode::Mirror, [3, 3, 0, 0, 3, 3, 0, 0].map(Some)),
+            (BorderMode::Wrap, [3, 0, 3, 0, 3, 0, 3, 0].map(Some)),
+        ];
+
+        for (border, expected) in expected {
+            assert_eq!(indices.map(|index| border.resolve(index, 4)), expected, "{border}");
+        }
+    }
+
+    #[test]
+    fn border_modes_apply_at_the_faces() {
+        let densities = [1.0, 2.0, 4.0, 8.0];
+        let field = RadianceField {
+            size: [4, 1, 1],
+            cells: densities.map(|density| Cell::<1> { density, ..Cell::zeroed() }).to_vec(),
+        };
+
+        // the faces lie halfway between the border cells and the ones read past them
+        let expected = [
+            (BorderMode::Zero, [0.5, 4.0]),
+            (BorderMode::Clamp, [1.0, 8.0]),
+            (BorderMode::Mirror, [1.0, 8.0]),
+            (BorderMode::Wrap, [4.5, 4.5]),
+        ];
+
+        for (border, expected) in expected {
+            let density = |x: f32| field.sample(vec3(x, 0.5, 0.5), Filtering::Trilinear, border).unwrap().density;
+
+            assert_eq!([density(0.0), density(1.0)], expected, "{border}");
+            assert_eq!(field.sample(vec3(1.0, 0.5, 0.5), Filtering::Nearest, border).unwrap().density, 8.0);
+            assert!(field.sample(vec3(1.01, 0.5, 0.5), Filtering::Trilinear, border).is_none());
+            assert!(field.sample(vec3(-0.01, 0.5, 0.5), Filtering::CatmullRom, border).is_none());
+        }
+
+        let field = crate::procedural::Procedural::Noise { density: 10.0, frequency: 2, seed: 5 }.generate([6; 3]);
+
+        for filtering in [Filtering::Trilinear, Filtering::BSpline, Filtering::CatmullRom] {
+            for i in 0..8 {
+                let (y, z) = (0.1 + 0.1 * i as f32, 0.85 - 0.09 * i as f32);
+
+                // opposite faces of a wrapped grid read the same cells
+                let sample = |x| field.sample(vec3(x, y, z), filtering, BorderMode::Wrap).unwrap().density;
+                assert!((sample(0.0) - sample(1.0)).abs() < 1e-4, "{filtering}: {} != {}", sample(0.0), sample(1.0));
+
+                // mirrored grid is symmetric about its faces
+                for x in [0.0, 1.0] {
+                    let gradient = field.density_gradient(vec3(x, y, z), filtering, BorderMode::Mirror).unwrap();
+                    assert!(gradient.x.abs() < 1e-3, "{filtering} gradient {gradient} at the face {x}");
+                }
+            }
+        }
+    }
+}
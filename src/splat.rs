@@ -0,0 +1,494 @@
+use crate::{
+    geometry::Aabb,
+    ply::{Ply, PlyError},
+    spherical::{sh_degree, Cell, Grid, RadianceField, SH_C0},
+};
+use glam::*;
+use rayon::prelude::*;
+use std::{
+    f32::consts::PI,
+    io::{BufWriter, Write},
+};
+use thiserror::Error;
+
+
+
+#[derive(Debug, Error)]
+pub enum SplatError {
+    #[error(transparent)]
+    Ply(#[from] PlyError),
+
+    #[error("Gaussian splats have {0} 'f_rest_*' coefficients, which does not match \
+             a spherical harmonic degree from 0 to 3")]
+    BadShCoefficients(usize),
+
+    #[error("there are no Gaussian splats in the file")]
+    Empty,
+}
+
+
+
+/// 3D Gaussians with colors given by spherical harmonics in the convention of the renderer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GaussianCloud {
+    pub positions: Vec<Vec3>,
+    /// Standard deviations along the local axes
+    pub scales: Vec<Vec3>,
+    pub rotations: Vec<Quat>,
+    /// Opacities at the centers in the [0, 1] range
+    pub opacities: Vec<f32>,
+    /// Coefficients of every Gaussian: red, then green, then blue ones
+    pub sh: Vec<f32>,
+    /// Number of spherical harmonic coefficients per color channel
+    pub sh_width: usize,
+}
+
+impl GaussianCloud {
+    /// Standard deviation of a Gaussian replacing a cube in units of its edge. The optical depth
+    /// of the cube becomes the central depth of the Gaussian, so `1/sqrt(2π)` makes the total
+    /// density of the Gaussian `depth·2πσ²` equal to the one of the cube.
+    pub const CELL_SIGMA: f32 = 0.398_942_3;
+
+    /// Turns every cluster of `cluster_size`^3 cells into an isotropic Gaussian. Only cells with
+    /// density above the threshold contribute, their colors are averaged with density weights
+    /// and the opacity is derived from the mean density times the cluster size.
+    pub fn from_grid<const W: usize>(
+        grid: &(impl Grid<W> + Sync), bounding_box: Aabb, density_threshold: f32, cluster_size: usize,
+    ) -> Self {
+        let size = grid.size();
+        let cell_size = (bounding_box.hi - bounding_box.lo) / RadianceField::size_vec(size);
+        let cluster_size = cluster_size.max(1);
+
+        let n_clusters = size.map(|len| len.div_ceil(cluster_size));
+        let cluster_volume = (cluster_size * cluster_size * cluster_size) as f32;
+
+        // edge of the cube with the same volume as the cluster
+        let edge = cluster_size as f32 * cell_size.element_product().cbrt();
+
+        let clusters = (0..RadianceField::volume(n_clusters))
+            .into_par_iter()
+            .filter_map(|i| {
+                let cluster = RadianceField::index_from_flat(n_clusters, i);
+
+                let mut density_sum = 0.0;
+                let mut position_sum = Vec3::ZERO;
+                let mut sh_sum = vec![0.0; 3 * W];
+
+                for offset in 0..cluster_size * cluster_size * cluster_size {
+                    let offset = [
+                        offset % cluster_size,
+                        offset / cluster_size % cluster_size,
+                        offset / (cluster_size * cluster_size),
+                    ];
+                    let index = std::array::from_fn(|axis| cluster[axis] * cluster_size + offset[axis]);
+
+                    let Some(cell) = grid.cell(index) else {
+                        continue;
+                    };
+
+                    if cell.density <= density_threshold {
+                        continue;
+                    }
+
+                    let center = bounding_box.lo + cell_size * (Vec3::from_array(index.map(|i| i as f32)) + 0.5);
+
+                    density_sum += cell.density;
+                    position_sum += cell.density * center;
+
+                    for (sum, &value) in sh_sum.iter_mut().zip(cell.sh_r.iter().chain(&cell.sh_g).chain(&cell.sh_b)) {
+                        *sum += cell.density * value;
+                    }
+                }
+
+                if density_sum <= 0.0 {
+                    return None;
+                }
+
+                let opacity = 1.0 - f32::exp(-density_sum / cluster_volume * edge);
+
+                Some((
+                    position_sum / density_sum,
+                    opacity,
+                    sh_sum.into_iter().map(|sum| sum / density_sum).collect::<Vec<_>>(),
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            positions: clusters.iter().map(|(position, _, _)| *position).collect(),
+            scales: vec![Vec3::splat(Self::CELL_SIGMA * edge); clusters.len()],
+            rotations: vec![Quat::IDENTITY; clusters.len()],
+            opacities: clusters.iter().map(|(_, opacity, _)| *opacity).collect(),
+            sh: clusters.into_iter().flat_map(|(_, _, sh)| sh).collect(),
+            sh_width: W,
+        }
+    }
+
+    /// Whether the vertices of the PLY file have the color and opacity of Gaussian splats
+    pub fn is_splat_ply(ply: &Ply) -> bool {
+        ply.element("vertex").is_ok_and(|vertex| {
+            ["f_dc_0", "opacity"].iter().all(|name| vertex.scalar(name).is_ok())
+        })
+    }
+
+    /// Reads Gaussians from the vertices of a 3D Gaussian splatting PLY file
+    pub fn from_ply(ply: &Ply) -> Result<Self, SplatError> {
+        let vertex = ply.element("vertex")?;
+
+        if vertex.count == 0 {
+            return Err(SplatError::Empty);
+        }
+
+        let n_rest = vertex.properties.iter()
+            .filter(|property| property.name.starts_with("f_rest_"))
+            .count();
+
+        let width = n_rest / 3 + 1;
+
+        if n_rest % 3 != 0 || sh_degree(width).is_none() {
+            return Err(SplatError::BadShCoefficients(n_rest));
+        }
+
+        let columns = |names: &[&str]| -> Result<Vec<&[f32]>, PlyError> {
+            names.iter().map(|name| vertex.scalar(name)).collect()
+        };
+
+        let position = columns(&["x", "y", "z"])?;
+        let scale = columns(&["scale_0", "scale_1", "scale_2"])?;
+        let rotation = columns(&["rot_0", "rot_1", "rot_2", "rot_3"])?;
+        let opacity = vertex.scalar("opacity")?;
+        let dc = columns(&["f_dc_0", "f_dc_1", "f_dc_2"])?;
+        let rest = (0..n_rest)
+            .map(|i| vertex.scalar(&format!("f_rest_{i}")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let n = vertex.count;
+
+        Ok(Self {
+            positions: (0..n).map(|i| vec3(position[0][i], position[1][i], position[2][i])).collect(),
+            scales: (0..n).map(|i| vec3(scale[0][i], scale[1][i], scale[2][i]).exp()).collect(),
+            rotations: (0..n)
+                .map(|i| Quat::from_xyzw(rotation[1][i], rotation[2][i], rotation[3][i], rotation[0][i]))
+                .map(|rotation| match rotation.length() > 0.0 {
+                    true => rotation.normalize(),
+                    false => Quat::IDENTITY,
+                })
+                .collect(),
+            opacities: opacity.iter().map(|&logit| 1.0 / (1.0 + f32::exp(-logit))).collect(),
+            sh: (0..n)
+                .flat_map(|i| {
+                    let (dc, rest) = (&dc, &rest);
+
+                    (0..3).flat_map(move |color| {
+                        std::iter::once(dc[color][i] + 0.5 / SH_C0)
+                            .chain((0..width - 1).map(move |j| rest[color * (width - 1) + j][i]))
+                    })
+                })
+                .collect(),
+            sh_width: width,
+        })
+    }
+
+    /// Box containing the Gaussians up to three standard deviations
+    pub fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(self.positions.iter().zip(&self.scales).flat_map(|(&position, &scale)| {
+            let radius = Vec3::splat(3.0 * scale.max_element());
+            [position - radius, position + radius]
+        }))
+    }
+
+    /// Accumulates the Gaussians into a grid of the size placed into the bounding box.
+    ///
+    /// A Gaussian becomes a blob of density whose optical depth through its center equals
+    /// the one of its opacity. The blob is widened by the cell footprint keeping its total
+    /// density, so that small Gaussians are not lost between the cell centers. Colors are
+    /// averaged with density weights, coefficients above `W` are dropped.
+    pub fn rasterize<const W: usize>(&self, size: [usize; 3], bounding_box: Aabb) -> RadianceField<W> {
+        let cell_size = (bounding_box.hi - bounding_box.lo) / RadianceField::size_vec(size);
+        let [sx, sy, sz] = size;
+
+        // Gaussians too transparent to be seen in an 8-bit image are skipped
+        const MIN_OPACITY: f32 = 1.0 / 255.0;
+
+        struct Blob {
+            /// Index of the Gaussian
+            index: usize,
+            center: Vec3,
+            inverse_covariance: Mat3,
+            peak: f32,
+            lo: [usize; 3],
+            hi: [usize; 3],
+        }
+
+        let blobs = (0..self.len())
+            .into_par_iter()
+            .filter(|&i| self.opacities[i] >= MIN_OPACITY)
+            .filter_map(|i| {
+                let rotation = Mat3::from_quat(self.rotations[i]);
+                let scale = self.scales[i];
+                let covariance = rotation * Mat3::from_diagonal(scale * scale) * rotation.transpose();
+
+                // variance of the uniform distribution over a cell
+                let widened = covariance + Mat3::from_diagonal(cell_size * cell_size / 12.0);
+
+                let opacity = self.opacities[i].min(0.99);
+                let depth = -f32::ln(1.0 - opacity);
+                let peak = depth / (f32::sqrt(2.0 * PI) * scale.element_product().cbrt())
+                    * f32::sqrt(covariance.determinant() / widened.determinant());
+
+                let variance = vec3(widened.x_axis.x, widened.y_axis.y, widened.z_axis.z);
+                let radius = 3.0 * Vec3::from_array(variance.to_array().map(f32::sqrt));
+                let to_index = |point: Vec3| (point - bounding_box.lo) / cell_size - 0.5;
+
+                let lo = to_index(self.positions[i] - radius).ceil().max(Vec3::ZERO);
+                let hi = to_index(self.positions[i] + radius).floor() + 1.0;
+                let hi = hi.min(RadianceField::size_vec(size));
+
+                (lo.cmplt(hi).all() && peak.is_finite()).then(|| Blob {
+                    index: i,
+                    center: self.positions[i],
+                    inverse_covariance: widened.inverse(),
+                    peak,
+                    lo: lo.to_array().map(|x| x as usize),
+                    hi: hi.to_array().map(|x| x as usize),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // cells are stored in layers along Y, so every layer is filled independently
+        let mut layers = vec![Vec::new(); sy];
+
+        for (i, blob) in blobs.iter().enumerate() {
+            for layer in &mut layers[blob.lo[1]..blob.hi[1]] {
+                layer.push(i);
+            }
+        }
+
+        let width = self.sh_width;
+
+        let cells = layers.into_par_iter()
+            .enumerate()
+            .flat_map_iter(|(y, layer)| {
+                let mut densities = vec![0.0; sx * sz];
+                let mut sh = vec![[[0.0; W]; 3]; sx * sz];
+
+                for blob in layer.into_iter().map(|i| &blobs[i]) {
+                    let coefficients = &self.sh[3 * width * blob.index..3 * width * (blob.index + 1)];
+
+                    for z in blob.lo[2]..blob.hi[2] {
+                        for x in blob.lo[0]..blob.hi[0] {
+                            let index = vec3(x as f32, y as f32, z as f32);
+                            let offset = bounding_box.lo + cell_size * (index + 0.5) - blob.center;
+                            let density = blob.peak
+                                * f32::exp(-0.5 * offset.dot(blob.inverse_covariance * offset));
+
+                            let cell = x + sx * z;
+                            densities[cell] += density;
+
+                            for (color, sum) in sh[cell].iter_mut().enumerate() {
+                                for (j, sum) in sum.iter_mut().enumerate().take(width) {
+                                    *sum += density * coefficients[color * width + j];
+                                }
+                            }
+                        }
+                    }
+                }
+
+                densities.into_iter().zip(sh).map(|(density, [sh_r, sh_g, sh_b])| {
+                    let average = |sh: [f32; W]| match density > 0.0 {
+                        true => sh.map(|sum| sum / density),
+                        false => [0.0; W],
+                    };
+
+                    Cell { density, sh_r: average(sh_r), sh_g: average(sh_g), sh_b: average(sh_b) }
+                }).collect::<Vec<_>>()
+            })
+            .collect();
+
+        RadianceField { size, cells }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Writes the Gaussians in the binary PLY layout of 3D Gaussian splatting. Splatting adds 0.5
+    /// to the evaluated color, so it is subtracted from the degree 0 term, opacities are stored
+    /// as logits and scales as logarithms.
+    pub fn write_ply(&self, writer: impl Write) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        let width = self.sh_width;
+
+        writeln!(writer, "ply")?;
+        writeln!(writer, "format binary_little_endian 1.0")?;
+        writeln!(writer, "element vertex {}", self.len())?;
+
+        for name in ["x", "y", "z", "nx", "ny", "nz", "f_dc_0", "f_dc_1", "f_dc_2"] {
+            writeln!(writer, "property float {name}")?;
+        }
+
+        for i in 0..3 * (width - 1) {
+            writeln!(writer, "property float f_rest_{i}")?;
+        }
+
+        for name in ["opacity", "scale_0", "scale_1", "scale_2", "rot_0", "rot_1", "rot_2", "rot_3"] {
+            writeln!(writer, "property float {name}")?;
+        }
+
+        writeln!(writer, "end_header")?;
+
+        for i in 0..self.len() {
+            let sh = &self.sh[3 * width * i..3 * width * (i + 1)];
+            let opacity = self.opacities[i].clamp(1e-6, 1.0 - 1e-6);
+            let [x, y, z, w] = self.rotations[i].to_array();
+
+            let mut values = Vec::with_capacity(17 + 3 * width);
+
+            values.extend(self.positions[i].to_array());
+            values.extend([0.0; 3]);
+            values.extend((0..3).map(|color| sh[color * width] - 0.5 / SH_C0));
+
+            // higher degree coefficients are grouped by color as well
+            for color in 0..3 {
+                values.extend(&sh[color * width + 1..(color + 1) * width]);
+            }
+
+            values.push(f32::ln(opacity / (1.0 - opacity)));
+            values.extend(self.scales[i].to_array().map(f32::ln));
+            values.extend([w, x, y, z]);
+
+            writer.write_all(bytemuck::cast_slice(&values))?;
+        }
+
+        writer.flush()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procedural::Procedural;
+
+    /// Cloud read back from its PLY file
+    fn through_ply(splats: &GaussianCloud) -> GaussianCloud {
+        let mut bytes = Vec::new();
+        splats.write_ply(&mut bytes).unwrap();
+
+        GaussianCloud::from_ply(&Ply::read(bytes.as_slice()).unwrap()).unwrap()
+    }
+
+    fn unit_box() -> Aabb {
+        Aabb::new(Vec3::ZERO, Vec3::ONE)
+    }
+
+    /// Distinct coefficients of every channel
+    fn sh_cell() -> Cell<16> {
+        let coefficients = |offset: f32| std::array::from_fn(|i| f32::sin(offset + 1.3 * i as f32));
+        Cell { density: 4.0, sh_r: coefficients(0.0), sh_g: coefficients(1.0), sh_b: coefficients(2.0) }
+    }
+
+    #[test]
+    fn round_trip_keeps_density() {
+        let field = Procedural::Cube { density: 5.0, color: Vec3::ONE }.generate([16; 3]);
+
+        let splats = through_ply(&GaussianCloud::from_grid(&field, unit_box(), 0.0, 1));
+        let result = splats.rasterize::<4>([16; 3], unit_box());
+
+        // the Gaussians are sampled at the cell centers, which overlap slightly in the sum
+        let density = result.get([8; 3]).unwrap().density;
+        assert!((density - 5.0).abs() < 0.4, "density {density} in the center");
+    }
+
+    #[test]
+    fn rest_coefficients_are_grouped_by_channel() {
+        let cell = sh_cell();
+        let field = RadianceField { size: [1; 3], cells: vec![cell] };
+
+        let mut bytes = Vec::new();
+        GaussianCloud::from_grid(&field, unit_box(), 0.0, 1).write_ply(&mut bytes).unwrap();
+
+        let ply = Ply::read(bytes.as_slice()).unwrap();
+        let vertex = ply.element("vertex").unwrap();
+
+        for (color, sh) in [cell.sh_r, cell.sh_g, cell.sh_b].iter().enumerate() {
+            let dc = vertex.scalar(&format!("f_dc_{color}")).unwrap()[0];
+            assert!((dc - (sh[0] - 0.5 / SH_C0)).abs() < 1e-5, "f_dc_{color} is {dc}");
+
+            for (j, &expected) in sh.iter().enumerate().skip(1) {
+                let name = format!("f_rest_{}", 15 * color + j - 1);
+                let value = vertex.scalar(&name).unwrap()[0];
+                assert!((value - expected).abs() < 1e-6, "{name} is {value} instead of {expected}");
+            }
+        }
+
+        let splats = GaussianCloud::from_ply(&ply).unwrap();
+        let expected = cell.sh_r.iter().chain(&cell.sh_g).chain(&cell.sh_b);
+
+        assert_eq!(splats.sh_width, 16);
+
+        for (value, expected) in splats.sh.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-5, "{value} instead of {expected}");
+        }
+    }
+
+    #[test]
+    fn degree_3_is_truncated_to_degree_2() {
+        let cell = sh_cell();
+        let splats = GaussianCloud {
+            positions: vec![Vec3::splat(8.5 / 16.0)],
+            scales: vec![Vec3::splat(0.05)],
+            rotations: vec![Quat::IDENTITY],
+            opacities: vec![0.5],
+            sh: cell.sh_r.iter().chain(&cell.sh_g).chain(&cell.sh_b).copied().collect(),
+            sh_width: 16,
+        };
+
+        let field = through_ply(&splats).rasterize::<9>([16; 3], unit_box());
+        let result = field.get([8; 3]).unwrap();
+
+        for (actual, expected) in [(result.sh_r, cell.sh_r), (result.sh_g, cell.sh_g), (result.sh_b, cell.sh_b)] {
+            for (actual, expected) in actual.iter().zip(&expected[..9]) {
+                assert!((actual - expected).abs() < 1e-4, "{actual} instead of {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn anisotropic_gaussian_keeps_rotated_extents() {
+        const SIZE: usize = 32;
+
+        let cell_size = 1.0 / SIZE as f32;
+        let scale = vec3(0.08, 0.02, 0.03);
+        // the long local X axis turns into the world Y axis
+        let rotation = Quat::from_rotation_z(0.5 * PI);
+
+        let splats = GaussianCloud {
+            positions: vec![Vec3::splat(16.5 * cell_size)],
+            scales: vec![scale],
+            rotations: vec![rotation],
+            opacities: vec![0.5],
+            sh: vec![1.0; 3],
+            sh_width: 1,
+        };
+
+        let field = through_ply(&splats).rasterize::<1>([SIZE; 3], unit_box());
+        let density = |index: [usize; 3]| field.get(index).unwrap().density;
+
+        // the footprint of a cell widens the variance along every axis
+        let world_variance = vec3(scale.y, scale.x, scale.z).powf(2.0) + cell_size * cell_size / 12.0;
+        let center = density([16; 3]);
+
+        for (axis, neighbour) in [[17, 16, 16], [16, 17, 16], [16, 16, 17]].into_iter().enumerate() {
+            let expected = f32::exp(-0.5 * cell_size * cell_size / world_variance[axis]);
+            let ratio = density(neighbour) / center;
+
+            assert!((ratio - expected).abs() < 1e-3, "ratio {ratio} instead of {expected} along axis {axis}");
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use crate::spherical::{Channel, Grid, RadianceField};
+use rayon::prelude::*;
+use serde::Serialize;
+
+
+
+/// Percentiles reported for every channel
+pub const PERCENTILES: [f64; 5] = [1.0, 5.0, 50.0, 95.0, 99.0];
+
+
+
+#[derive(Clone, Debug, Default, PartialEq, Copy, Serialize)]
+pub struct Percentile {
+    pub percent: f64,
+    pub value: f32,
+}
+
+
+
+/// Value distribution of a single channel, computed over finite values only.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ChannelStatistics {
+    pub channel: String,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f64,
+    /// Values at [`PERCENTILES`]
+    pub percentiles: Vec<Percentile>,
+    pub nan: usize,
+    pub infinite: usize,
+}
+
+impl ChannelStatistics {
+    pub fn new(channel: Channel, mut values: Vec<f32>) -> Self {
+        let nan = values.par_iter().filter(|value| value.is_nan()).count();
+        let infinite = values.par_iter().filter(|value| value.is_infinite()).count();
+
+        values.retain(|value| value.is_finite());
+        values.par_sort_unstable_by(f32::total_cmp);
+
+        let (Some(&min), Some(&max)) = (values.first(), values.last()) else {
+            return Self { channel: channel.to_string(), nan, infinite, ..Default::default() };
+        };
+
+        let mean = values.par_iter().map(|&value| value as f64).sum::<f64>() / values.len() as f64;
+
+        // nearest-rank percentiles
+        let percentiles = PERCENTILES.iter()
+            .map(|&percent| {
+                let rank = (percent / 100.0 * values.len() as f64).ceil() as usize;
+                Percentile { percent, value: values[rank.clamp(1, values.len()) - 1] }
+            })
+            .collect();
+
+        Self { channel: channel.to_string(), min, max, mean, percentiles, nan, infinite }
+    }
+}
+
+
+
+/// Summary of a field's cells.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FieldStatistics {
+    pub size: [usize; 3],
+    pub n_cells: usize,
+    /// Cells with density below zero
+    pub negative_density: usize,
+    /// Density above which a cell is considered occupied
+    pub density_threshold: f32,
+    /// Fraction of occupied cells
+    pub occupied_fraction: f64,
+    /// Channels in the order of [`Channel::from_index`]
+    pub channels: Vec<ChannelStatistics>,
+}
+
+impl FieldStatistics {
+    pub fn compute<const W: usize>(grid: &(impl Grid<W> + Sync), density_threshold: f32) -> Self {
+        let size = grid.size();
+        let n_cells = RadianceField::volume(size);
+
+        // one channel at a time to keep only a single copy of the values in memory
+        let channels = (0..1 + 3 * W)
+            .map(|i| {
+                let channel = Channel::from_index(W, i);
+
+                let values = (0..n_cells)
+                    .into_par_iter()
+                    .map(|i| {
+                        let cell = grid.cell(RadianceField::index_from_flat(size, i)).unwrap();
+                        channel.get(&cell).unwrap()
+                    })
+                    .collect::<Vec<_>>();
+
+                ChannelStatistics::new(channel, values)
+            })
+            .collect();
+
+        let (negative_density, occupied) = (0..n_cells)
+            .into_par_iter()
+            .map(|i| {
+                let density = grid.cell(RadianceField::index_from_flat(size, i)).unwrap().density;
+                ((density < 0.0) as usize, (density > density_threshold) as usize)
+            })
+            .reduce(|| (0, 0), |(ln, lo), (rn, ro)| (ln + rn, lo + ro));
+
+        Self {
+            size,
+            n_cells,
+            negative_density,
+            density_threshold,
+            occupied_fraction: occupied as f64 / n_cells.max(1) as f64,
+            channels,
+        }
+    }
+
+    /// Problems found in the field that make it invalid
+    pub fn issues(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        for channel in &self.channels {
+            if channel.nan != 0 {
+                issues.push(format!("{} values of {} are NaN", channel.nan, channel.channel));
+            }
+
+            if channel.infinite != 0 {
+                issues.push(format!("{} values of {} are infinite", channel.infinite, channel.channel));
+            }
+        }
+
+        issues
+    }
+
+    /// Suspicious properties of the field that don't make it invalid, e.g. negative
+    /// density left by optimization, which is treated as empty space when rendering
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.negative_density != 0 {
+            warnings.push(format!("{} cells have negative density", self.negative_density));
+        }
+
+        warnings
+    }
+}
+
+impl std::fmt::Display for FieldStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [sx, sy, sz] = self.size;
+
+        writeln!(f, "size: {sx}x{sy}x{sz} ({} cells)", self.n_cells)?;
+        writeln!(
+            f, "occupied: {:.2}% (density > {})",
+            100.0 * self.occupied_fraction, self.density_threshold,
+        )?;
+        writeln!(f, "negative density: {} cells", self.negative_density)?;
+        writeln!(f)?;
+
+        write!(f, "{:<10} {:>12} {:>12} {:>12}", "channel", "min", "max", "mean")?;
+
+        for percent in PERCENTILES {
+            write!(f, " {:>12}", format!("p{percent}"))?;
+        }
+
+        writeln!(f, " {:>8} {:>8}", "nan", "inf")?;
+
+        for channel in &self.channels {
+            write!(
+                f, "{:<10} {:>12.6e} {:>12.6e} {:>12.6e}",
+                channel.channel, channel.min, channel.max, channel.mean,
+            )?;
+
+            for percentile in &channel.percentiles {
+                write!(f, " {:>12.6e}", percentile.value)?;
+            }
+
+            writeln!(f, " {:>8} {:>8}", channel.nan, channel.infinite)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+
+/// Result of validating a model file.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ValidationReport {
+    pub path: String,
+    /// `false` if the file could not be loaded or the field has issues
+    pub valid: bool,
+    /// Reason the file could not be loaded
+    pub error: Option<String>,
+    pub storage: Option<String>,
+    pub sh_degree: Option<u32>,
+    pub issues: Vec<String>,
+    pub warnings: Vec<String>,
+    pub statistics: Option<FieldStatistics>,
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "model: {}", self.path)?;
+
+        if let Some(error) = &self.error {
+            return writeln!(f, "error: {error}");
+        }
+
+        if let (Some(storage), Some(sh_degree)) = (&self.storage, self.sh_degree) {
+            writeln!(f, "storage: {storage}, spherical harmonic degree: {sh_degree}")?;
+        }
+
+        if let Some(statistics) = &self.statistics {
+            write!(f, "{statistics}")?;
+        }
+
+        for warning in &self.warnings {
+            writeln!(f, "warning: {warning}")?;
+        }
+
+        for issue in &self.issues {
+            writeln!(f, "issue: {issue}")?;
+        }
+
+        writeln!(f, "{}", if self.valid { "valid" } else { "invalid" })
+    }
+}
@@ -0,0 +1,393 @@
+use crate::spherical::{serde_array, Cell, Channel, Grid, RadianceField, SPHERICAL_HARMONIC_WIDTH};
+use bytemuck::{Pod, Zeroable};
+use half::{f16, slice::HalfFloatSliceExt as _};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::array;
+
+
+
+/// [`Cell`] with all channels stored in half precision.
+#[repr(C)]
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct HalfCell<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
+    pub density: f16,
+    #[serde(with = "serde_array")]
+    pub sh_r: [f16; W],
+    #[serde(with = "serde_array")]
+    pub sh_g: [f16; W],
+    #[serde(with = "serde_array")]
+    pub sh_b: [f16; W],
+}
+
+// # Safety
+//
+// `HalfCell` is `repr(C)` and consists of `f16`s only, so it has no padding
+unsafe impl<const W: usize> Zeroable for HalfCell<W> {}
+unsafe impl<const W: usize> Pod for HalfCell<W> {}
+
+impl<const W: usize> From<&Cell<W>> for HalfCell<W> {
+    fn from(value: &Cell<W>) -> Self {
+        Self {
+            density: f16::from_f32(value.density),
+            sh_r: value.sh_r.map(f16::from_f32),
+            sh_g: value.sh_g.map(f16::from_f32),
+            sh_b: value.sh_b.map(f16::from_f32),
+        }
+    }
+}
+
+impl<const W: usize> From<&HalfCell<W>> for Cell<W> {
+    fn from(value: &HalfCell<W>) -> Self {
+        // slice conversion picks hardware instructions at runtime if they are available
+        let mut cell = Cell::zeroed();
+
+        bytemuck::cast_slice::<_, f16>(std::slice::from_ref(value))
+            .convert_to_f32_slice(bytemuck::cast_slice_mut(std::slice::from_mut(&mut cell)));
+
+        cell
+    }
+}
+
+
+
+/// Radiance field with half precision cells, has the same layout as [`RadianceField`].
+#[derive(Clone, Default, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct HalfField<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
+    pub size: [usize; 3],
+    pub cells: Vec<HalfCell<W>>,
+}
+
+impl<const W: usize> HalfField<W> {
+    pub fn from_grid(grid: &(impl Grid<W> + Sync)) -> Self {
+        let size = grid.size();
+
+        Self {
+            size,
+            cells: (0..RadianceField::volume(size))
+                .into_par_iter()
+                .map(|i| HalfCell::from(&grid.cell(RadianceField::index_from_flat(size, i)).unwrap()))
+                .collect(),
+        }
+    }
+
+    pub fn to_field(&self) -> RadianceField<W> {
+        RadianceField {
+            size: self.size,
+            cells: self.cells.par_iter().map(Cell::from).collect(),
+        }
+    }
+}
+
+impl<const W: usize> Grid<W> for HalfField<W> {
+    fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    fn cell(&self, index: [usize; 3]) -> Option<Cell<W>> {
+        if !RadianceField::contains(self.size, index) {
+            return None;
+        }
+
+        self.cells.get(RadianceField::index_of(self.size, index)).map(Cell::from)
+    }
+}
+
+
+
+/// Affine mapping of the `u8` range onto a channel's value range: `value = offset + scale * q`.
+#[derive(Clone, Debug, Default, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct Quantization {
+    pub scale: f32,
+    pub offset: f32,
+}
+
+impl Quantization {
+    /// Fits the quantization to the `[min, max]` range of the values
+    pub fn fit(values: impl Iterator<Item = f32>) -> Self {
+        let (min, max) = values.fold(
+            (f32::INFINITY, f32::NEG_INFINITY),
+            |(min, max), value| (min.min(value), max.max(value)),
+        );
+
+        if min > max {
+            return Self::default();
+        }
+
+        Self { scale: (max - min) / u8::MAX as f32, offset: min }
+    }
+
+    pub fn quantize(self, value: f32) -> u8 {
+        if self.scale == 0.0 {
+            return 0;
+        }
+
+        ((value - self.offset) / self.scale)
+            .round()
+            .clamp(0.0, u8::MAX as f32) as u8
+    }
+
+    pub fn dequantize(self, value: u8) -> f32 {
+        self.offset + self.scale * value as f32
+    }
+}
+
+
+
+/// [`Cell`] with a half precision density and 8-bit quantized spherical harmonic coefficients.
+#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct QuantizedCell<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
+    pub density: f16,
+    #[serde(with = "serde_array")]
+    pub sh_r: [u8; W],
+    #[serde(with = "serde_array")]
+    pub sh_g: [u8; W],
+    #[serde(with = "serde_array")]
+    pub sh_b: [u8; W],
+}
+
+
+
+/// Radiance field with quantized cells. Every spherical harmonic
+/// coefficient of every color channel has its own [`Quantization`].
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct QuantizedField<const W: usize = SPHERICAL_HARMONIC_WIDTH> {
+    pub size: [usize; 3],
+    #[serde(with = "serde_array")]
+    pub sh_r: [Quantization; W],
+    #[serde(with = "serde_array")]
+    pub sh_g: [Quantization; W],
+    #[serde(with = "serde_array")]
+    pub sh_b: [Quantization; W],
+    pub cells: Vec<QuantizedCell<W>>,
+}
+
+impl<const W: usize> QuantizedField<W> {
+    pub fn from_grid(grid: &(impl Grid<W> + Sync)) -> Self {
+        let size = grid.size();
+        let cells = || (0..RadianceField::volume(size))
+            .into_par_iter()
+            .map(|i| grid.cell(RadianceField::index_from_flat(size, i)).unwrap());
+
+        // lowest and highest values of every channel
+        let (lo, hi) = cells()
+            .map(|cell| (cell, cell))
+            .reduce(
+                || (Cell::splat(f32::INFINITY), Cell::splat(f32::NEG_INFINITY)),
+                |(llo, lhi), (rlo, rhi)| (llo.zip_with(rlo, f32::min), lhi.zip_with(rhi, f32::max)),
+            );
+
+        let fit = |lo: [f32; W], hi: [f32; W]| {
+            array::from_fn(|i| Quantization::fit([lo[i], hi[i]].into_iter()))
+        };
+
+        let sh_r: [Quantization; W] = fit(lo.sh_r, hi.sh_r);
+        let sh_g: [Quantization; W] = fit(lo.sh_g, hi.sh_g);
+        let sh_b: [Quantization; W] = fit(lo.sh_b, hi.sh_b);
+
+        let cells = cells()
+            .map(|cell| QuantizedCell {
+                density: f16::from_f32(cell.density),
+                sh_r: array::from_fn(|i| sh_r[i].quantize(cell.sh_r[i])),
+                sh_g: array::from_fn(|i| sh_g[i].quantize(cell.sh_g[i])),
+                sh_b: array::from_fn(|i| sh_b[i].quantize(cell.sh_b[i])),
+            })
+            .collect();
+
+        Self { size, sh_r, sh_g, sh_b, cells }
+    }
+
+    pub fn decode(&self, cell: &QuantizedCell<W>) -> Cell<W> {
+        Cell {
+            density: cell.density.to_f32(),
+            sh_r: array::from_fn(|i| self.sh_r[i].dequantize(cell.sh_r[i])),
+            sh_g: array::from_fn(|i| self.sh_g[i].dequantize(cell.sh_g[i])),
+            sh_b: array::from_fn(|i| self.sh_b[i].dequantize(cell.sh_b[i])),
+        }
+    }
+
+    pub fn to_field(&self) -> RadianceField<W> {
+        RadianceField {
+            size: self.size,
+            cells: self.cells.par_iter().map(|cell| self.decode(cell)).collect(),
+        }
+    }
+}
+
+impl<const W: usize> Grid<W> for QuantizedField<W> {
+    fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    fn cell(&self, index: [usize; 3]) -> Option<Cell<W>> {
+        if !RadianceField::contains(self.size, index) {
+            return None;
+        }
+
+        self.cells.get(RadianceField::index_of(self.size, index))
+            .map(|cell| self.decode(cell))
+    }
+}
+
+
+
+/// Error statistics of a single channel
+#[derive(Clone, Debug, Default, PartialEq, Copy)]
+pub struct ChannelError {
+    pub max: f32,
+    pub rms: f32,
+}
+
+
+
+/// Per-channel difference between an original field and its encoded version.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorReport {
+    /// Number of spherical harmonic coefficients per color
+    pub width: usize,
+    /// Channels in the [`ErrorReport::channel_name`] order
+    pub channels: Vec<ChannelError>,
+}
+
+impl ErrorReport {
+    /// Number of channels in a cell with `width` spherical harmonic coefficients
+    pub const fn n_channels(width: usize) -> usize {
+        1 + 3 * width
+    }
+
+    pub fn channel_name(width: usize, index: usize) -> String {
+        Channel::from_index(width, index).to_string()
+    }
+
+    pub fn compare<const W: usize>(
+        original: &(impl Grid<W> + Sync), encoded: &(impl Grid<W> + Sync),
+    ) -> Self {
+        assert_eq!(original.size(), encoded.size(), "fields should have the same size");
+
+        let size = original.size();
+        let n_cells = RadianceField::volume(size);
+        let n_channels = Self::n_channels(W);
+
+        let (max, sum_sq) = (0..n_cells)
+            .into_par_iter()
+            .map(|i| {
+                let index = RadianceField::index_from_flat(size, i);
+                let lhs = original.cell(index).unwrap();
+                let lhs: &[f32] = bytemuck::cast_slice(std::slice::from_ref(&lhs));
+                let rhs = encoded.cell(index).unwrap();
+                let rhs: &[f32] = bytemuck::cast_slice(std::slice::from_ref(&rhs));
+
+                let diff = lhs.iter().zip(rhs)
+                    .map(|(l, r)| (l - r).abs())
+                    .collect::<Vec<_>>();
+
+                let diff_sq = diff.iter().map(|&d| d as f64 * d as f64).collect();
+
+                (diff, diff_sq)
+            })
+            .reduce(
+                || (vec![0.0; n_channels], vec![0.0; n_channels]),
+                |(lmax, lsum), (rmax, rsum)| (
+                    lmax.iter().zip(&rmax).map(|(l, r)| l.max(*r)).collect(),
+                    lsum.iter().zip(&rsum).map(|(l, r)| l + r).collect(),
+                ),
+            );
+
+        let n_cells = n_cells.max(1) as f64;
+
+        Self {
+            width: W,
+            channels: max.iter().zip(&sum_sq)
+                .map(|(&max, &sum_sq)| ChannelError {
+                    max,
+                    rms: (sum_sq / n_cells).sqrt() as f32,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<10} {:>12} {:>12}", "channel", "max error", "rms error")?;
+
+        for (i, channel) in self.channels.iter().enumerate() {
+            writeln!(
+                f, "{:<10} {:>12.6e} {:>12.6e}",
+                Self::channel_name(self.width, i), channel.max, channel.rms,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procedural::Procedural;
+
+    /// Noise field with distinct higher harmonics in every cell
+    fn noise_field() -> RadianceField<4> {
+        let mut field = Procedural::Noise { density: 20.0, frequency: 3, seed: 7 }.generate([12, 10, 8]);
+
+        for (i, cell) in field.cells.iter_mut().enumerate() {
+            for j in 1..4 {
+                cell.sh_r[j] = f32::sin(0.3 * i as f32 + j as f32);
+                cell.sh_g[j] = 2.0 * f32::cos(0.7 * i as f32 - j as f32);
+                cell.sh_b[j] = -0.5 * j as f32;
+            }
+        }
+
+        field
+    }
+
+    /// Largest rounding error of a half precision value
+    fn half_bound(value: f32) -> f32 {
+        value.abs() * f32::powi(2.0, -11) + 1e-7
+    }
+
+    #[test]
+    fn half_field_keeps_half_precision() {
+        let field = noise_field();
+        let half = HalfField::from_grid(&field);
+
+        for (original, encoded) in field.cells.iter().zip(&half.to_field().cells) {
+            let original: &[f32] = bytemuck::cast_slice(std::slice::from_ref(original));
+            let encoded: &[f32] = bytemuck::cast_slice(std::slice::from_ref(encoded));
+
+            for (original, encoded) in original.iter().zip(encoded) {
+                assert!((original - encoded).abs() <= half_bound(*original), "{encoded} instead of {original}");
+            }
+        }
+    }
+
+    #[test]
+    fn quantized_field_errs_by_half_a_step() {
+        let field = noise_field();
+        let quantized = QuantizedField::from_grid(&field);
+        let report = ErrorReport::compare(&field, &quantized);
+
+        let max_density = field.cells.iter().map(|cell| cell.density).fold(0.0, f32::max);
+        assert!(report.channels[0].max <= half_bound(max_density), "density error {:?}", report.channels[0]);
+
+        let quantizations = quantized.sh_r.iter().chain(&quantized.sh_g).chain(&quantized.sh_b);
+
+        for (i, (error, quantization)) in report.channels[1..].iter().zip(quantizations).enumerate() {
+            let bound = 0.5 * quantization.scale * (1.0 + 1e-4) + 1e-6;
+            let name = ErrorReport::channel_name(4, i + 1);
+            assert!(error.max <= bound, "channel {name} error {} above {bound}", error.max);
+        }
+
+        // constant channels have no steps
+        assert_eq!(quantized.sh_b[3].scale, 0.0);
+        assert_eq!(report.channels[ErrorReport::n_channels(4) - 1].max, 0.0);
+    }
+}
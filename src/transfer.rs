@@ -0,0 +1,210 @@
+use crate::{
+    colormap::Colormap,
+    spherical::{Channel, Grid, RadianceField},
+    statistics::ChannelStatistics,
+};
+use glam::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+
+
+
+/// Maps density to color and opacity for the density render target, written as a `[transfer]` table.
+/// Density is first normalized by the range to [0, 1].
+#[derive(Clone, Debug, Default, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferFunction {
+    /// Densities mapped to 0 and 1, detected from the field when missing
+    pub range: Option<[f32; 2]>,
+    /// Color of the normalized density when there are no `color` points
+    pub colormap: Colormap,
+    /// Control points `[density, r, g, b]` of a piecewise-linear color, sorted by density when loaded
+    #[serde(deserialize_with = "deserialize_sorted")]
+    pub color: Vec<[f32; 4]>,
+    /// Control points `[density, opacity]` of a piecewise-linear opacity, sorted by density when loaded.
+    /// Opacity is the density per model space unit used for absorption, the raw density is used without points.
+    #[serde(deserialize_with = "deserialize_sorted")]
+    pub opacity: Vec<[f32; 2]>,
+}
+
+impl TransferFunction {
+    /// Range used when it is neither configured nor detected
+    pub const DEFAULT_RANGE: [f32; 2] = [0.0, 1.0];
+
+    pub fn range(&self) -> [f32; 2] {
+        self.range.unwrap_or(Self::DEFAULT_RANGE)
+    }
+
+    /// Density in the range mapped to [0, 1] and clamped
+    pub fn normalize(&self, density: f32) -> f32 {
+        let [lo, hi] = self.range();
+        let value = (density - lo) / (hi - lo);
+
+        if value.is_nan() { 0.0 } else { value.clamp(0.0, 1.0) }
+    }
+
+    /// Color of the normalized density
+    pub fn color(&self, value: f32) -> Vec3 {
+        match self.color.is_empty() {
+            true => self.colormap.eval(value),
+            false => {
+                let (i, j, t) = segment(self.color.iter().map(|point| point[0]), value);
+                let to_color = |[_, r, g, b]: [f32; 4]| vec3(r, g, b);
+
+                to_color(self.color[i]).lerp(to_color(self.color[j]), t)
+            },
+        }
+    }
+
+    /// Opacity of the normalized density, `None` if the raw density is used
+    pub fn opacity(&self, value: f32) -> Option<f32> {
+        if self.opacity.is_empty() {
+            return None;
+        }
+
+        let (i, j, t) = segment(self.opacity.iter().map(|point| point[0]), value);
+
+        Some(self.opacity[i][1].lerp(self.opacity[j][1], t))
+    }
+
+    /// Color and density used for absorption of the raw density
+    pub fn eval(&self, density: f32) -> (Vec3, f32) {
+        let value = self.normalize(density);
+
+        (self.color(value), self.opacity(value).unwrap_or(density))
+    }
+
+    /// Colors in `xyz` and opacities in `w` at `n` evenly spaced normalized densities,
+    /// opacity is zero if the raw density is used
+    pub fn table(&self, n: usize) -> Vec<Vec4> {
+        (0..n)
+            .map(|i| {
+                let value = i as f32 / (n - 1) as f32;
+                self.color(value).extend(self.opacity(value).unwrap_or(0.0))
+            })
+            .collect()
+    }
+}
+
+/// Control points in any order sorted by their normalized density, points with equal densities
+/// keep their order
+fn deserialize_sorted<'de, D, const N: usize>(deserializer: D) -> Result<Vec<[f32; N]>, D::Error>
+where
+    D: Deserializer<'de>,
+    [f32; N]: Deserialize<'de>,
+{
+    let mut points = Vec::<[f32; N]>::deserialize(deserializer)?;
+    points.sort_by(|lhs, rhs| lhs[0].total_cmp(&rhs[0]));
+
+    Ok(points)
+}
+
+/// Neighbouring control points around `x` and the position between them, the first
+/// and the last points extend to infinity
+fn segment(xs: impl ExactSizeIterator<Item = f32> + Clone, x: f32) -> (usize, usize, f32) {
+    let n = xs.len();
+    let next = xs.clone().position(|point| point > x).unwrap_or(n);
+
+    match next {
+        0 => (0, 0, 0.0),
+        next if next == n => (n - 1, n - 1, 0.0),
+        next => {
+            let (lo, hi) = (xs.clone().nth(next - 1).unwrap(), xs.clone().nth(next).unwrap());
+            (next - 1, next, (x - lo) / (hi - lo))
+        },
+    }
+}
+
+
+
+/// Density range from zero to the 99th percentile of the occupied cells, so that
+/// rare dense cells don't push the rest to the bottom of the range
+pub fn detect_density_range<const W: usize>(grid: &(impl Grid<W> + Sync)) -> [f32; 2] {
+    let size = grid.size();
+
+    let densities = (0..RadianceField::volume(size))
+        .into_par_iter()
+        .map(|i| grid.cell(RadianceField::index_from_flat(size, i)).unwrap().density)
+        .filter(|&density| density > 0.0)
+        .collect::<Vec<_>>();
+
+    let statistics = ChannelStatistics::new(Channel::Density, densities);
+
+    match statistics.percentiles.last() {
+        Some(percentile) if percentile.value > 0.0 => [0.0, percentile.value],
+        _ => TransferFunction::DEFAULT_RANGE,
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spherical::Cell;
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn control_points_are_sorted_and_interpolated() {
+        let transfer = toml::from_str::<TransferFunction>(r#"
+            range = [10.0, 20.0]
+            color = [[1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 0.0]]
+            opacity = [[0.5, 4.0], [1.0, 8.0], [0.0, 0.0]]
+        "#).unwrap();
+
+        assert_eq!(transfer.color, [[0.0, 1.0, 0.0, 0.0], [1.0, 0.0, 0.0, 1.0]]);
+        assert_eq!(transfer.opacity, [[0.0, 0.0], [0.5, 4.0], [1.0, 8.0]]);
+
+        // the density is normalized by the range first
+        let (color, opacity) = transfer.eval(12.5);
+        assert!(color.abs_diff_eq(vec3(0.75, 0.0, 0.25), 1e-6), "{color}");
+        assert!((opacity - 2.0).abs() < 1e-6, "{opacity}");
+
+        assert!((transfer.eval(17.5).1 - 6.0).abs() < 1e-6);
+
+        // the end points extend past the range
+        assert_eq!(transfer.eval(5.0), (Vec3::X, 0.0));
+        assert_eq!(transfer.eval(25.0), (Vec3::Z, 8.0));
+
+        let table = transfer.table(3);
+        assert_eq!(table, [vec4(1.0, 0.0, 0.0, 0.0), vec4(0.5, 0.0, 0.5, 4.0), vec4(0.0, 0.0, 1.0, 8.0)]);
+    }
+
+    #[test]
+    fn colormap_colors_the_density_without_control_points() {
+        let transfer = TransferFunction { range: Some([0.0, 4.0]), colormap: Colormap::Viridis, ..Default::default() };
+
+        // the raw density is used for absorption
+        let (color, opacity) = transfer.eval(4.0);
+        assert_eq!(opacity, 4.0);
+        assert!(color.abs_diff_eq(vec3(0xFD as f32, 0xE7 as f32, 0x25 as f32) / 255.0, 1e-6), "{color}");
+
+        let (color, _) = transfer.eval(0.0);
+        assert!(color.abs_diff_eq(vec3(0x44 as f32, 0x01 as f32, 0x54 as f32) / 255.0, 1e-6), "{color}");
+
+        // the default function is the gray ramp over [0, 1]
+        let transfer = TransferFunction::default();
+        assert_eq!(transfer.eval(0.25), (Vec3::splat(0.25), 0.25));
+        assert_eq!(transfer.eval(3.0), (Vec3::ONE, 3.0));
+        assert_eq!(transfer.normalize(f32::NAN), 0.0);
+        assert_eq!(transfer.table(2), [Vec4::ZERO, vec4(1.0, 1.0, 1.0, 0.0)]);
+    }
+
+    #[test]
+    fn range_ignores_empty_cells_and_rare_outliers() {
+        let size = [10; 3];
+        let mut field = RadianceField::<1> { size, cells: vec![Cell::zeroed(); RadianceField::volume(size)] };
+
+        assert_eq!(detect_density_range(&field), TransferFunction::DEFAULT_RANGE);
+
+        for (i, cell) in field.cells.iter_mut().take(200).enumerate() {
+            cell.density = (i + 1) as f32;
+        }
+
+        field.cells[500].density = 1e6;
+
+        // the 99th percentile of 201 occupied cells is the 199th of them
+        assert_eq!(detect_density_range(&field), [0.0, 199.0]);
+    }
+}
@@ -0,0 +1,454 @@
+use crate::{
+    geometry::Aabb,
+    mesh::Mesh,
+    spherical::{Cell, RadianceField, SH_C0},
+};
+use glam::*;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+
+/// Part of the space around a mesh that is filled with density
+#[derive(Clone, Debug, Default, PartialEq, Copy)]
+pub enum VoxelShape {
+    /// Everything inside of the closed surface
+    #[default]
+    Solid,
+    /// Cells near the surface, the thickness is in cells
+    Shell { thickness: f32 },
+}
+
+
+
+/// Parameters of the voxelization of a mesh
+#[derive(Clone, Debug, Default, PartialEq, Copy)]
+pub struct Voxelization {
+    pub shape: VoxelShape,
+    /// Density of the fully occupied cells
+    pub density: f32,
+    /// Whether the density is proportional to the covered part of the cell,
+    /// otherwise cells are either full or empty depending on their centers
+    pub coverage: bool,
+}
+
+/// Number of rays along every horizontal axis of a cell that measure the coverage of solids
+const COVERAGE_SAMPLES: usize = 4;
+
+/// Distance to the surface in cells within which cells take the color of its closest point
+const COLOR_BAND: f32 = 1.5;
+
+
+
+impl Mesh {
+    /// Fills a degree 0 field with the mesh. Cells near the surface take the color of its closest point,
+    /// deeper cells of solids interpolate between the surface points above and below them.
+    ///
+    /// Solids are filled by the parity of vertical ray crossings, so the mesh has to be closed.
+    pub fn voxelized(&self, size: [usize; 3], bounding_box: Aabb, voxelization: &Voxelization) -> RadianceField<1> {
+        let cell_size = (bounding_box.hi - bounding_box.lo) / RadianceField::size_vec(size);
+
+        // cell centers are at half-integer coordinates
+        let positions = self.positions.iter()
+            .map(|&position| (position - bounding_box.lo) / cell_size)
+            .collect::<Vec<_>>();
+
+        let reach = match voxelization.shape {
+            VoxelShape::Solid => COLOR_BAND,
+            VoxelShape::Shell { thickness } => COLOR_BAND.max(0.5 * thickness + 0.5),
+        };
+
+        let closest = self.closest_triangles(&positions, size, reach);
+
+        let columns = match voxelization.shape {
+            VoxelShape::Solid => {
+                let samples = if voxelization.coverage { COVERAGE_SAMPLES } else { 1 };
+                Some(ColumnHits::new(self, &positions, size, samples))
+            },
+            VoxelShape::Shell { .. } => None,
+        };
+
+        let cells = (0..RadianceField::volume(size))
+            .into_par_iter()
+            .map(|i| {
+                let index = RadianceField::index_from_flat(size, i);
+                let center = Vec3::from_array(index.map(|i| i as f32)) + 0.5;
+
+                let nearest = (closest[i] != u64::MAX).then(|| {
+                    let distance = f32::from_bits((closest[i] >> 32) as u32);
+                    let [a, b, c] = self.triangles[(closest[i] & 0xFFFFFFFF) as usize].map(|i| i as usize);
+
+                    let weights = closest_barycentric(center, positions[a], positions[b], positions[c]);
+                    let color = weights.x * self.colors[a] + weights.y * self.colors[b] + weights.z * self.colors[c];
+
+                    (distance, color)
+                });
+
+                let (occupancy, column_color) = match (voxelization.shape, &columns) {
+                    (VoxelShape::Solid, Some(columns)) => columns.occupancy(index, voxelization.coverage),
+                    (VoxelShape::Shell { thickness }, _) => {
+                        let distance = nearest.map_or(f32::INFINITY, |(distance, _)| distance);
+
+                        let occupancy = match voxelization.coverage {
+                            true => (0.5 * thickness + 0.5 - distance).clamp(0.0, 1.0),
+                            false => if distance <= 0.5 * thickness { 1.0 } else { 0.0 },
+                        };
+
+                        (occupancy, None)
+                    },
+                    (VoxelShape::Solid, None) => unreachable!("solids are filled from columns"),
+                };
+
+                let color = nearest.map(|(_, color)| color)
+                    .or(column_color)
+                    .unwrap_or(Vec3::ZERO);
+
+                Cell {
+                    density: voxelization.density * occupancy,
+                    sh_r: [color.x / SH_C0],
+                    sh_g: [color.y / SH_C0],
+                    sh_b: [color.z / SH_C0],
+                }
+            })
+            .collect();
+
+        RadianceField { size, cells }
+    }
+
+    /// Closest triangle of every cell within the distance, packed as the distance bits
+    /// followed by the triangle index, or `u64::MAX` if there is none
+    fn closest_triangles(&self, positions: &[Vec3], size: [usize; 3], reach: f32) -> Vec<u64> {
+        let closest = (0..RadianceField::volume(size))
+            .into_par_iter()
+            .map(|_| AtomicU64::new(u64::MAX))
+            .collect::<Vec<_>>();
+
+        let max_index = IVec3::from_array(size.map(|len| len as i32 - 1));
+
+        self.triangles.par_iter().enumerate().for_each(|(triangle, vertices)| {
+            let [a, b, c] = vertices.map(|i| positions[i as usize]);
+
+            if (b - a).cross(c - a).length_squared() == 0.0 {
+                return;
+            }
+
+            let lo = (a.min(b).min(c) - reach - 0.5).floor().as_ivec3().max(IVec3::ZERO);
+            let hi = (a.max(b).max(c) + reach - 0.5).ceil().as_ivec3().min(max_index);
+
+            for y in lo.y..=hi.y {
+                for z in lo.z..=hi.z {
+                    for x in lo.x..=hi.x {
+                        let center = ivec3(x, y, z).as_vec3() + 0.5;
+                        let weights = closest_barycentric(center, a, b, c);
+                        let distance = center.distance(weights.x * a + weights.y * b + weights.z * c);
+
+                        if distance <= reach {
+                            let index = RadianceField::index_of(size, [x, y, z].map(|i| i as usize));
+                            let key = (distance.to_bits() as u64) << 32 | triangle as u64;
+
+                            closest[index].fetch_min(key, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        });
+
+        closest.into_iter().map(AtomicU64::into_inner).collect()
+    }
+}
+
+
+
+/// Crossings of vertical rays through the cells with the surface
+struct ColumnHits {
+    /// Number of rays along X and Z axes of every cell
+    samples: usize,
+    /// Number of rays along X and Z axes
+    n_rays: [usize; 2],
+    /// Start of the hits of every ray, rays are ordered by X first
+    offsets: Vec<usize>,
+    /// Heights in cells and colors of the hits sorted along every ray
+    hits: Vec<(f32, Vec3)>,
+}
+
+impl ColumnHits {
+    fn new(mesh: &Mesh, positions: &[Vec3], [sx, _, sz]: [usize; 3], samples: usize) -> Self {
+        let n_rays = [sx * samples, sz * samples];
+        let [nx, nz] = n_rays;
+
+        let cross = |lhs: Vec2, rhs: Vec2| lhs.perp_dot(rhs);
+
+        // rays on an edge shared by two triangles hit exactly one of them
+        let owns_edge = |from: Vec2, to: Vec2| {
+            let edge = to - from;
+            edge.y > 0.0 || edge.y == 0.0 && edge.x < 0.0
+        };
+
+        let mut hits = mesh.triangles.par_iter()
+            .flat_map_iter(|&vertices| {
+                let [a, mut b, mut c] = vertices.map(|i| i as usize);
+                let flat = |i: usize| positions[i].xz() * samples as f32;
+
+                let mut area = cross(flat(b) - flat(a), flat(c) - flat(a));
+
+                // counter-clockwise when looking from below
+                if area < 0.0 {
+                    std::mem::swap(&mut b, &mut c);
+                    area = -area;
+                }
+
+                let (pa, pb, pc) = (flat(a), flat(b), flat(c));
+
+                let lo = (pa.min(pb).min(pc) - 0.5).ceil().max(Vec2::ZERO).as_uvec2();
+                let hi = (pa.max(pb).max(pc) - 0.5).floor().as_ivec2();
+
+                let range = |lo: u32, hi: i32, len: usize| lo as usize..(hi + 1).clamp(0, len as i32) as usize;
+                let (x_range, z_range) = (range(lo.x, hi.x, nx), range(lo.y, hi.y, nz));
+
+                // vertical triangles are crossed by no rays
+                let z_range = if area > 0.0 { z_range } else { 0..0 };
+
+                let inside = |weight: f32, from: Vec2, to: Vec2| weight > 0.0 || weight == 0.0 && owns_edge(from, to);
+
+                z_range
+                    .flat_map(move |z| x_range.clone().map(move |x| (x, z)))
+                    .filter_map(move |(x, z)| {
+                        let ray = vec2(x as f32, z as f32) + 0.5;
+                        let (wa, wb, wc) = (cross(pc - pb, ray - pb), cross(pa - pc, ray - pc), cross(pb - pa, ray - pa));
+
+                        if !(inside(wa, pb, pc) && inside(wb, pc, pa) && inside(wc, pa, pb)) {
+                            return None;
+                        }
+
+                        let weights = vec3(wa, wb, wc) / area;
+                        let height = weights.dot(vec3(positions[a].y, positions[b].y, positions[c].y));
+                        let color = weights.x * mesh.colors[a] + weights.y * mesh.colors[b] + weights.z * mesh.colors[c];
+
+                        Some((x + nx * z, height, color))
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        hits.par_sort_unstable_by(|lhs, rhs| lhs.0.cmp(&rhs.0).then(lhs.1.total_cmp(&rhs.1)));
+
+        let mut offsets = vec![0; nx * nz + 1];
+
+        for &(ray, _, _) in &hits {
+            offsets[ray + 1] += 1;
+        }
+
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+
+        Self {
+            samples,
+            n_rays,
+            offsets,
+            hits: hits.into_iter().map(|(_, height, color)| (height, color)).collect(),
+        }
+    }
+
+    /// Part of the cell inside of the surface and the color interpolated between the hits
+    /// around it. Only the cell center is tested without coverage.
+    fn occupancy(&self, [x, y, z]: [usize; 3], coverage: bool) -> (f32, Option<Vec3>) {
+        let [nx, _] = self.n_rays;
+        let (cell_lo, cell_hi) = (y as f32, y as f32 + 1.0);
+
+        let mut inside = 0.0;
+        let mut color_sum = Vec3::ZERO;
+
+        for i in 0..self.samples * self.samples {
+            let ray = (x * self.samples + i % self.samples) + nx * (z * self.samples + i / self.samples);
+            let hits = &self.hits[self.offsets[ray]..self.offsets[ray + 1]];
+
+            // an unmatched crossing of an open mesh is ignored
+            for interval in hits.chunks_exact(2) {
+                let [(lo, lo_color), (hi, hi_color)] = [interval[0], interval[1]];
+
+                let (length, middle) = match coverage {
+                    true => {
+                        let (from, to) = (lo.max(cell_lo), hi.min(cell_hi));
+                        ((to - from).max(0.0), 0.5 * (from + to))
+                    },
+                    false => {
+                        let center = cell_lo + 0.5;
+                        (if (lo..hi).contains(&center) { 1.0 } else { 0.0 }, center)
+                    },
+                };
+
+                if length > 0.0 {
+                    let t = if hi > lo { (middle - lo) / (hi - lo) } else { 0.5 };
+
+                    inside += length;
+                    color_sum += length * lo_color.lerp(hi_color, t);
+                }
+            }
+        }
+
+        let color = (inside > 0.0).then(|| color_sum / inside);
+
+        (inside / (self.samples * self.samples) as f32, color)
+    }
+}
+
+
+
+/// Barycentric coordinates of the point of the triangle closest to the point
+fn closest_barycentric(point: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
+    let (ab, ac) = (b - a, c - a);
+
+    let ap = point - a;
+    let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return Vec3::X;
+    }
+
+    let bp = point - b;
+    let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+
+    if d3 >= 0.0 && d4 <= d3 {
+        return Vec3::Y;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return vec3(1.0 - v, v, 0.0);
+    }
+
+    let cp = point - c;
+    let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+
+    if d6 >= 0.0 && d5 <= d6 {
+        return Vec3::Z;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return vec3(1.0 - w, 0.0, w);
+    }
+
+    let va = d3 * d6 - d5 * d4;
+
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return vec3(0.0, 1.0 - w, w);
+    }
+
+    let v = vb / (va + vb + vc);
+    let w = vc / (va + vb + vc);
+
+    vec3(1.0 - v - w, v, w)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Closed box mesh colored by the positions of its vertices
+    fn box_mesh(aabb: Aabb) -> Mesh {
+        let positions = aabb.corners().to_vec();
+
+        let faces = [[0, 4, 6, 2], [1, 3, 7, 5], [0, 1, 5, 4], [2, 6, 7, 3], [0, 2, 3, 1], [4, 5, 7, 6]];
+
+        Mesh {
+            colors: positions.clone(),
+            positions,
+            triangles: faces.iter().flat_map(|&[a, b, c, d]| [[a, b, c], [a, c, d]]).collect(),
+        }
+    }
+
+    fn unit_box() -> Aabb {
+        Aabb::new(Vec3::ZERO, Vec3::ONE)
+    }
+
+    fn voxelization(shape: VoxelShape, coverage: bool) -> Voxelization {
+        Voxelization { shape, density: 10.0, coverage }
+    }
+
+    #[test]
+    fn solids_fill_the_cells_with_centers_inside() {
+        let mesh = box_mesh(Aabb::new(Vec3::splat(0.25), Vec3::splat(0.75)));
+        let field = mesh.voxelized([16; 3], unit_box(), &voxelization(VoxelShape::Solid, false));
+
+        for (i, cell) in field.cells.iter().enumerate() {
+            let index = RadianceField::index_from_flat(field.size, i);
+            let inside = index.iter().all(|i| (4..12).contains(i));
+
+            assert_eq!(cell.density, if inside { 10.0 } else { 0.0 }, "cell {index:?}");
+
+            // surface points near the cell or the vertical interpolation between them
+            if inside {
+                let color = vec3(cell.sh_r[0], cell.sh_g[0], cell.sh_b[0]) * SH_C0;
+                let center = (Vec3::from_array(index.map(|i| i as f32)) + 0.5) / 16.0;
+
+                assert!(color.abs_diff_eq(center, COLOR_BAND / 16.0 + 1e-5), "cell {index:?} color {color}");
+            }
+        }
+
+        // deeper cells are colored between the hits above and below them
+        let color = field[[7, 7, 8]].sh_r.map(|sh| sh * SH_C0);
+        assert!((color[0] - 7.5 / 16.0).abs() < 1e-5, "{color:?}");
+    }
+
+    #[test]
+    fn coverage_keeps_the_volume_of_solids() {
+        // faces cross the cells in the middle
+        let mesh = box_mesh(Aabb::new(Vec3::splat(2.5 / 8.0), Vec3::splat(5.5 / 8.0)));
+        let field = mesh.voxelized([8; 3], unit_box(), &voxelization(VoxelShape::Solid, true));
+
+        let volume = field.cells.iter().map(|cell| cell.density / 10.0).sum::<f32>();
+        assert!((volume - 27.0).abs() < 1e-4, "volume {volume} cells");
+
+        // every face halves the covered part of the cell
+        let expected = [([3, 3, 3], 10.0), ([2, 3, 3], 5.0), ([2, 2, 3], 2.5), ([2, 2, 2], 1.25), ([1, 3, 3], 0.0)];
+
+        for (index, expected) in expected {
+            let density = field[index].density;
+            assert!((density - expected).abs() < 1e-5, "cell {index:?} density {density}");
+        }
+    }
+
+    #[test]
+    fn shells_fill_the_cells_near_the_surface() {
+        // faces go through the cell centers
+        let mesh = box_mesh(Aabb::new(Vec3::splat(2.5 / 8.0), Vec3::splat(5.5 / 8.0)));
+        let field = mesh.voxelized([8; 3], unit_box(), &voxelization(VoxelShape::Shell { thickness: 1.0 }, false));
+
+        for (i, cell) in field.cells.iter().enumerate() {
+            let index = RadianceField::index_from_flat(field.size, i);
+
+            let in_box = index.iter().all(|i| (2..=5).contains(i));
+            let on_face = index.iter().any(|&i| i == 2 || i == 5);
+
+            assert_eq!(cell.density, if in_box && on_face { 10.0 } else { 0.0 }, "cell {index:?}");
+
+            // the cell centers lie on the surface
+            if cell.density > 0.0 {
+                let color = vec3(cell.sh_r[0], cell.sh_g[0], cell.sh_b[0]) * SH_C0;
+                let center = (Vec3::from_array(index.map(|i| i as f32)) + 0.5) / 8.0;
+
+                assert!(color.abs_diff_eq(center, 1e-5), "cell {index:?} color {color}");
+            }
+        }
+
+        // with coverage the density falls off over half of a cell on both sides of the shell
+        let field = mesh.voxelized([8; 3], unit_box(), &voxelization(VoxelShape::Shell { thickness: 1.0 }, true));
+
+        assert_eq!(field[[2, 3, 3]].density, 10.0);
+        assert_eq!(field[[3, 3, 3]].density, 0.0);
+        assert_eq!(field[[1, 3, 3]].density, 0.0);
+
+        let field = mesh.voxelized([8; 3], unit_box(), &voxelization(VoxelShape::Shell { thickness: 2.0 }, true));
+
+        assert_eq!(field[[3, 3, 3]].density, 5.0);
+        assert_eq!(field[[1, 3, 3]].density, 5.0);
+    }
+}